@@ -0,0 +1,320 @@
+//! A simple indexed archive for shipping assets as one file.
+//!
+//! Layout (all integers little-endian):
+//!
+//! ```text
+//! magic    b"MGPK"
+//! version  u32
+//! count    u32
+//! count × { path_len u16, path [u8; path_len], offset u64, length u64 }
+//! file data
+//! ```
+//!
+//! Paths are UTF-8, relative to the packed directory, with `/` separators.
+//! Offsets are from the start of the archive.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+use vfs::FileSystem;
+
+const MAGIC: &'static [u8; 4] = b"MGPK";
+const VERSION: u32 = 1;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut buf = [0; 2];
+    try!(reader.read_exact(&mut buf));
+    Ok(buf[0] as u16 | (buf[1] as u16) << 8)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    try!(reader.read_exact(&mut buf));
+    Ok(buf.iter().rev().fold(0, |acc, &b| acc << 8 | b as u32))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    try!(reader.read_exact(&mut buf));
+    Ok(buf.iter().rev().fold(0, |acc, &b| acc << 8 | b as u64))
+}
+
+fn write_u16<W: Write>(writer: &mut W, value: u16) -> io::Result<()> {
+    writer.write_all(&[value as u8, (value >> 8) as u8])
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> io::Result<()> {
+    let mut buf = [0; 4];
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = (value >> (8 * i)) as u8;
+    }
+    writer.write_all(&buf)
+}
+
+fn write_u64<W: Write>(writer: &mut W, value: u64) -> io::Result<()> {
+    let mut buf = [0; 8];
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = (value >> (8 * i)) as u8;
+    }
+    writer.write_all(&buf)
+}
+
+/// Turn a relative path into the form stored in the index.
+fn entry_name(path: &Path) -> Option<String> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => match part.to_str() {
+                Some(part) => parts.push(part),
+                None => return None,
+            },
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(parts.join("/"))
+}
+
+struct Entry {
+    offset: u64,
+    length: u64,
+}
+
+/// A packed archive opened for reading.
+pub struct Archive {
+    path: PathBuf,
+    entries: HashMap<String, Entry>,
+}
+
+impl Archive {
+    pub fn open<P>(path: P) -> io::Result<Archive>
+        where P: AsRef<Path> {
+        let path = path.as_ref();
+        let file = try!(File::open(path));
+        let size = try!(file.metadata()).len();
+        let mut reader = BufReader::new(file);
+
+        let mut magic = [0; 4];
+        try!(reader.read_exact(&mut magic));
+        if &magic != MAGIC {
+            return Err(invalid("not an asset archive"));
+        }
+        if try!(read_u32(&mut reader)) != VERSION {
+            return Err(invalid("unsupported archive version"));
+        }
+
+        let count = try!(read_u32(&mut reader));
+        let mut entries = HashMap::new();
+        for _ in 0..count {
+            let len = try!(read_u16(&mut reader));
+            let mut name = vec![0; len as usize];
+            try!(reader.read_exact(&mut name));
+            let name = try!(String::from_utf8(name).map_err(|_| invalid("archive path is not UTF-8")));
+            let offset = try!(read_u64(&mut reader));
+            let length = try!(read_u64(&mut reader));
+            // Reading trusts these, so don't let them point past the end
+            match offset.checked_add(length) {
+                Some(end) if end <= size => {}
+                _ => return Err(invalid(&format!("{} lies outside the archive", name))),
+            }
+            entries.insert(name, Entry { offset: offset, length: length });
+        }
+
+        Ok(Archive {
+            path: path.to_path_buf(),
+            entries: entries,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn paths(&self) -> Vec<&str> {
+        self.entries.keys().map(|name| name.as_str()).collect()
+    }
+
+    fn entry(&self, path: &Path) -> Option<&Entry> {
+        entry_name(path).and_then(|name| self.entries.get(&name))
+    }
+}
+
+impl FileSystem for Archive {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let entry = match self.entry(path) {
+            Some(entry) => entry,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound,
+                                              format!("{} is not in the archive", path.display()))),
+        };
+
+        // Reopen each time so reads don't need &mut self
+        let mut file = try!(File::open(&self.path));
+        try!(file.seek(SeekFrom::Start(entry.offset)));
+        let mut contents = vec![0; entry.length as usize];
+        try!(file.read_exact(&mut contents));
+        Ok(contents)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.entry(path).is_some()
+    }
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+        if path.is_dir() {
+            try!(collect_files(&path, files));
+        }
+        else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Pack every file under `dir` into an archive at `output`, returning the
+/// number of files packed. `output` itself is left out if it's under `dir`.
+pub fn pack_directory<P, Q>(dir: P, output: Q) -> io::Result<usize>
+    where P: AsRef<Path>,
+          Q: AsRef<Path> {
+    let dir = dir.as_ref();
+    let output = output.as_ref();
+    let mut files = Vec::new();
+    try!(collect_files(dir, &mut files));
+    // Don't pack the last archive into the next one
+    if let Ok(output) = fs::canonicalize(output) {
+        files.retain(|file| fs::canonicalize(file).ok().as_ref() != Some(&output));
+    }
+    // Keep archives reproducible regardless of directory order
+    files.sort();
+
+    let mut entries = Vec::new();
+    for file in files.iter() {
+        let relative = file.strip_prefix(dir).unwrap();
+        let name = try!(entry_name(relative).ok_or_else(
+            || invalid(&format!("{} is not a valid archive path", relative.display()))));
+        if name.len() > u16::max_value() as usize {
+            return Err(invalid(&format!("{} is too long", name)));
+        }
+        let length = try!(fs::metadata(file)).len();
+        entries.push((name, file, length));
+    }
+
+    let header_len = entries.iter().fold(4 + 4 + 4, |acc, &(ref name, _, _)| {
+        acc + 2 + name.len() as u64 + 8 + 8
+    });
+
+    let mut writer = BufWriter::new(try!(File::create(output)));
+    try!(writer.write_all(MAGIC));
+    try!(write_u32(&mut writer, VERSION));
+    try!(write_u32(&mut writer, entries.len() as u32));
+
+    let mut offset = header_len;
+    for &(ref name, _, length) in entries.iter() {
+        try!(write_u16(&mut writer, name.len() as u16));
+        try!(writer.write_all(name.as_bytes()));
+        try!(write_u64(&mut writer, offset));
+        try!(write_u64(&mut writer, length));
+        offset += length;
+    }
+
+    for &(_, file, length) in entries.iter() {
+        let copied = try!(io::copy(&mut try!(File::open(file)), &mut writer));
+        if copied != length {
+            return Err(invalid(&format!("{} changed while packing", file.display())));
+        }
+    }
+
+    try!(writer.flush());
+    Ok(entries.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::path::{Path, PathBuf};
+
+    use vfs::FileSystem;
+    use super::{Archive, pack_directory};
+
+    // A fresh directory under the system temp directory
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("mgmm-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: &Path, contents: &[u8]) {
+        File::create(path).unwrap().write_all(contents).unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = scratch("archive-round-trip");
+        let assets = dir.join("assets");
+        fs::create_dir_all(assets.join("maps")).unwrap();
+        write(&assets.join("hello.txt"), b"hello");
+        write(&assets.join("maps").join("level.tmx"), &[0, 1, 2, 255]);
+        write(&assets.join("empty"), b"");
+
+        let output = dir.join("assets.pak");
+        assert_eq!(pack_directory(&assets, &output).unwrap(), 3);
+
+        let archive = Archive::open(&output).unwrap();
+        assert_eq!(archive.len(), 3);
+        let mut paths = archive.paths();
+        paths.sort();
+        assert_eq!(paths, ["empty", "hello.txt", "maps/level.tmx"]);
+        assert_eq!(archive.read(Path::new("hello.txt")).unwrap(), b"hello");
+        assert_eq!(archive.read(Path::new("./maps/level.tmx")).unwrap(), [0, 1, 2, 255]);
+        assert_eq!(archive.read(Path::new("empty")).unwrap(), b"");
+        assert!(archive.exists(Path::new("maps/level.tmx")));
+        assert!(!archive.exists(Path::new("missing")));
+        assert!(archive.read(Path::new("../hello.txt")).is_err());
+    }
+
+    #[test]
+    fn skips_output() {
+        let dir = scratch("archive-skips-output");
+        write(&dir.join("hello.txt"), b"hello");
+        let output = dir.join("assets.pak");
+        assert_eq!(pack_directory(&dir, &output).unwrap(), 1);
+        // Packing again doesn't pick up the first archive
+        assert_eq!(pack_directory(&dir, &output).unwrap(), 1);
+        assert_eq!(Archive::open(&output).unwrap().paths(), ["hello.txt"]);
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        let dir = scratch("archive-truncated");
+        let assets = dir.join("assets");
+        fs::create_dir_all(&assets).unwrap();
+        write(&assets.join("hello.txt"), b"hello");
+        let output = dir.join("assets.pak");
+        pack_directory(&assets, &output).unwrap();
+
+        // Cut off the end of the data, so the entry points past the end
+        let mut bytes = Vec::new();
+        File::open(&output).unwrap().read_to_end(&mut bytes).unwrap();
+        let len = bytes.len();
+        write(&output, &bytes[..len - 1]);
+        assert!(Archive::open(&output).is_err());
+
+        write(&output, b"nope");
+        assert!(Archive::open(&output).is_err());
+    }
+}
@@ -0,0 +1,327 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use gfx;
+use tiled;
+
+use atlas::{Atlas, AtlasData};
+use error::Result;
+use sprite::{self, SampledTexture, TextureOptions};
+use tilemap;
+use vfs::{Directory, SharedFileSystem};
+
+// How often to check for changed files when hot reloading
+const POLL_INTERVAL_MS: u64 = 500;
+
+/// A shared, reference-counted asset.
+pub type Handle<T> = Rc<RefCell<T>>;
+
+/// Loads assets by path from a `FileSystem`, handing out a shared handle to everything that
+/// asks for the same path.
+///
+/// The manager keeps its own reference to each asset, so nothing is freed
+/// until `collect` is called.
+///
+/// With hot reloading enabled, `reload_changed` polls the modification
+/// times of loaded files and reloads changed assets in place, so existing
+/// handles see the new contents.
+pub struct Assets<R: gfx::Resources> {
+    fs: SharedFileSystem,
+    textures: HashMap<PathBuf, Handle<SampledTexture<R>>>,
+    tilemaps: HashMap<PathBuf, Handle<tiled::Map>>,
+    /// Counts of how often each map was replaced in place, for whoever
+    /// asked through `tilemap_generation`.
+    tilemap_generations: HashMap<PathBuf, Rc<Cell<u64>>>,
+    shaders: HashMap<PathBuf, Handle<Vec<u8>>>,
+    texture_options: HashMap<PathBuf, TextureOptions>,
+    hot_reload: bool,
+    modified: HashMap<PathBuf, SystemTime>,
+    last_poll: Option<Instant>,
+}
+
+fn users<T>(handle: &Handle<T>) -> usize {
+    // Don't count the manager's own reference
+    Rc::strong_count(handle) - 1
+}
+
+impl<R: gfx::Resources> Assets<R> {
+    /// Load from the working directory.
+    pub fn new() -> Assets<R> {
+        Assets::with_file_system(Arc::new(Directory::new(".")))
+    }
+
+    /// Load from `fs`, e.g. a packed `archive::Archive`.
+    pub fn with_file_system(fs: SharedFileSystem) -> Assets<R> {
+        Assets {
+            fs: fs,
+            textures: HashMap::new(),
+            tilemaps: HashMap::new(),
+            tilemap_generations: HashMap::new(),
+            shaders: HashMap::new(),
+            texture_options: HashMap::new(),
+            hot_reload: false,
+            modified: HashMap::new(),
+            last_poll: None,
+        }
+    }
+
+    pub fn hot_reload(&self) -> bool {
+        self.hot_reload
+    }
+
+    pub fn set_hot_reload(&mut self, enabled: bool) {
+        self.hot_reload = enabled;
+        self.modified.clear();
+        if enabled {
+            let paths: Vec<PathBuf> = self.textures.keys()
+                .chain(self.tilemaps.keys())
+                .chain(self.shaders.keys())
+                .cloned()
+                .collect();
+            for path in paths {
+                self.watch(&path);
+            }
+        }
+    }
+
+    fn watch(&mut self, path: &Path) {
+        if !self.hot_reload {
+            return;
+        }
+        if let Some(time) = self.fs.modified(path) {
+            self.modified.insert(path.to_path_buf(), time);
+        }
+    }
+
+    /// Reload any assets whose files changed since they were loaded,
+    /// returning the paths that were reloaded. Does nothing unless hot
+    /// reloading is enabled.
+    ///
+    /// If an asset fails to reload, the old version is kept and the error
+    /// is logged.
+    pub fn reload_changed<F>(&mut self, factory: &mut F) -> Vec<PathBuf>
+        where F: gfx::Factory<R> {
+        if !self.hot_reload {
+            return Vec::new();
+        }
+
+        let now = Instant::now();
+        if let Some(last_poll) = self.last_poll {
+            if now.duration_since(last_poll) < Duration::from_millis(POLL_INTERVAL_MS) {
+                return Vec::new();
+            }
+        }
+        self.last_poll = Some(now);
+
+        let mut changed = Vec::new();
+        let fs = &self.fs;
+        for (path, time) in self.modified.iter_mut() {
+            match fs.modified(path) {
+                Some(new_time) if new_time != *time => {
+                    *time = new_time;
+                    changed.push(path.clone());
+                }
+                _ => {}
+            }
+        }
+
+        let mut reloaded = Vec::new();
+        for path in changed {
+            match self.reload(factory, &path) {
+                Ok(()) => reloaded.push(path),
+                Err(err) => {
+                    let _ = writeln!(io::stderr(), "Could not reload {}: {}", path.display(), err);
+                }
+            }
+        }
+        reloaded
+    }
+
+    fn reload<F>(&mut self, factory: &mut F, path: &Path) -> Result<()>
+        where F: gfx::Factory<R> {
+        if let Some(handle) = self.textures.get(path) {
+            let options = self.texture_options.get(path).cloned().unwrap_or_default();
+            let img = try!(sprite::decode_texture_from(&*self.fs, path));
+            handle.borrow_mut().texture = try!(sprite::upload_texture_with(factory, &img, &options));
+        }
+        else if let Some(handle) = self.tilemaps.get(path) {
+            let map = try!(tilemap::load_tilemap_from(&*self.fs, path));
+            *handle.borrow_mut() = map;
+            self.bump_tilemap_generation(path);
+        }
+        else if let Some(handle) = self.shaders.get(path) {
+            *handle.borrow_mut() = try!(self.fs.read(path));
+        }
+        Ok(())
+    }
+
+    pub fn texture<F, P>(&mut self, factory: &mut F, path: P) -> Result<Handle<SampledTexture<R>>>
+        where F: gfx::Factory<R>,
+              P: AsRef<Path> {
+        self.texture_with(factory, path, &TextureOptions::default())
+    }
+
+    /// Load a texture with the given options, which also pick its sampler.
+    /// If the texture is already loaded, the existing one is returned as is.
+    pub fn texture_with<F, P>(&mut self, factory: &mut F, path: P, options: &TextureOptions)
+                              -> Result<Handle<SampledTexture<R>>>
+        where F: gfx::Factory<R>,
+              P: AsRef<Path> {
+        let path = path.as_ref();
+        if let Some(handle) = self.textures.get(path) {
+            return Ok(handle.clone());
+        }
+
+        let img = try!(sprite::decode_texture_from(&*self.fs, path));
+        let texture = try!(sprite::upload_texture_with(factory, &img, options));
+        self.texture_options.insert(path.to_path_buf(), *options);
+        let handle = Rc::new(RefCell::new(SampledTexture::new(factory, texture, options)));
+        self.textures.insert(path.to_path_buf(), handle.clone());
+        self.watch(path);
+        Ok(handle)
+    }
+
+    /// Load an atlas, with its pages loaded like `texture_with` and shared
+    /// with the manager. The metadata itself isn't cached.
+    pub fn atlas<F, P>(&mut self, factory: &mut F, path: P, options: &TextureOptions) -> Result<Atlas<R>>
+        where F: gfx::Factory<R>,
+              P: AsRef<Path> {
+        let path = path.as_ref();
+        let data = try!(AtlasData::load_from(&*self.fs, path));
+        let mut pages = Vec::new();
+        for index in 0..data.pages.len() {
+            pages.push(try!(self.texture_with(factory, data.page_path(path, index), options)));
+        }
+        Atlas::from_pages(data, pages)
+    }
+
+    pub fn tilemap<P>(&mut self, path: P) -> Result<Handle<tiled::Map>>
+        where P: AsRef<Path> {
+        let path = path.as_ref();
+        if let Some(handle) = self.tilemaps.get(path) {
+            return Ok(handle.clone());
+        }
+
+        let map = try!(tilemap::load_tilemap_from(&*self.fs, path));
+        let handle = Rc::new(RefCell::new(map));
+        self.tilemaps.insert(path.to_path_buf(), handle.clone());
+        self.watch(path);
+        Ok(handle)
+    }
+
+    /// A counter that goes up every time the map at `path` is replaced in
+    /// place, by hot reloading or `insert_tilemap`. Anything built from the
+    /// map, like `TilemapLayer`s, is out of date once it changes.
+    pub fn tilemap_generation<P>(&mut self, path: P) -> Rc<Cell<u64>>
+        where P: AsRef<Path> {
+        self.tilemap_generations.entry(path.as_ref().to_path_buf())
+            .or_insert_with(|| Rc::new(Cell::new(0)))
+            .clone()
+    }
+
+    fn bump_tilemap_generation(&self, path: &Path) {
+        if let Some(generation) = self.tilemap_generations.get(path) {
+            generation.set(generation.get() + 1);
+        }
+    }
+
+    /// The source of a shader, for passing to pipeline creation.
+    pub fn shader<P>(&mut self, path: P) -> Result<Handle<Vec<u8>>>
+        where P: AsRef<Path> {
+        let path = path.as_ref();
+        if let Some(handle) = self.shaders.get(path) {
+            return Ok(handle.clone());
+        }
+
+        let source = try!(self.fs.read(path));
+        let handle = Rc::new(RefCell::new(source));
+        self.shaders.insert(path.to_path_buf(), handle.clone());
+        self.watch(path);
+        Ok(handle)
+    }
+
+    /// Add a texture loaded elsewhere (e.g. by a `Loader`) under `path`,
+    /// replacing any texture already loaded from there.
+    pub fn insert_texture<P>(&mut self, path: P, texture: SampledTexture<R>) -> Handle<SampledTexture<R>>
+        where P: AsRef<Path> {
+        self.insert_texture_with(path, texture, &TextureOptions::default())
+    }
+
+    /// Like `insert_texture`, remembering the options it was created with
+    /// for hot reloading.
+    pub fn insert_texture_with<P>(&mut self, path: P, texture: SampledTexture<R>, options: &TextureOptions)
+                                  -> Handle<SampledTexture<R>>
+        where P: AsRef<Path> {
+        let path = path.as_ref();
+        self.texture_options.insert(path.to_path_buf(), *options);
+        if let Some(handle) = self.textures.get(path) {
+            *handle.borrow_mut() = texture;
+            return handle.clone();
+        }
+
+        let handle = Rc::new(RefCell::new(texture));
+        self.textures.insert(path.to_path_buf(), handle.clone());
+        self.watch(path);
+        handle
+    }
+
+    pub fn insert_tilemap<P>(&mut self, path: P, map: tiled::Map) -> Handle<tiled::Map>
+        where P: AsRef<Path> {
+        let path = path.as_ref();
+        if let Some(handle) = self.tilemaps.get(path) {
+            *handle.borrow_mut() = map;
+            self.bump_tilemap_generation(path);
+            return handle.clone();
+        }
+
+        let handle = Rc::new(RefCell::new(map));
+        self.tilemaps.insert(path.to_path_buf(), handle.clone());
+        self.watch(path);
+        handle
+    }
+
+    /// How many handles to the asset at `path` are held outside the manager,
+    /// or `None` if it isn't loaded.
+    pub fn usage<P>(&self, path: P) -> Option<usize>
+        where P: AsRef<Path> {
+        let path = path.as_ref();
+        self.textures.get(path).map(users)
+            .or_else(|| self.tilemaps.get(path).map(users))
+            .or_else(|| self.shaders.get(path).map(users))
+    }
+
+    pub fn len(&self) -> usize {
+        self.textures.len() + self.tilemaps.len() + self.shaders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drop every asset nobody else holds a handle to, returning how many
+    /// were freed.
+    pub fn collect(&mut self) -> usize {
+        let before = self.len();
+        self.textures.retain(|_, handle| users(handle) > 0);
+        self.tilemaps.retain(|_, handle| users(handle) > 0);
+        self.shaders.retain(|_, handle| users(handle) > 0);
+
+        let textures = &self.textures;
+        let tilemaps = &self.tilemaps;
+        let shaders = &self.shaders;
+        self.texture_options.retain(|path, _| textures.contains_key(path));
+        self.tilemap_generations.retain(|path, generation| {
+            tilemaps.contains_key(path) || Rc::strong_count(generation) > 1
+        });
+        self.modified.retain(|path, _| {
+            textures.contains_key(path) || tilemaps.contains_key(path) || shaders.contains_key(path)
+        });
+
+        before - self.len()
+    }
+}
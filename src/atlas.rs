@@ -0,0 +1,388 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use gfx;
+use image;
+
+use assets::Handle;
+use error::{Error, Result};
+use sprite::{self, SampledTexture, TextureOptions};
+use vfs::{Directory, FileSystem};
+
+/// Where a packed image ended up, in pixels from the top left of its page.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    pub page: usize,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Page {
+    /// The page image, relative to the metadata file.
+    pub file: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The contents of an atlas metadata file.
+///
+/// The file is plain text, one record per line:
+///
+/// ```text
+/// page <file> <width> <height>
+/// region <name> <page> <x> <y> <width> <height>
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct AtlasData {
+    pub pages: Vec<Page>,
+    pub regions: BTreeMap<String, Region>,
+}
+
+fn parse_error(line: usize, message: &str) -> Error {
+    Error::Atlas(format!("line {}: {}", line + 1, message))
+}
+
+fn field<T: ::std::str::FromStr>(fields: &[&str], index: usize, line: usize) -> Result<T> {
+    match fields.get(index).and_then(|field| field.parse().ok()) {
+        Some(value) => Ok(value),
+        None => Err(parse_error(line, "missing or malformed field")),
+    }
+}
+
+impl AtlasData {
+    pub fn parse(text: &str) -> Result<AtlasData> {
+        let mut pages = Vec::new();
+        let mut regions = BTreeMap::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.first() {
+                None => continue,
+                Some(&"page") => {
+                    pages.push(Page {
+                        file: try!(field(&fields, 1, number)),
+                        width: try!(field(&fields, 2, number)),
+                        height: try!(field(&fields, 3, number)),
+                    });
+                }
+                Some(&"region") => {
+                    let name: String = try!(field(&fields, 1, number));
+                    let region = Region {
+                        page: try!(field(&fields, 2, number)),
+                        x: try!(field(&fields, 3, number)),
+                        y: try!(field(&fields, 4, number)),
+                        width: try!(field(&fields, 5, number)),
+                        height: try!(field(&fields, 6, number)),
+                    };
+                    if region.page >= pages.len() {
+                        return Err(parse_error(number, "region refers to an unknown page"));
+                    }
+                    regions.insert(name, region);
+                }
+                Some(_) => return Err(parse_error(number, "unknown record")),
+            }
+        }
+
+        Ok(AtlasData {
+            pages: pages,
+            regions: regions,
+        })
+    }
+
+    /// Read and parse the metadata file at `path` in `fs`.
+    pub fn load_from<P>(fs: &FileSystem, path: P) -> Result<AtlasData>
+        where P: AsRef<Path> {
+        let bytes = try!(fs.read(path.as_ref()));
+        let text = try!(String::from_utf8(bytes).map_err(|_| Error::Atlas("not UTF-8".to_string())));
+        AtlasData::parse(&text)
+    }
+
+    /// Where the image of `page` is, for metadata read from `path`.
+    pub fn page_path<P>(&self, path: P, page: usize) -> PathBuf
+        where P: AsRef<Path> {
+        let dir = path.as_ref().parent().unwrap_or(Path::new(""));
+        dir.join(&self.pages[page].file)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for page in self.pages.iter() {
+            writeln!(text, "page {} {} {}", page.file, page.width, page.height).unwrap();
+        }
+        for (name, region) in self.regions.iter() {
+            writeln!(text, "region {} {} {} {} {} {}",
+                     name, region.page, region.x, region.y, region.width, region.height).unwrap();
+        }
+        text
+    }
+
+    /// The texture coordinates of a region as `[u, v, width, height]`.
+    pub fn uv_rect(&self, name: &str) -> Option<[f32; 4]> {
+        self.regions.get(name).map(|region| {
+            let page = &self.pages[region.page];
+            [region.x as f32 / page.width as f32,
+             region.y as f32 / page.height as f32,
+             region.width as f32 / page.width as f32,
+             region.height as f32 / page.height as f32]
+        })
+    }
+}
+
+// `size` with `border` added on both sides, or `None` if that overflows
+fn grow(size: u32, border: u32) -> Option<u32> {
+    border.checked_mul(2).and_then(|both| size.checked_add(both))
+}
+
+struct Shelf {
+    x: u32,
+    y: u32,
+    height: u32,
+}
+
+/// Pack `images` into square pages of `page_size` pixels.
+///
+/// Images are kept `padding` pixels apart, and each one's edge pixels are
+/// repeated `extrude` pixels outwards so filtering at the border doesn't
+/// pick up its neighbours. Page files are named `<page_prefix>_<n>.png`.
+///
+/// Names and the prefix can't contain whitespace, which separates fields
+/// in the metadata. Empty images take no space and get no region.
+pub fn pack(images: Vec<(String, image::RgbaImage)>,
+            page_prefix: &str,
+            page_size: u32,
+            padding: u32,
+            extrude: u32) -> Result<(AtlasData, Vec<image::RgbaImage>)> {
+    if page_prefix.is_empty() || page_prefix.chars().any(char::is_whitespace) {
+        return Err(Error::Atlas(format!("{:?} is not a valid page prefix", page_prefix)));
+    }
+    if let Some(&(ref name, _)) = images.iter().find(|&&(ref name, _)| {
+        name.is_empty() || name.chars().any(char::is_whitespace)
+    }) {
+        return Err(Error::Atlas(format!("{:?} is not a valid region name", name)));
+    }
+
+    let mut images = images;
+    // Tallest first packs shelves more tightly
+    images.sort_by(|a, b| b.1.height().cmp(&a.1.height()).then_with(|| a.0.cmp(&b.0)));
+
+    let mut pages: Vec<image::RgbaImage> = Vec::new();
+    let mut shelf = Shelf { x: padding, y: padding, height: 0 };
+    let mut regions = BTreeMap::new();
+
+    for (name, img) in images {
+        let (width, height) = img.dimensions();
+        if width == 0 || height == 0 {
+            continue;
+        }
+        let too_big = || Error::Atlas(format!("{} does not fit on a {}x{} page", name, page_size, page_size));
+        let (cell_width, cell_height) = match (grow(width, extrude), grow(height, extrude)) {
+            (Some(cell_width), Some(cell_height)) => (cell_width, cell_height),
+            _ => return Err(too_big()),
+        };
+        match (grow(cell_width, padding), grow(cell_height, padding)) {
+            (Some(padded_width), Some(padded_height)) if padded_width <= page_size && padded_height <= page_size => {}
+            _ => return Err(too_big()),
+        }
+
+        // Positions stay within the page, but the sums past its edge may not
+        // fit in a u32
+        if shelf.x as u64 + (cell_width + padding) as u64 > page_size as u64 {
+            shelf = Shelf { x: padding, y: shelf.y.saturating_add(shelf.height).saturating_add(padding), height: 0 };
+        }
+        if pages.is_empty() || shelf.y as u64 + (cell_height + padding) as u64 > page_size as u64 {
+            pages.push(image::ImageBuffer::new(page_size, page_size));
+            shelf = Shelf { x: padding, y: padding, height: 0 };
+        }
+
+        let page_index = pages.len() - 1;
+        let page = &mut pages[page_index];
+        for dy in 0..cell_height {
+            for dx in 0..cell_width {
+                // Clamping the source repeats the edge pixels into the border
+                let sx = (dx as i64 - extrude as i64).max(0).min(width as i64 - 1) as u32;
+                let sy = (dy as i64 - extrude as i64).max(0).min(height as i64 - 1) as u32;
+                page.put_pixel(shelf.x + dx, shelf.y + dy, *img.get_pixel(sx, sy));
+            }
+        }
+
+        regions.insert(name, Region {
+            page: page_index,
+            x: shelf.x + extrude,
+            y: shelf.y + extrude,
+            width: width,
+            height: height,
+        });
+
+        shelf.x += cell_width + padding;
+        shelf.height = ::std::cmp::max(shelf.height, cell_height);
+    }
+
+    let data = AtlasData {
+        pages: (0..pages.len()).map(|i| Page {
+            file: format!("{}_{}.png", page_prefix, i),
+            width: page_size,
+            height: page_size,
+        }).collect(),
+        regions: regions,
+    };
+
+    Ok((data, pages))
+}
+
+/// An atlas with its pages loaded as textures.
+///
+/// Pages are shared handles, so an atlas from `Assets::atlas` shares its
+/// pages with the manager and sees them hot reloaded.
+pub struct Atlas<R: gfx::Resources> {
+    pub data: AtlasData,
+    pages: Vec<Handle<SampledTexture<R>>>,
+}
+
+impl<R: gfx::Resources> Atlas<R> {
+    /// An atlas from metadata and its pages, loaded elsewhere. Fails if
+    /// there isn't one page for each in `data`.
+    pub fn from_pages(data: AtlasData, pages: Vec<Handle<SampledTexture<R>>>) -> Result<Atlas<R>> {
+        if pages.len() != data.pages.len() {
+            return Err(Error::Atlas(format!("expected {} pages, got {}", data.pages.len(), pages.len())));
+        }
+        Ok(Atlas {
+            data: data,
+            pages: pages,
+        })
+    }
+
+    /// Load an atlas metadata file and the page images next to it, from
+    /// the working directory.
+    pub fn load<F, P>(factory: &mut F, path: P) -> Result<Atlas<R>>
+        where F: gfx::Factory<R>,
+              P: AsRef<Path> {
+        Atlas::load_from(factory, &Directory::new("."), path, &TextureOptions::default())
+    }
+
+    /// Load an atlas from `fs`, e.g. a packed `archive::Archive`, with its
+    /// pages uploaded and sampled as `options` ask.
+    pub fn load_from<F, P>(factory: &mut F, fs: &FileSystem, path: P, options: &TextureOptions)
+                           -> Result<Atlas<R>>
+        where F: gfx::Factory<R>,
+              P: AsRef<Path> {
+        let path = path.as_ref();
+        let data = try!(AtlasData::load_from(fs, path));
+        let mut pages = Vec::new();
+        for index in 0..data.pages.len() {
+            let texture = try!(sprite::load_texture_from_with(factory, fs, data.page_path(path, index), options));
+            pages.push(Rc::new(RefCell::new(SampledTexture::new(factory, texture, options))));
+        }
+        Atlas::from_pages(data, pages)
+    }
+
+    pub fn region(&self, name: &str) -> Option<&Region> {
+        self.data.regions.get(name)
+    }
+
+    pub fn page(&self, index: usize) -> Option<&Handle<SampledTexture<R>>> {
+        self.pages.get(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use image;
+
+    use super::{AtlasData, pack};
+
+    fn solid(width: u32, height: u32, color: [u8; 4]) -> image::RgbaImage {
+        image::ImageBuffer::from_pixel(width, height, image::Rgba { data: color })
+    }
+
+    #[test]
+    fn parse_round_trip() {
+        let text = "page atlas_0.png 256 128\nregion tank 0 2 4 64 32\n\nregion shell 0 70 4 8 8\n";
+        let data = AtlasData::parse(text).unwrap();
+        assert_eq!(data.pages.len(), 1);
+        assert_eq!(data.pages[0].file, "atlas_0.png");
+        assert_eq!(data.regions["tank"].x, 2);
+        assert_eq!(data.regions["shell"].width, 8);
+        assert_eq!(data.uv_rect("tank"), Some([2.0 / 256.0, 4.0 / 128.0, 0.25, 0.25]));
+        assert_eq!(data.uv_rect("missing"), None);
+        assert_eq!(AtlasData::parse(&data.to_text()).unwrap(), data);
+    }
+
+    #[test]
+    fn parse_rejects_bad_records() {
+        assert!(AtlasData::parse("region tank 0 0 0 8 8\n").is_err());
+        assert!(AtlasData::parse("page atlas_0.png 256\n").is_err());
+        assert!(AtlasData::parse("sprite tank\n").is_err());
+    }
+
+    #[test]
+    fn pack_extrudes_and_separates() {
+        let images = vec![("red".to_string(), solid(4, 4, [255, 0, 0, 255])),
+                          ("blue".to_string(), solid(2, 6, [0, 0, 255, 255]))];
+        let (data, pages) = pack(images, "atlas", 32, 1, 1).unwrap();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(data.pages[0].file, "atlas_0.png");
+
+        let red = data.regions["red"];
+        let blue = data.regions["blue"];
+        assert_eq!((red.width, red.height, blue.width, blue.height), (4, 4, 2, 6));
+        // Padding plus extrusion on both sides keeps the cells apart
+        let separate = red.x + red.width + 2 <= blue.x || blue.x + blue.width + 2 <= red.x ||
+                       red.y + red.height + 2 <= blue.y || blue.y + blue.height + 2 <= red.y;
+        assert!(separate);
+
+        let page = &pages[0];
+        assert_eq!(page.get_pixel(red.x, red.y).data, [255, 0, 0, 255]);
+        // The edge is repeated into the border
+        assert_eq!(page.get_pixel(red.x - 1, red.y - 1).data, [255, 0, 0, 255]);
+        assert_eq!(page.get_pixel(blue.x + blue.width, blue.y).data, [0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn pack_starts_new_pages() {
+        let images = (0..3).map(|i| (format!("tile{}", i), solid(12, 12, [0, 0, 0, 255]))).collect();
+        let (data, pages) = pack(images, "atlas", 16, 0, 0).unwrap();
+        assert_eq!(pages.len(), 3);
+        assert_eq!(data.pages.len(), 3);
+        assert_eq!(data.regions["tile2"].page, 2);
+    }
+
+    #[test]
+    fn pack_validates_names_and_skips_empty_images() {
+        let images = vec![("bad name".to_string(), solid(1, 1, [0, 0, 0, 255]))];
+        assert!(pack(images, "atlas", 16, 0, 0).is_err());
+        assert!(pack(Vec::new(), "my atlas", 16, 0, 0).is_err());
+
+        let images = vec![("empty".to_string(), solid(0, 0, [0, 0, 0, 0])),
+                          ("dot".to_string(), solid(1, 1, [0, 0, 0, 255]))];
+        let (data, pages) = pack(images, "atlas", 16, 0, 0).unwrap();
+        assert_eq!(pages.len(), 1);
+        assert!(!data.regions.contains_key("empty"));
+        assert!(data.regions.contains_key("dot"));
+    }
+
+    #[test]
+    fn pack_rejects_oversized_images() {
+        let images = vec![("huge".to_string(), solid(20, 20, [0, 0, 0, 255]))];
+        assert!(pack(images, "atlas", 16, 0, 0).is_err());
+
+        // Borders too wide to add up are refused rather than overflowing
+        let dot = || vec![("dot".to_string(), solid(1, 1, [0, 0, 0, 255]))];
+        assert!(pack(dot(), "atlas", u32::max_value(), 0, u32::max_value()).is_err());
+        assert!(pack(dot(), "atlas", u32::max_value(), u32::max_value() / 2 + 1, 0).is_err());
+    }
+
+    #[test]
+    fn page_paths() {
+        let data = AtlasData::parse("page ui_0.png 64 64\n").unwrap();
+        assert_eq!(data.page_path("assets/ui.atlas", 0), Path::new("assets/ui_0.png"));
+        assert_eq!(data.page_path("ui.atlas", 0), Path::new("ui_0.png"));
+    }
+}
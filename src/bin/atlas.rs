@@ -0,0 +1,71 @@
+extern crate image;
+
+extern crate mgmm;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::process;
+
+use mgmm::atlas;
+
+const DEFAULT_PAGE_SIZE: u32 = 1024;
+const DEFAULT_PADDING: u32 = 2;
+const DEFAULT_EXTRUDE: u32 = 1;
+
+fn parse_arg(args: &[String], index: usize, default: u32) -> u32 {
+    match args.get(index) {
+        Some(arg) => arg.parse().unwrap_or_else(|_| {
+            println!("Expected a number, got {}", arg);
+            process::exit(1);
+        }),
+        None => default,
+    }
+}
+
+fn run(input: &Path, output: &Path, page_size: u32, padding: u32, extrude: u32) -> Result<(), String> {
+    let mut images = Vec::new();
+    for entry in try!(fs::read_dir(input).map_err(|e| e.to_string())) {
+        let path = try!(entry.map_err(|e| e.to_string())).path();
+        if path.extension().map_or(true, |ext| ext != "png") {
+            continue;
+        }
+
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let img = try!(image::open(&path).map_err(|e| format!("{}: {}", path.display(), e)));
+        images.push((name, img.to_rgba()));
+    }
+
+    let prefix = output.file_stem().unwrap().to_string_lossy().into_owned();
+    let (data, pages) = try!(atlas::pack(images, &prefix, page_size, padding, extrude)
+                             .map_err(|e| e.to_string()));
+
+    let dir = output.parent().unwrap_or(Path::new(""));
+    for (page, img) in data.pages.iter().zip(pages.iter()) {
+        try!(img.save(dir.join(&page.file)).map_err(|e| e.to_string()));
+    }
+
+    let mut metadata = try!(File::create(output).map_err(|e| e.to_string()));
+    try!(metadata.write_all(data.to_text().as_bytes()).map_err(|e| e.to_string()));
+
+    println!("Packed {} images onto {} pages", data.regions.len(), data.pages.len());
+    Ok(())
+}
+
+pub fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 || args.len() > 6 {
+        println!("Usage: {} <image directory> <output.atlas> [page size] [padding] [extrude]", args[0]);
+        process::exit(1);
+    }
+
+    let page_size = parse_arg(&args, 3, DEFAULT_PAGE_SIZE);
+    let padding = parse_arg(&args, 4, DEFAULT_PADDING);
+    let extrude = parse_arg(&args, 5, DEFAULT_EXTRUDE);
+
+    if let Err(err) = run(Path::new(&args[1]), Path::new(&args[2]), page_size, padding, extrude) {
+        println!("Could not build atlas: {}", err);
+        process::exit(1);
+    }
+}
@@ -14,8 +14,10 @@ use gfx::Bundle;
 use gfx::Factory;
 use gfx::traits::FactoryExt;
 
-use mgmm::circle::Circle;
+use mgmm::circle::{Circle, CircleFactory};
+use mgmm::blend::AlphaMode;
 use mgmm::blur::Blur;
+use mgmm::letterbox::Scaling;
 pub use mgmm::types::*;
 
 const BG_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
@@ -36,35 +38,39 @@ struct Game {
 }
 
 impl mgmm::game::Game for Game {
-    fn init(factory: &mut gfx_device_gl::Factory, main_color: &RenderTarget, _main_depth: &DepthTarget) -> Game {
+    fn init(factory: &mut gfx_device_gl::Factory, main_color: &RenderTarget, _main_depth: &DepthTarget)
+            -> mgmm::error::Result<Game> {
         let proj: UniformMat4 = cgmath::ortho(0.0, WORLD_WIDTH, 0.0, WORLD_HEIGHT, 0.0, 1.0).into();
         let view: UniformMat4 = cgmath::Matrix4::identity().into();
 
-        let blur = Blur::new(factory, main_color, WORLD_WIDTH, WORLD_HEIGHT);
-        let circle = Circle::new(
+        let mut blur = try!(Blur::new(factory, main_color, WORLD_WIDTH, WORLD_HEIGHT));
+        // Blending over the transparent buffer leaves it premultiplied
+        try!(blur.set_alpha_mode(factory, AlphaMode::Premultiplied));
+        let circles = try!(CircleFactory::new(factory));
+        let circle = circles.create(
             factory,
             blur.rtv.clone(),
             [1.0, 0.0, 0.0],
             10.0,
         );
 
-        Game {
+        Ok(Game {
             proj: proj,
             view: view,
             circle: circle,
             blur: blur,
-        }
+        })
     }
 
-    fn tick(&mut self) {
-
+    fn tick(&mut self) -> mgmm::error::Result<()> {
+        Ok(())
     }
 
     fn handle_event(&mut self, event: &glutin::Event) {
 
     }
 
-    fn render(&mut self, encoder: &mut GLEncoder, target: &RenderTarget) {
+    fn render(&mut self, encoder: &mut GLEncoder, target: &RenderTarget, _alpha: f32) {
         encoder.clear(&self.blur.rtv, [0.0, 0.0, 0.0, 0.0]);
         encoder.clear(target, BG_COLOR);
         self.circle.render(encoder, self.proj, self.view);
@@ -73,6 +79,12 @@ impl mgmm::game::Game for Game {
 }
 
 pub fn main() {
-    let mut game = mgmm::game::App::<Game>::new("Blur", WINDOW_WIDTH, WINDOW_HEIGHT);
-    game.run();
+    let result = mgmm::game::App::<Game>::with_virtual_resolution(
+        "Blur", WINDOW_WIDTH, WINDOW_HEIGHT,
+        WORLD_WIDTH as u16, WORLD_HEIGHT as u16, Scaling::Integer)
+        .and_then(|mut game| game.run());
+    if let Err(err) = result {
+        println!("{}", err);
+        std::process::exit(1);
+    }
 }
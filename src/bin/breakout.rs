@@ -10,15 +10,25 @@ extern crate time;
 extern crate mgmm;
 
 use mgmm::blur::Blur;
-use mgmm::rectangle::Rectangle;
-use mgmm::circle::Circle;
+use mgmm::letterbox::Scaling;
+use mgmm::rectangle::{Rectangle, RectangleFactory};
+use mgmm::circle::{Circle, CircleFactory};
+use mgmm::error::Result;
+use mgmm::input::{Bindings, Input};
+use mgmm::replay::StateHasher;
+use mgmm::scene::{Effect, Scene, SceneContext, SceneStack, Transition};
 use mgmm::types::*;
 
 use cgmath::{SquareMatrix};
 
+use std::hash::Hasher;
+
 const PI: f32 = std::f32::consts::PI;
 
 const BG_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+const PAUSE_COLOR: [f32; 4] = [0.0, 0.0, 0.0, 0.5];
+const WON_COLOR: [f32; 4] = [0.2, 0.7, 0.3, 1.0];
+const LOST_COLOR: [f32; 4] = [0.7, 0.2, 0.2, 1.0];
 
 const WINDOW_WIDTH: u32 = 960;
 const WINDOW_HEIGHT: u32 = 640;
@@ -34,6 +44,14 @@ const BLOCK_HEIGHT: f32 = 16.0;
 
 const BALL_RADIUS: f32 = 8.0;
 
+const BINDINGS_PATH: &'static str = "assets/breakout.bindings";
+
+const LIVES: u32 = 3;
+
+const FADE_TICKS: u32 = 25;
+const WIPE_TICKS: u32 = 40;
+const BLINK_TICKS: u32 = 25;
+
 type R = gfx_device_gl::Resources;
 
 struct Paddle {
@@ -56,12 +74,6 @@ impl Paddle {
     }
 }
 
-struct Input {
-    left: bool,
-    right: bool,
-    launch: bool,
-}
-
 struct CollisionDirection {
     top: bool,
     bottom: bool,
@@ -76,8 +88,9 @@ enum CollisionLocation {
 
 impl CollisionLocation {
     fn check(new_x: f32, new_y: f32, r: f32, rect: &Rectangle<R>) -> CollisionLocation {
-        let closest_x = f32::max(rect.position.x, f32::min(new_x, rect.position.x + rect.width));
-        let closest_y = f32::max(rect.position.y, f32::min(new_y, rect.position.y + rect.height));
+        let position = rect.transform.position();
+        let closest_x = f32::max(position.x, f32::min(new_x, position.x + rect.width));
+        let closest_y = f32::max(position.y, f32::min(new_y, position.y + rect.height));
 
         // Check whether the distance is less than the radius
         let d2 = (closest_x - new_x).powi(2) + (closest_y - new_y).powi(2);
@@ -101,17 +114,18 @@ impl CollisionDirection {
         rects.retain(|ref rect| {
             match CollisionLocation::check(new_x, new_y, r, &rect) {
                 CollisionLocation::Hit(closest_x, closest_y) => {
-                    if closest_y >= rect.position.y + rect.height {
+                    let position = rect.transform.position();
+                    if closest_y >= position.y + rect.height {
                         bottom = true;
                     }
-                    else if closest_y <= rect.position.y {
+                    else if closest_y <= position.y {
                         top = true;
                     }
 
-                    if closest_x <= rect.position.x {
+                    if closest_x <= position.x {
                         right = true;
                     }
-                    else if closest_x >= rect.position.x + rect.width {
+                    else if closest_x >= position.x + rect.width {
                         left = true;
                     }
                     false
@@ -129,7 +143,31 @@ impl CollisionDirection {
     }
 }
 
-struct Game {
+fn create_blocks(factory: &mut gfx_device_gl::Factory,
+                 rectangles: &RectangleFactory<R>,
+                 main_color: &RenderTarget) -> Vec<Rectangle<R>> {
+    let mut blocks = vec![];
+    for y in 0..6 {
+        let top = if y % 2 == 0 { 8 } else { 7 };
+        let left = (WORLD_WIDTH - (top as f32) * (BLOCK_WIDTH + 4.0) + 4.0) / 2.0;
+        for x in 0..top {
+            let mut block = rectangles.create(
+                factory,
+                main_color.clone(),
+                [0.0, 0.0, 1.0],
+                BLOCK_WIDTH, BLOCK_HEIGHT
+            );
+            block.transform.set_position(cgmath::vec3(
+                left + (BLOCK_WIDTH + 4.0) * (x as f32),
+                WORLD_HEIGHT - (y as f32) * (BLOCK_HEIGHT + 4.0),
+                0.0));
+            blocks.push(block);
+        }
+    }
+    blocks
+}
+
+struct World {
     proj: UniformMat4,
     view: UniformMat4,
     blur: Blur<R>,
@@ -139,51 +177,37 @@ struct Game {
     paddle_speed: f32,
     ball_speed: f32,
     ball_angle: f32,
+    lives: u32,
     input: Input,
 }
 
-impl mgmm::game::Game for Game {
-    fn init(factory: &mut gfx_device_gl::Factory, main_color: &RenderTarget, _main_depth: &DepthTarget) -> Game {
-        let proj: UniformMat4 = cgmath::ortho(0.0, WORLD_WIDTH, 0.0, WORLD_HEIGHT, 0.0, 1.0).into();
-        let view: UniformMat4 = cgmath::Matrix4::identity().into();
-
-        let blur = Blur::new(factory, main_color, WORLD_WIDTH, WORLD_HEIGHT);
+impl World {
+    fn new(factory: &mut gfx_device_gl::Factory, main_color: &RenderTarget) -> Result<World> {
+        let blur = try!(Blur::new(factory, main_color, WORLD_WIDTH, WORLD_HEIGHT));
 
-        let rectangle = Rectangle::new(
+        let rectangles = try!(RectangleFactory::new(factory));
+        let rectangle = rectangles.create(
             factory,
             main_color.clone(),
             [1.0, 0.0, 0.0],
             PADDLE_WIDTH, PADDLE_HEIGHT
         );
-        let mut ball = Circle::new(
+        let circles = try!(CircleFactory::new(factory));
+        let mut ball = circles.create(
             factory,
             blur.rtv.clone(),
             [1.0, 0.0, 1.0],
             BALL_RADIUS,
         );
-        ball.position.x = PADDLE_WIDTH / 2.0 - BALL_RADIUS;
-        ball.position.y = PADDLE_HEIGHT;
-
-        let mut blocks = vec![];
-        for y in 0..6 {
-            let top = if y % 2 == 0 { 8 } else { 7 };
-            let left = (WORLD_WIDTH - (top as f32) * (BLOCK_WIDTH + 4.0) + 4.0) / 2.0;
-            for x in 0..top {
-                let mut block = Rectangle::new(
-                    factory,
-                    main_color.clone(),
-                    [0.0, 0.0, 1.0],
-                    BLOCK_WIDTH, BLOCK_HEIGHT
-                );
-                block.position.y = WORLD_HEIGHT - (y as f32) * (BLOCK_HEIGHT + 4.0);
-                block.position.x = left + (BLOCK_WIDTH + 4.0) * (x as f32);
-                blocks.push(block);
-            }
-        }
+        ball.transform.set_position(cgmath::vec3(PADDLE_WIDTH / 2.0 - BALL_RADIUS, PADDLE_HEIGHT, 0.0));
+
+        let blocks = create_blocks(factory, &rectangles, main_color);
 
-        Game {
-            proj: proj,
-            view: view,
+        let input = try!(load_input());
+
+        Ok(World {
+            proj: ortho(),
+            view: identity(),
             blur: blur,
             paddle: Paddle::new(rectangle),
             blocks: blocks,
@@ -191,36 +215,38 @@ impl mgmm::game::Game for Game {
             paddle_speed: 0.0,
             ball_speed: 0.0,
             ball_angle: 0.0,
-            input: Input { left: false, right: false, launch: false, },
-        }
+            lives: LIVES,
+            input: input,
+        })
     }
 
     fn tick(&mut self) {
-        if self.input.left || self.input.right {
+        self.input.update();
+        let direction = self.input.axis("move");
+        if direction != 0.0 {
             self.paddle_speed = 3.0;
         }
-
-        if !(self.input.left || self.input.right) {
+        else {
             self.paddle_speed = 0.0;
         }
 
-        let delta_paddle = if self.input.left && self.paddle.rect.position.x > 0.0 {
-            -self.paddle_speed
+        let delta_paddle = if direction < 0.0 && self.paddle.rect.transform.position().x > 0.0 {
+            direction * self.paddle_speed
         }
-        else if self.input.right && self.paddle.rect.position.x + PADDLE_WIDTH < WORLD_WIDTH {
-            self.paddle_speed
+        else if direction > 0.0 && self.paddle.rect.transform.position().x + PADDLE_WIDTH < WORLD_WIDTH {
+            direction * self.paddle_speed
         }
         else {
             0.0
         };
-        self.paddle.rect.position.x += delta_paddle;
+        self.paddle.rect.transform.translate(cgmath::vec3(delta_paddle, 0.0, 0.0));
 
         // Ball is "sticky" when on the paddle
-        if self.ball.position.y <= PADDLE_HEIGHT + 2.0 {
-            self.ball.position.x += delta_paddle;
+        if self.ball.transform.position().y <= PADDLE_HEIGHT + 2.0 {
+            self.ball.transform.translate(cgmath::vec3(delta_paddle, 0.0, 0.0));
         }
 
-        if self.input.launch {
+        if self.input.action_pressed("launch") {
             self.ball_angle = if delta_paddle == 0.0 {
                 std::f32::consts::PI / 2.0
             } else if delta_paddle > 0.0 {
@@ -235,8 +261,9 @@ impl mgmm::game::Game for Game {
         let ball_dy = self.ball_speed * f32::sin(self.ball_angle);
 
         // Figure out where the ball will be
-        let new_x = self.ball.position.x + ball_dx;
-        let new_y = self.ball.position.y + ball_dy;
+        let ball = self.ball.transform.position();
+        let new_x = ball.x + ball_dx;
+        let new_y = ball.y + ball_dy;
 
         // Check collisions with bricks
         // Add the radius, because the origin of the ball's frame is
@@ -258,9 +285,12 @@ impl mgmm::game::Game for Game {
 
         // Check collisions with floor
         if new_y <= 0.0 {
+            self.lives -= 1;
             self.ball_speed = 0.0;
-            self.ball.position.x = self.paddle.rect.position.x + PADDLE_WIDTH / 2.0 - BALL_RADIUS;
-            self.ball.position.y = PADDLE_HEIGHT;
+            let x = self.paddle.rect.transform.position().x + PADDLE_WIDTH / 2.0 - BALL_RADIUS;
+            self.ball.transform.set_position(cgmath::vec3(x, PADDLE_HEIGHT, 0.0));
+            // Back on the paddle, rather than sliding there
+            self.ball.transform.reset_interpolation();
             return;
         }
 
@@ -319,38 +349,24 @@ impl mgmm::game::Game for Game {
 
             let ball_dx = self.ball_speed * f32::cos(self.ball_angle);
             let ball_dy = self.ball_speed * f32::sin(self.ball_angle);
-            let new_x = self.ball.position.x + ball_dx;
-            let new_y = self.ball.position.y + ball_dy;
-            self.ball.position.x = new_x;
-            self.ball.position.y = new_y;
+            self.ball.transform.translate(cgmath::vec3(ball_dx, ball_dy, 0.0));
 
             // Each bounce also increases speed
             self.ball_speed = f32::min(6.0, 1.25 * self.ball_speed);
             self.paddle_speed = f32::min(6.0, 1.25 * self.paddle_speed);
         }
         else {
-            self.ball.position.x = new_x;
-            self.ball.position.y = new_y;
+            self.ball.transform.set_position(cgmath::vec3(new_x, new_y, 0.0));
         }
 
         self.blur.strength = self.ball_speed / 1200.0;
     }
 
     fn handle_event(&mut self, event: &glutin::Event) {
-        match *event {
-            glutin::Event::KeyboardInput(state, code, _vcode) => {
-                match code {
-                    38 => self.input.left = state == glutin::ElementState::Pressed,
-                    40 => self.input.right = state == glutin::ElementState::Pressed,
-                    65 => self.input.launch = state == glutin::ElementState::Pressed,
-                    _ => {},
-                }
-            }
-            _ => {}
-        }
+        self.input.handle_event(event);
     }
 
-    fn render(&mut self, encoder: &mut GLEncoder, target: &RenderTarget) {
+    fn render(&mut self, encoder: &mut GLEncoder, target: &RenderTarget, _alpha: f32) {
         encoder.clear(target, BG_COLOR);
         encoder.clear(&self.blur.rtv, [1.0, 1.0, 1.0, 0.0]);
         self.paddle.render(encoder, self.proj, self.view);
@@ -362,7 +378,267 @@ impl mgmm::game::Game for Game {
     }
 }
 
+fn load_input() -> Result<Input> {
+    Ok(Input::new(try!(Bindings::load(BINDINGS_PATH))))
+}
+
+fn ortho() -> UniformMat4 {
+    cgmath::ortho(0.0, WORLD_WIDTH, 0.0, WORLD_HEIGHT, 0.0, 1.0).into()
+}
+
+fn identity() -> UniformMat4 {
+    cgmath::Matrix4::identity().into()
+}
+
+/// The wall and paddle, with a blinking ball. The start action starts.
+#[derive(Default)]
+struct Title {
+    blocks: Vec<Rectangle<R>>,
+    paddle: Option<Rectangle<R>>,
+    ball: Option<Circle<R>>,
+    ticks: u32,
+    input: Option<Input>,
+}
+
+impl Scene for Title {
+    fn init(&mut self, context: &mut SceneContext) -> Result<()> {
+        let rectangles = try!(RectangleFactory::new(context.factory));
+        self.blocks = create_blocks(context.factory, &rectangles, context.main_color);
+        let mut paddle = rectangles.create(
+            context.factory,
+            context.main_color.clone(),
+            [1.0, 0.0, 0.0],
+            PADDLE_WIDTH, PADDLE_HEIGHT
+        );
+        paddle.transform.set_position(cgmath::vec3((WORLD_WIDTH - PADDLE_WIDTH) / 2.0, 0.0, 0.0));
+        let circles = try!(CircleFactory::new(context.factory));
+        let mut ball = circles.create(
+            context.factory,
+            context.main_color.clone(),
+            [1.0, 0.0, 1.0],
+            BALL_RADIUS,
+        );
+        ball.transform.set_position(cgmath::vec3(WORLD_WIDTH / 2.0 - BALL_RADIUS, PADDLE_HEIGHT, 0.0));
+        self.paddle = Some(paddle);
+        self.ball = Some(ball);
+        self.input = Some(try!(load_input()));
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Result<Transition> {
+        self.ticks += 1;
+        let input = self.input.as_mut().unwrap();
+        input.update();
+        if input.action_pressed("start") {
+            Ok(Transition::Replace(Box::new(Play::default()), Effect::Fade(FADE_TICKS)))
+        }
+        else {
+            Ok(Transition::None)
+        }
+    }
+
+    fn handle_event(&mut self, event: &glutin::Event) -> Result<Transition> {
+        self.input.as_mut().unwrap().handle_event(event);
+        Ok(Transition::None)
+    }
+
+    fn render(&mut self, encoder: &mut GLEncoder, target: &RenderTarget, _alpha: f32) {
+        encoder.clear(target, BG_COLOR);
+        for block in self.blocks.iter_mut() {
+            block.render(encoder, ortho(), identity());
+        }
+        if let Some(ref mut paddle) = self.paddle {
+            paddle.render(encoder, ortho(), identity());
+        }
+        if let Some(ref mut ball) = self.ball {
+            if (self.ticks / BLINK_TICKS) % 2 == 0 {
+                ball.render(encoder, ortho(), identity());
+            }
+        }
+    }
+}
+
+/// The game itself. The pause action pauses.
+#[derive(Default)]
+struct Play {
+    world: Option<World>,
+}
+
+impl Scene for Play {
+    fn init(&mut self, context: &mut SceneContext) -> Result<()> {
+        self.world = Some(try!(World::new(context.factory, context.main_color)));
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Result<Transition> {
+        let world = self.world.as_mut().unwrap();
+        world.tick();
+        if world.lives == 0 || world.blocks.is_empty() {
+            let over = GameOver {
+                won: world.lives > 0,
+                lives: world.lives,
+                balls: vec![],
+                input: None,
+            };
+            Ok(Transition::Replace(Box::new(over), Effect::Wipe(WIPE_TICKS)))
+        }
+        else if world.input.action_pressed("pause") {
+            Ok(Transition::Push(Box::new(Pause::default()), Effect::Cut))
+        }
+        else {
+            Ok(Transition::None)
+        }
+    }
+
+    fn handle_event(&mut self, event: &glutin::Event) -> Result<Transition> {
+        self.world.as_mut().unwrap().handle_event(event);
+        Ok(Transition::None)
+    }
+
+    fn render(&mut self, encoder: &mut GLEncoder, target: &RenderTarget, alpha: f32) {
+        self.world.as_mut().unwrap().render(encoder, target, alpha);
+    }
+
+    fn state_hash(&self) -> u64 {
+        let world = self.world.as_ref().unwrap();
+        let mut hasher = StateHasher::new();
+        let paddle = world.paddle.rect.transform.position();
+        let ball = world.ball.transform.position();
+        hasher.write_f32(paddle.x);
+        hasher.write_f32(ball.x);
+        hasher.write_f32(ball.y);
+        hasher.write_f32(world.paddle_speed);
+        hasher.write_f32(world.ball_speed);
+        hasher.write_f32(world.ball_angle);
+        hasher.write_u32(world.lives);
+        hasher.write_u64(world.blocks.len() as u64);
+        hasher.finish()
+    }
+
+    fn resume(&mut self) {
+        // Keys released while paused never reached us
+        self.world.as_mut().unwrap().input.release_all();
+    }
+}
+
+/// Dims the game underneath and shows a pause sign. The pause action
+/// resumes.
+#[derive(Default)]
+struct Pause {
+    bars: Vec<Rectangle<R>>,
+    input: Option<Input>,
+}
+
+impl Scene for Pause {
+    fn init(&mut self, context: &mut SceneContext) -> Result<()> {
+        let rectangles = try!(RectangleFactory::new(context.factory));
+        for i in 0..2 {
+            let mut bar = rectangles.create(
+                context.factory,
+                context.main_color.clone(),
+                [1.0, 1.0, 1.0],
+                16.0, 64.0
+            );
+            bar.transform.set_position(cgmath::vec3(
+                WORLD_WIDTH / 2.0 - 24.0 + 32.0 * (i as f32),
+                WORLD_HEIGHT / 2.0 - 32.0,
+                0.0));
+            self.bars.push(bar);
+        }
+        self.input = Some(try!(load_input()));
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Result<Transition> {
+        let input = self.input.as_mut().unwrap();
+        input.update();
+        if input.action_pressed("pause") {
+            Ok(Transition::Pop(Effect::Cut))
+        }
+        else {
+            Ok(Transition::None)
+        }
+    }
+
+    fn handle_event(&mut self, event: &glutin::Event) -> Result<Transition> {
+        self.input.as_mut().unwrap().handle_event(event);
+        Ok(Transition::None)
+    }
+
+    fn render(&mut self, encoder: &mut GLEncoder, target: &RenderTarget, _alpha: f32) {
+        encoder.clear(target, PAUSE_COLOR);
+        for bar in self.bars.iter_mut() {
+            bar.render(encoder, ortho(), identity());
+        }
+    }
+
+    fn is_overlay(&self) -> bool {
+        true
+    }
+}
+
+/// Green with a ball per life left after a win, red after a loss. The
+/// start action goes back to the title.
+struct GameOver {
+    won: bool,
+    lives: u32,
+    balls: Vec<Circle<R>>,
+    input: Option<Input>,
+}
+
+impl Scene for GameOver {
+    fn init(&mut self, context: &mut SceneContext) -> Result<()> {
+        let circles = try!(CircleFactory::new(context.factory));
+        let left = WORLD_WIDTH / 2.0 - (self.lives as f32) * BALL_RADIUS * 2.0;
+        for i in 0..self.lives {
+            let mut ball = circles.create(
+                context.factory,
+                context.main_color.clone(),
+                [1.0, 1.0, 1.0],
+                BALL_RADIUS,
+            );
+            ball.transform.set_position(cgmath::vec3(
+                left + (i as f32) * BALL_RADIUS * 4.0 + BALL_RADIUS,
+                WORLD_HEIGHT / 2.0 - BALL_RADIUS,
+                0.0));
+            self.balls.push(ball);
+        }
+        self.input = Some(try!(load_input()));
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Result<Transition> {
+        let input = self.input.as_mut().unwrap();
+        input.update();
+        if input.action_pressed("start") {
+            Ok(Transition::Replace(Box::new(Title::default()), Effect::Fade(FADE_TICKS)))
+        }
+        else {
+            Ok(Transition::None)
+        }
+    }
+
+    fn handle_event(&mut self, event: &glutin::Event) -> Result<Transition> {
+        self.input.as_mut().unwrap().handle_event(event);
+        Ok(Transition::None)
+    }
+
+    fn render(&mut self, encoder: &mut GLEncoder, target: &RenderTarget, _alpha: f32) {
+        encoder.clear(target, if self.won { WON_COLOR } else { LOST_COLOR });
+        for ball in self.balls.iter_mut() {
+            ball.render(encoder, ortho(), identity());
+        }
+    }
+}
+
 pub fn main() {
-    let mut game = mgmm::game::App::<Game>::new("Breakout", WINDOW_WIDTH, WINDOW_HEIGHT);
-    game.run();
+    let args: Vec<String> = std::env::args().collect();
+    let result = mgmm::game::App::<SceneStack<Title>>::with_virtual_resolution(
+        "Breakout", WINDOW_WIDTH, WINDOW_HEIGHT,
+        WORLD_WIDTH as u16, WORLD_HEIGHT as u16, Scaling::Integer)
+        .and_then(|mut game| game.run_with_args(&args));
+    if let Err(err) = result {
+        println!("{}", err);
+        std::process::exit(1);
+    }
 }
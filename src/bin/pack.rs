@@ -0,0 +1,22 @@
+extern crate mgmm;
+
+use std::env;
+use std::process;
+
+use mgmm::archive;
+
+pub fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        println!("Usage: {} <asset directory> <output archive>", args[0]);
+        process::exit(1);
+    }
+
+    match archive::pack_directory(&args[1], &args[2]) {
+        Ok(count) => println!("Packed {} files into {}", count, args[2]),
+        Err(err) => {
+            println!("Could not pack {}: {}", args[1], err);
+            process::exit(1);
+        }
+    }
+}
@@ -9,12 +9,17 @@ extern crate tiled;
 extern crate time;
 extern crate mgmm;
 
-use mgmm::{game, input, sprite, tilemap, types, player};
+use mgmm::{assets, blend, camera, clock, error, game, gamepad, input, loader, replay, sprite, tilemap, types, player};
+use mgmm::letterbox::Scaling;
+use mgmm::rectangle::{Rectangle, RectangleFactory};
+use mgmm::render_queue::{RenderQueue, SortMode};
 
 use mgmm::types::*;
 
 use cgmath::{SquareMatrix};
 
+use std::hash::Hasher;
+
 const BG_COLOR: [f32; 4] = [0.529, 0.808, 0.980, 1.0];
 
 const WINDOW_WIDTH: u32 = 1280;
@@ -23,85 +28,273 @@ const WINDOW_HEIGHT: u32 = 720;
 const WORLD_WIDTH: f32 = 1280.0;
 const WORLD_HEIGHT: f32 = 720.0;
 
+const BINDINGS_PATH: &'static str = "assets/tanks.bindings";
+const MAP_PATH: &'static str = "assets/maps/test.tmx";
+const TILESET_PATH: &'static str = "assets/textures/mapPack_tilesheet.png";
+const SPRITE_VERTEX_SHADER: &'static str = "src/shader/sprite_150.glslv";
+const SPRITE_FRAGMENT_SHADER: &'static str = "src/shader/sprite_150.glslf";
+
+const LOADING_BAR_WIDTH: f32 = 640.0;
+const LOADING_BAR_HEIGHT: f32 = 16.0;
+
+/// Seeds the camera shake, so runs can be reproduced.
+const SHAKE_SEED: u32 = 0x7a4c;
+
+const ZOOM_STEP: f32 = 1.1;
+const MIN_ZOOM: f32 = 0.5;
+const MAX_ZOOM: f32 = 2.0;
+
+const LAYER_GROUND: i32 = 0;
+const LAYER_UNITS: i32 = 1;
+
 type R = gfx_device_gl::Resources;
+
+// Nearest filtering stops neighbouring tiles bleeding in
+fn tileset_options() -> sprite::TextureOptions {
+    sprite::TextureOptions { premultiply: true, ..sprite::TextureOptions::pixel_art() }
+}
+
 struct TankGame {
+    factory: gfx_device_gl::Factory,
+    main_color: RenderTarget,
     input: input::Input,
-    proj: UniformMat4,
-    view: UniformMat4,
+    gamepads: gamepad::Gamepads,
+    assets: assets::Assets<R>,
+    camera: camera::Camera2D,
+    effects: camera::CameraEffects,
     sprite_factory: sprite::SpriteFactory<R>,
     player: player::Player<R>,
-    tilemap: tilemap::Tilemap<R>,
+    loader: loader::Loader<R>,
+    pending_map: Option<(loader::Loading<tiled::Map>, loader::Loading<sprite::SampledTexture<R>>)>,
+    loading_bar: Rectangle<R>,
+    tilemap: Option<tilemap::Tilemap<R>>,
     layers: Vec<tilemap::TilemapLayer<R>>,
+    /// Debugging aids: P freezes the game, and N then steps one tick.
+    paused: bool,
+    steps: u32,
 }
 
 impl game::Game for TankGame {
-    fn init(factory: &mut gfx_device_gl::Factory, main_color: &RenderTarget, _main_depth: &DepthTarget) -> TankGame {
-        let proj: UniformMat4 = cgmath::ortho(0.0, WORLD_WIDTH, 0.0, WORLD_HEIGHT, 0.0, 1.0).into();
-        let view: UniformMat4 = cgmath::Matrix4::identity().into();
+    fn init(factory: &mut gfx_device_gl::Factory, main_color: &RenderTarget, _main_depth: &DepthTarget)
+            -> error::Result<TankGame> {
+        let mut camera = camera::Camera2D::new(WORLD_WIDTH, WORLD_HEIGHT);
+        camera.deadzone = cgmath::vec2(128.0, 96.0);
+        camera.damping = 0.9;
 
-        let sprite_factory = sprite::SpriteFactory::new(factory);
+        // Set MGMM_HOT_RELOAD to reload textures, maps and shaders as they
+        // change on disk
+        let mut assets = assets::Assets::new();
+        assets.set_hot_reload(std::env::var_os("MGMM_HOT_RELOAD").is_some());
 
-        let texture = sprite::load_texture(factory, std::path::Path::new("assets/textures/tankBlue_outline.png")).unwrap();
-        let barrel_texture = sprite::load_texture(factory, std::path::Path::new("assets/textures/barrelBlue_outline.png")).unwrap();
-        let tileset = sprite::load_texture(factory, std::path::Path::new("assets/textures/mapPack_tilesheet.png")).unwrap();
-        let tilemap = tilemap::load_tilemap(std::path::Path::new("assets/maps/test.tmx")).unwrap();
+        let mut sprite_factory = if assets.hot_reload() {
+            let vertex = try!(assets.shader(SPRITE_VERTEX_SHADER));
+            let fragment = try!(assets.shader(SPRITE_FRAGMENT_SHADER));
+            try!(sprite::SpriteFactory::from_shaders(factory, vertex, fragment))
+        }
+        else {
+            try!(sprite::SpriteFactory::new(factory))
+        };
+        // Premultiplied textures keep the outlines from getting dark
+        // fringes when filtered
+        try!(sprite_factory.set_alpha_mode(factory, blend::AlphaMode::Premultiplied));
+        let options = sprite::TextureOptions { premultiply: true, ..Default::default() };
 
-        let sprite = sprite_factory.create(factory, main_color.clone(), texture.clone(), 64.0, 64.0);
-        let barrel = sprite_factory.create(factory, main_color.clone(), barrel_texture.clone(), 24.0, 52.0);
+        let hull = try!(assets.texture_with(factory, "assets/textures/tankBlue_outline.png", &options));
+        let sprite = sprite_factory.create_from_handle(factory, main_color.clone(), &hull, 64.0, 64.0);
+        let barrel = try!(assets.texture_with(factory, "assets/textures/barrelBlue_outline.png", &options));
+        let barrel = sprite_factory.create_from_handle(factory, main_color.clone(), &barrel, 24.0, 52.0);
 
         let player = player::Player::new(sprite, barrel);
-        let tilemap = tilemap::Tilemap::new(factory, tilemap, tileset);
-        let layers = tilemap.create_layers(factory, main_color.clone());
 
-        let input = input::Input::new();
+        // The map streams in while a loading bar is shown
+        let mut loader = loader::Loader::new();
+        let pending_map = (loader.tilemap(MAP_PATH),
+                           loader.texture_with(TILESET_PATH, &tileset_options()));
+        let rectangles = try!(RectangleFactory::new(factory));
+        let mut loading_bar = rectangles.create(
+            factory, main_color.clone(), [1.0, 1.0, 1.0],
+            0.0, LOADING_BAR_HEIGHT);
+        loading_bar.transform.set_position(cgmath::vec3((WORLD_WIDTH - LOADING_BAR_WIDTH) / 2.0,
+                                                        (WORLD_HEIGHT - LOADING_BAR_HEIGHT) / 2.0,
+                                                        0.0));
+
+        let input = input::Input::new(try!(input::Bindings::load(BINDINGS_PATH)));
 
-        TankGame {
+        Ok(TankGame {
+            factory: factory.clone(),
+            main_color: main_color.clone(),
             input: input,
-            proj: proj,
-            view: view,
+            gamepads: gamepad::Gamepads::new(),
+            assets: assets,
+            camera: camera,
+            effects: camera::CameraEffects::new(SHAKE_SEED),
             sprite_factory: sprite_factory,
             player: player,
-            tilemap: tilemap,
-            layers: layers,
-        }
+            loader: loader,
+            pending_map: Some(pending_map),
+            loading_bar: loading_bar,
+            tilemap: None,
+            layers: Vec::new(),
+            paused: false,
+            steps: 0,
+        })
     }
 
-    fn tick(&mut self) {
+    fn tick(&mut self) -> error::Result<()> {
+        self.input.update();
+
+        if let Some((map, tileset)) = self.pending_map.take() {
+            if map.is_ready() && tileset.is_ready() {
+                // Handed to the asset manager, so the tilemap picks them up
+                // from there and notices when they're reloaded
+                self.assets.insert_tilemap(MAP_PATH, try!(map.take().unwrap()));
+                let options = tileset_options();
+                self.assets.insert_texture_with(TILESET_PATH, try!(tileset.take().unwrap()), &options);
+                let mut tilemap = try!(tilemap::Tilemap::from_paths(
+                    &mut self.factory, &mut self.assets, MAP_PATH, TILESET_PATH));
+                try!(tilemap.set_alpha_mode(&mut self.factory, blend::AlphaMode::Premultiplied));
+                if self.assets.hot_reload() {
+                    let vertex = try!(self.assets.shader(SPRITE_VERTEX_SHADER));
+                    let fragment = try!(self.assets.shader(SPRITE_FRAGMENT_SHADER));
+                    try!(tilemap.set_shaders(&mut self.factory, vertex, fragment));
+                }
+                self.layers = tilemap.create_layers(&mut self.factory, self.main_color.clone());
+                self.camera.bounds = Some(tilemap.bounds());
+                self.tilemap = Some(tilemap);
+            }
+            else {
+                self.pending_map = Some((map, tileset));
+                return Ok(());
+            }
+        }
+
+        let reloaded = self.assets.reload_changed(&mut self.factory);
+        if let Some(ref tilemap) = self.tilemap {
+            tilemap.update_layers(&mut self.factory, &mut self.layers);
+        }
+        if reloaded.iter().any(|path| path == std::path::Path::new(SPRITE_VERTEX_SHADER) ||
+                               path == std::path::Path::new(SPRITE_FRAGMENT_SHADER)) {
+            self.sprite_factory.recompile(&mut self.factory);
+            if let Some(ref tilemap) = self.tilemap {
+                tilemap.recompile(&mut self.factory);
+            }
+        }
+
+        // The camera may have moved since the mouse did
+        let mouse = self.camera.screen_to_world(self.input.win_x as f32, self.input.win_y as f32);
+        self.input.world_x = mouse.x;
+        self.input.world_y = mouse.y;
+
+        // Scrolling up zooms in
+        let (_, scroll) = self.input.wheel();
+        if scroll != 0.0 {
+            let zoom = self.camera.zoom() * ZOOM_STEP.powf(scroll);
+            self.camera.set_zoom(zoom.max(MIN_ZOOM).min(MAX_ZOOM));
+        }
+
+        if self.input.action_pressed("grab") {
+            let cursor = match self.input.cursor() {
+                glutin::CursorState::Grab => glutin::CursorState::Normal,
+                _ => glutin::CursorState::Grab,
+            };
+            self.input.set_cursor(cursor);
+        }
+
         self.player.update(&self.input);
-        if self.input.action {
-            let (x, y) = self.player.position();
-            self.view[3][0] = -x + WORLD_WIDTH / 2.0 - 16.0;
-            self.view[3][1] = -y + WORLD_HEIGHT / 2.0 - 16.0;
+        if self.input.action("fire") {
+            if let Some(direction) = self.player.fire() {
+                // Recoil throws the view back opposite the shot
+                self.effects.kick(-direction, 8.0);
+                self.effects.add_trauma(0.3);
+                self.effects.pulse_zoom(0.03);
+                self.gamepads.rumble(0.6, 0.3, std::time::Duration::from_millis(150));
+            }
         }
+
+        let (x, y) = self.player.center();
+        self.camera.follow(cgmath::vec2(x, y));
+        self.effects.tick();
+        Ok(())
     }
 
     fn handle_event(&mut self, event: &glutin::Event) {
         match *event {
-            glutin::Event::MouseMoved(mx, my) => {
-                let my = WINDOW_HEIGHT as i32 - my;
-                let x = (mx as f32 / WINDOW_WIDTH as f32) * WORLD_WIDTH - self.view[3][0];
-                let y = (my as f32 / WINDOW_HEIGHT as f32) * WORLD_HEIGHT - self.view[3][1];
-                self.input.mouse_moved(mx, my as i32, x, y);
+            glutin::Event::KeyboardInput(glutin::ElementState::Pressed, _, Some(glutin::VirtualKeyCode::P)) => {
+                self.paused = !self.paused;
             }
-            glutin::Event::KeyboardInput(state, code, vcode) => {
-                self.input.key_pressed(state, code, vcode);
+            glutin::Event::KeyboardInput(glutin::ElementState::Pressed, _, Some(glutin::VirtualKeyCode::N)) => {
+                self.steps += 1;
             }
             _ => {},
         }
+        self.input.handle_event(event);
+    }
+
+    fn update_time(&mut self, time: &mut clock::TimeConfig, _stats: &clock::FrameStats) {
+        time.single_step = self.paused;
+        if self.paused {
+            time.steps += self.steps;
+        }
+        self.steps = 0;
+    }
+
+    fn handle_gamepad(&mut self, event: &gamepad::GamepadEvent) {
+        self.input.handle_gamepad(event);
+    }
+
+    fn gamepads(&mut self) -> Option<&mut gamepad::Gamepads> {
+        Some(&mut self.gamepads)
+    }
+
+    fn state_hash(&self) -> u64 {
+        let mut hasher = replay::StateHasher::new();
+        hasher.write_u8(self.tilemap.is_some() as u8);
+        self.player.hash_state(&mut hasher);
+        hasher.write_f32(self.camera.position.x);
+        hasher.write_f32(self.camera.position.y);
+        hasher.write_f32(self.camera.zoom());
+        hasher.write_f32(self.effects.trauma());
+        hasher.finish()
     }
 
-    fn render(&mut self, encoder: &mut GLEncoder, target: &RenderTarget) {
+    fn cursor_state(&self) -> glutin::CursorState {
+        self.input.cursor()
+    }
+
+    fn loader(&mut self) -> Option<&mut loader::Loader<R>> {
+        Some(&mut self.loader)
+    }
+
+    fn render(&mut self, encoder: &mut GLEncoder, target: &RenderTarget, _alpha: f32) {
         encoder.clear(target, BG_COLOR);
 
-        for layer in self.layers.iter() {
-            layer.render(encoder, self.proj, self.view);
+        if self.tilemap.is_none() {
+            let view: UniformMat4 = cgmath::Matrix4::identity().into();
+            self.loading_bar.width = LOADING_BAR_WIDTH * self.loader.progress();
+            self.loading_bar.render(encoder, self.camera.proj(), view);
+            return;
         }
 
-        self.player.render(encoder, self.proj, self.view);
+        let mut queue: RenderQueue<R, gfx_device_gl::CommandBuffer> = RenderQueue::new();
+        queue.sort_layer(LAYER_UNITS, SortMode::Y);
+
+        for layer in self.layers.iter_mut() {
+            queue.submit(LAYER_GROUND, layer);
+        }
+        queue.submit(LAYER_UNITS, &mut self.player);
+
+        queue.flush(encoder, self.camera.proj(), self.effects.view(&self.camera));
     }
 }
 
 pub fn main() {
-    let mut game = game::App::<TankGame>::new("Test", WINDOW_WIDTH, WINDOW_HEIGHT);
-    game.run();
+    let args: Vec<String> = std::env::args().collect();
+    let result = game::App::<TankGame>::with_virtual_resolution(
+        "Test", WINDOW_WIDTH, WINDOW_HEIGHT,
+        WORLD_WIDTH as u16, WORLD_HEIGHT as u16, Scaling::Fractional)
+        .and_then(|mut game| game.run_with_args(&args));
+    if let Err(err) = result {
+        println!("{}", err);
+        std::process::exit(1);
+    }
 }
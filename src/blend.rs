@@ -0,0 +1,153 @@
+use std::cell::{Ref, RefCell};
+
+use gfx;
+use gfx::state::{Blend, BlendChannel, BlendValue, Equation, Factor};
+
+use error::Result;
+
+/// Whether colours coming out of the shaders have already been multiplied
+/// by their alpha.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlphaMode {
+    Straight,
+    Premultiplied,
+}
+
+impl AlphaMode {
+    /// Convert a straight RGBA colour, such as a tint, to this mode.
+    pub fn color(self, color: [f32; 4]) -> [f32; 4] {
+        match self {
+            AlphaMode::Straight => color,
+            AlphaMode::Premultiplied => [color[0] * color[3], color[1] * color[3], color[2] * color[3], color[3]],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BlendMode {
+    /// Ordinary transparency.
+    Alpha,
+    /// Brighten what's underneath, for flashes and explosions.
+    Add,
+    /// Darken what's underneath, for shadows.
+    Multiply,
+    /// Lighten what's underneath without blowing it out like `Add`.
+    Screen,
+    /// Overwrite what's underneath, alpha included.
+    Replace,
+}
+
+const MODES: [BlendMode; 5] = [
+    BlendMode::Alpha,
+    BlendMode::Add,
+    BlendMode::Multiply,
+    BlendMode::Screen,
+    BlendMode::Replace,
+];
+
+fn channel(source: Factor, destination: Factor) -> BlendChannel {
+    BlendChannel {
+        equation: Equation::Add,
+        source: source,
+        destination: destination,
+    }
+}
+
+impl BlendMode {
+    pub fn all() -> &'static [BlendMode] {
+        &MODES
+    }
+
+    fn index(self) -> usize {
+        MODES.iter().position(|&mode| mode == self).unwrap()
+    }
+
+    pub fn state(self, alpha: AlphaMode) -> Blend {
+        // With premultiplied colours the source factor is always One; with
+        // straight colours the shader's alpha has to be applied here.
+        let source = match alpha {
+            AlphaMode::Straight => Factor::ZeroPlus(BlendValue::SourceAlpha),
+            AlphaMode::Premultiplied => Factor::One,
+        };
+        let over = channel(Factor::One, Factor::OneMinus(BlendValue::SourceAlpha));
+
+        match self {
+            BlendMode::Alpha => Blend {
+                color: channel(source, Factor::OneMinus(BlendValue::SourceAlpha)),
+                alpha: over,
+            },
+            BlendMode::Add => Blend {
+                color: channel(source, Factor::One),
+                alpha: over,
+            },
+            BlendMode::Multiply => Blend {
+                // dst * src where opaque, dst where transparent; straight
+                // colours can't fade out, so they multiply everywhere
+                color: match alpha {
+                    AlphaMode::Straight => channel(Factor::ZeroPlus(BlendValue::DestColor), Factor::Zero),
+                    AlphaMode::Premultiplied => channel(Factor::ZeroPlus(BlendValue::DestColor),
+                                                        Factor::OneMinus(BlendValue::SourceAlpha)),
+                },
+                alpha: over,
+            },
+            BlendMode::Screen => Blend {
+                // 1 - (1 - src)(1 - dst); as with multiply, straight
+                // colours ignore alpha
+                color: match alpha {
+                    AlphaMode::Straight => channel(Factor::OneMinus(BlendValue::DestColor), Factor::One),
+                    AlphaMode::Premultiplied => channel(Factor::One, Factor::OneMinus(BlendValue::SourceColor)),
+                },
+                alpha: over,
+            },
+            BlendMode::Replace => Blend {
+                color: channel(Factor::One, Factor::Zero),
+                alpha: channel(Factor::One, Factor::Zero),
+            },
+        }
+    }
+}
+
+/// One pipeline per blend mode, since gfx bakes blend state into the
+/// pipeline.
+pub struct PipelineSet<R: gfx::Resources, M> {
+    alpha: AlphaMode,
+    psos: RefCell<Vec<gfx::PipelineState<R, M>>>,
+}
+
+fn build_all<R, M, B>(alpha: AlphaMode, build: &mut B) -> Result<Vec<gfx::PipelineState<R, M>>>
+    where R: gfx::Resources,
+          B: FnMut(Blend) -> Result<gfx::PipelineState<R, M>> {
+    let mut psos = Vec::new();
+    for mode in BlendMode::all() {
+        psos.push(try!(build(mode.state(alpha))));
+    }
+    Ok(psos)
+}
+
+impl<R: gfx::Resources, M> PipelineSet<R, M> {
+    /// Build every pipeline by calling `build` with each mode's blend state.
+    pub fn new<B>(alpha: AlphaMode, mut build: B) -> Result<PipelineSet<R, M>>
+        where B: FnMut(Blend) -> Result<gfx::PipelineState<R, M>> {
+        Ok(PipelineSet {
+            alpha: alpha,
+            psos: RefCell::new(try!(build_all(alpha, &mut build))),
+        })
+    }
+
+    pub fn alpha(&self) -> AlphaMode {
+        self.alpha
+    }
+
+    pub fn get(&self, mode: BlendMode) -> Ref<gfx::PipelineState<R, M>> {
+        Ref::map(self.psos.borrow(), |psos| &psos[mode.index()])
+    }
+
+    /// Rebuild every pipeline, e.g. after the shaders changed. If any fails
+    /// to build, the old pipelines are all kept.
+    pub fn rebuild<B>(&self, mut build: B) -> Result<()>
+        where B: FnMut(Blend) -> Result<gfx::PipelineState<R, M>> {
+        let psos = try!(build_all(self.alpha, &mut build));
+        *self.psos.borrow_mut() = psos;
+        Ok(())
+    }
+}
@@ -1,5 +1,8 @@
 use gfx::{self, Bundle, texture};
 use gfx::traits::FactoryExt;
+use blend::{AlphaMode, BlendMode};
+use error::{self, Result};
+use render_queue::Drawable;
 pub use types::*;
 
 gfx_defines! {
@@ -21,6 +24,18 @@ gfx_defines! {
     }
 }
 
+fn build_pipeline<F, R>(factory: &mut F, alpha: AlphaMode) -> Result<gfx::PipelineState<R, blur::Meta>>
+    where F: gfx::Factory<R>,
+          R: gfx::Resources {
+    factory.create_pipeline_simple(
+        include_bytes!("shader/blur_150.glslv"),
+        include_bytes!("shader/blur_150.glslf"),
+        blur::Init {
+            out: ("Target0", gfx::state::ColorMask::all(), BlendMode::Alpha.state(alpha)),
+            ..blur::new()
+        }).map_err(error::shader)
+}
+
 pub struct Blur<R: gfx::Resources> {
     bundle: Bundle<R, blur::Data<R>>,
     pub strength: f32,
@@ -28,10 +43,11 @@ pub struct Blur<R: gfx::Resources> {
 }
 
 impl<R: gfx::Resources> Blur<R> {
-    pub fn new<F>(factory: &mut F, target: &gfx::handle::RenderTargetView<R, ColorFormat>, width: f32, height: f32) -> Blur<R> where F: gfx::Factory<R> {
+    pub fn new<F>(factory: &mut F, target: &gfx::handle::RenderTargetView<R, ColorFormat>, width: f32, height: f32) -> Result<Blur<R>> where F: gfx::Factory<R> {
          let (buf_width, buf_height, _, _) = target.get_dimensions();
         // TODO: want a non-sRGB intermediate buffer
-        let (_, srv, rtv) = factory.create_render_target::<ColorFormat>(buf_width, buf_height).unwrap();
+        let (_, srv, rtv) = try!(factory.create_render_target::<ColorFormat>(buf_width, buf_height)
+                                 .map_err(error::resource));
 
         let vertices = [
             BlurVertex { pos: [0.0, 0.0], uv: [0.0, 0.0] },
@@ -41,10 +57,7 @@ impl<R: gfx::Resources> Blur<R> {
         ];
         let indices: [u16; 6] = [ 0, 1, 3, 0, 3, 2 ];
         let (vbuf, slice) = factory.create_vertex_buffer_with_slice(&vertices, &indices as &[u16]);
-        let pso = factory.create_pipeline_simple(
-            include_bytes!("shader/blur_150.glslv"),
-            include_bytes!("shader/blur_150.glslf"),
-            blur::new()).unwrap();
+        let pso = try!(build_pipeline(factory, AlphaMode::Straight));
         let sampler = factory.create_sampler(
             texture::SamplerInfo::new(texture::FilterMethod::Scale,
                                       texture::WrapMode::Clamp)
@@ -57,11 +70,34 @@ impl<R: gfx::Resources> Blur<R> {
         };
         let bundle = Bundle::new(slice, pso, data);
 
-        Blur {
+        Ok(Blur {
             bundle: bundle,
             strength: 0.0,
             rtv: rtv,
-        }
+        })
+    }
+
+    /// Recreate the intermediate buffer to match a resized `target`.
+    /// Anything drawing into the old `rtv` has to be pointed at the new one.
+    pub fn resize<F>(&mut self, factory: &mut F, target: &gfx::handle::RenderTargetView<R, ColorFormat>)
+                     -> Result<()>
+        where F: gfx::Factory<R> {
+        let (buf_width, buf_height, _, _) = target.get_dimensions();
+        let (_, srv, rtv) = try!(factory.create_render_target::<ColorFormat>(buf_width, buf_height)
+                                 .map_err(error::resource));
+        self.bundle.data.texture.0 = srv;
+        self.bundle.data.out = target.clone();
+        self.rtv = rtv;
+        Ok(())
+    }
+
+    /// Composite with premultiplied alpha, for when everything drawn into
+    /// `rtv` is premultiplied. Blurring premultiplied colours also stops
+    /// transparent pixels darkening the edges.
+    pub fn set_alpha_mode<F>(&mut self, factory: &mut F, alpha: AlphaMode) -> Result<()>
+        where F: gfx::Factory<R> {
+        self.bundle.pso = try!(build_pipeline(factory, alpha));
+        Ok(())
     }
 
     pub fn render<C>(&mut self,
@@ -78,3 +114,14 @@ impl<R: gfx::Resources> Blur<R> {
         self.bundle.encode(encoder);
     }
 }
+
+impl<R, C> Drawable<R, C> for Blur<R>
+    where R: gfx::Resources,
+          C: gfx::CommandBuffer<R> {
+    fn draw(&mut self,
+            encoder: &mut gfx::Encoder<R, C>,
+            proj: UniformMat4,
+            view: UniformMat4) {
+        self.render(encoder, proj, view);
+    }
+}
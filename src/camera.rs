@@ -0,0 +1,318 @@
+use cgmath::{self, Rotation3};
+
+use transform;
+use types::*;
+
+/// The smallest zoom `set_zoom` allows.
+pub const MIN_ZOOM: f32 = 0.001;
+
+/// A rectangle in world units.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bounds {
+    pub left: f32,
+    pub bottom: f32,
+    pub right: f32,
+    pub top: f32,
+}
+
+/// A camera for 2D scenes, producing the `proj` and `view` matrices every
+/// drawable's `render` takes.
+pub struct Camera2D {
+    width: f32,
+    height: f32,
+    screen_width: f32,
+    screen_height: f32,
+    /// The world point shown at the centre of the screen.
+    pub position: cgmath::Vector2<f32>,
+    zoom: f32,
+    /// Counter-clockwise rotation of the camera, in radians.
+    pub rotation: f32,
+    /// Half the size of the box around the centre that a followed target
+    /// can move in without moving the camera.
+    pub deadzone: cgmath::Vector2<f32>,
+    /// The fraction of the distance to a followed target that is left
+    /// after each `follow`. 0 keeps up exactly; closer to 1 lags more.
+    pub damping: f32,
+    /// Keep the view inside this area, if it's big enough.
+    pub bounds: Option<Bounds>,
+    /// Where `follow` moved from, and in which tick.
+    previous: (cgmath::Vector2<f32>, u64),
+}
+
+impl Camera2D {
+    /// A camera showing `width` by `height` world units at zoom 1,
+    /// centred on the middle of that area.
+    pub fn new(width: f32, height: f32) -> Camera2D {
+        Camera2D {
+            width: width,
+            height: height,
+            screen_width: width,
+            screen_height: height,
+            position: cgmath::vec2(width / 2.0, height / 2.0),
+            zoom: 1.0,
+            rotation: 0.0,
+            deadzone: cgmath::vec2(0.0, 0.0),
+            damping: 0.0,
+            bounds: None,
+            previous: (cgmath::vec2(width / 2.0, height / 2.0), transform::current_tick()),
+        }
+    }
+
+    pub fn width(&self) -> f32 {
+        self.width
+    }
+
+    pub fn height(&self) -> f32 {
+        self.height
+    }
+
+    /// Magnification; 2 shows half as much of the world.
+    pub fn zoom(&self) -> f32 {
+        self.zoom
+    }
+
+    /// Set the magnification, which is kept at `MIN_ZOOM` or more.
+    pub fn set_zoom(&mut self, zoom: f32) {
+        self.zoom = zoom.max(MIN_ZOOM);
+    }
+
+    /// Set the size of the window in pixels, for converting mouse
+    /// coordinates.
+    pub fn set_screen_size(&mut self, width: f32, height: f32) {
+        self.screen_width = width;
+        self.screen_height = height;
+    }
+
+    pub fn proj(&self) -> UniformMat4 {
+        self.proj_matrix().into()
+    }
+
+    pub fn view(&self) -> UniformMat4 {
+        self.view_matrix().into()
+    }
+
+    pub fn proj_matrix(&self) -> cgmath::Matrix4<f32> {
+        cgmath::ortho(0.0, self.width, 0.0, self.height, 0.0, 1.0)
+    }
+
+    /// The position to draw from, blended like a `Transform` when `follow`
+    /// moved the camera this tick.
+    fn interpolated_position(&self) -> cgmath::Vector2<f32> {
+        let (previous, tick) = self.previous;
+        if tick == transform::current_tick() {
+            previous + (self.position - previous) * transform::alpha()
+        }
+        else {
+            self.position
+        }
+    }
+
+    pub fn view_matrix(&self) -> cgmath::Matrix4<f32> {
+        let position = self.interpolated_position();
+        let to_screen_center = cgmath::Matrix4::from_translation(
+            cgmath::vec3(self.width / 2.0, self.height / 2.0, 0.0));
+        let zoom = cgmath::Matrix4::from_nonuniform_scale(self.zoom, self.zoom, 1.0);
+        // Turning the camera one way turns the world the other
+        let rotation: cgmath::Matrix4<f32> = cgmath::Decomposed {
+            scale: 1.0,
+            rot: cgmath::Basis3::from_angle_z(cgmath::Rad { s: -self.rotation }),
+            disp: cgmath::vec3(0.0, 0.0, 0.0),
+        }.into();
+        let from_position = cgmath::Matrix4::from_translation(
+            cgmath::vec3(-position.x, -position.y, 0.0));
+
+        to_screen_center * zoom * rotation * from_position
+    }
+
+    /// Move towards `target`, once per tick. The camera only moves once the
+    /// target leaves the deadzone, and is slowed by `damping`.
+    pub fn follow(&mut self, target: cgmath::Vector2<f32>) {
+        fn axis(position: f32, target: f32, deadzone: f32) -> f32 {
+            if target > position + deadzone {
+                target - deadzone
+            }
+            else if target < position - deadzone {
+                target + deadzone
+            }
+            else {
+                position
+            }
+        }
+
+        self.previous = (self.position, transform::current_tick());
+        let desired = cgmath::vec2(axis(self.position.x, target.x, self.deadzone.x),
+                                   axis(self.position.y, target.y, self.deadzone.y));
+        self.position = desired + (self.position - desired) * self.damping;
+        self.clamp_to_bounds();
+    }
+
+    /// Move the camera back inside `bounds`. An area smaller than the view
+    /// is centred instead.
+    pub fn clamp_to_bounds(&mut self) {
+        let bounds = match self.bounds {
+            Some(bounds) => bounds,
+            None => return,
+        };
+
+        fn axis(position: f32, min: f32, max: f32, extent: f32) -> f32 {
+            if max - min < 2.0 * extent {
+                (min + max) / 2.0
+            }
+            else {
+                position.max(min + extent).min(max - extent)
+            }
+        }
+
+        // Half the size of the axis-aligned box around the rotated view
+        let (half_width, half_height) = (self.width / (2.0 * self.zoom), self.height / (2.0 * self.zoom));
+        let (sin, cos) = (self.rotation.sin().abs(), self.rotation.cos().abs());
+        let extent_x = cos * half_width + sin * half_height;
+        let extent_y = sin * half_width + cos * half_height;
+
+        self.position.x = axis(self.position.x, bounds.left, bounds.right, extent_x);
+        self.position.y = axis(self.position.y, bounds.bottom, bounds.top, extent_y);
+    }
+
+    /// Convert window coordinates (in pixels, from the top left) to world
+    /// coordinates.
+    pub fn screen_to_world(&self, x: f32, y: f32) -> cgmath::Vector2<f32> {
+        // Undo view_matrix step by step: relative to the screen centre,
+        // unzoomed, turned back and offset by the position
+        let screen_x = x / self.screen_width * self.width - self.width / 2.0;
+        let screen_y = (1.0 - y / self.screen_height) * self.height - self.height / 2.0;
+        let (sin, cos) = self.rotation.sin_cos();
+        let position = self.interpolated_position();
+        cgmath::vec2(position.x + (cos * screen_x - sin * screen_y) / self.zoom,
+                     position.y + (sin * screen_x + cos * screen_y) / self.zoom)
+    }
+
+    /// Convert world coordinates to window coordinates (in pixels, from the
+    /// top left).
+    pub fn world_to_screen(&self, x: f32, y: f32) -> cgmath::Vector2<f32> {
+        let clip = self.proj_matrix() * self.view_matrix() * cgmath::vec4(x, y, 0.0, 1.0);
+        cgmath::vec2((clip.x + 1.0) / 2.0 * self.screen_width,
+                     (1.0 - clip.y) / 2.0 * self.screen_height)
+    }
+}
+
+// Hash a lattice point to [-1, 1]
+fn hash(seed: u32, channel: u32, i: u32) -> f32 {
+    let mut x = seed ^ channel.wrapping_mul(0x9e3779b9) ^ i.wrapping_mul(0x85ebca6b);
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^= x >> 16;
+    (x as f32 / u32::max_value() as f32) * 2.0 - 1.0
+}
+
+// Smooth 1D value noise in [-1, 1]
+fn noise(seed: u32, channel: u32, t: f32) -> f32 {
+    let i = t.floor();
+    let f = t - i;
+    let f = f * f * (3.0 - 2.0 * f);
+    let i = i as i32 as u32;
+    let a = hash(seed, channel, i);
+    let b = hash(seed, channel, i.wrapping_add(1));
+    a + (b - a) * f
+}
+
+/// Screen shake, kicks and zoom pulses, layered on top of a camera's view.
+///
+/// Everything advances by `tick` alone and the shake comes from noise
+/// seeded by `seed`, so the same seed and the same calls give the same
+/// motion.
+pub struct CameraEffects {
+    seed: u32,
+    time: f32,
+    trauma: f32,
+    kick: cgmath::Vector2<f32>,
+    zoom_pulse: f32,
+    /// Trauma lost per tick.
+    pub decay: f32,
+    /// How far the view moves at full trauma, in world units at zoom 1.
+    pub max_offset: f32,
+    /// How far the view turns at full trauma, in radians.
+    pub max_angle: f32,
+    /// How quickly the shake changes direction, in noise cells per tick.
+    pub frequency: f32,
+    /// The fraction of a kick or zoom pulse left after each tick.
+    pub recovery: f32,
+}
+
+impl CameraEffects {
+    pub fn new(seed: u32) -> CameraEffects {
+        CameraEffects {
+            seed: seed,
+            time: 0.0,
+            trauma: 0.0,
+            kick: cgmath::vec2(0.0, 0.0),
+            zoom_pulse: 0.0,
+            decay: 0.02,
+            max_offset: 12.0,
+            max_angle: 0.05,
+            frequency: 0.5,
+            recovery: 0.8,
+        }
+    }
+
+    pub fn trauma(&self) -> f32 {
+        self.trauma
+    }
+
+    /// Add trauma, up to a maximum of 1. Shake grows with the square of
+    /// trauma, so small hits barely register and big ones stack up.
+    pub fn add_trauma(&mut self, amount: f32) {
+        self.trauma = (self.trauma + amount).max(0.0).min(1.0);
+    }
+
+    /// Jolt the view `distance` units along `direction`, easing back
+    /// afterwards.
+    pub fn kick(&mut self, direction: cgmath::Vector2<f32>, distance: f32) {
+        let length = (direction.x * direction.x + direction.y * direction.y).sqrt();
+        if length > 0.0 {
+            self.kick = self.kick + direction * (distance / length);
+        }
+    }
+
+    /// Zoom in by `amount` (0.1 is 10%) and ease back out.
+    pub fn pulse_zoom(&mut self, amount: f32) {
+        self.zoom_pulse += amount;
+    }
+
+    /// Advance by one tick.
+    pub fn tick(&mut self) {
+        self.time += self.frequency;
+        self.trauma = (self.trauma - self.decay).max(0.0);
+        self.kick = self.kick * self.recovery;
+        self.zoom_pulse *= self.recovery;
+    }
+
+    /// The camera's view with the effects applied.
+    pub fn view(&self, camera: &Camera2D) -> UniformMat4 {
+        self.view_matrix(camera).into()
+    }
+
+    pub fn view_matrix(&self, camera: &Camera2D) -> cgmath::Matrix4<f32> {
+        let shake = self.trauma * self.trauma;
+        // Sample the noise between ticks too, so the shake stays smooth
+        let time = self.time - self.frequency * (1.0 - transform::alpha());
+        let offset = cgmath::vec3(
+            self.kick.x + self.max_offset * shake * noise(self.seed, 0, time),
+            self.kick.y + self.max_offset * shake * noise(self.seed, 1, time),
+            0.0);
+        let angle = self.max_angle * shake * noise(self.seed, 2, time);
+
+        // Turn and zoom around the middle of the screen
+        let center = cgmath::vec3(camera.width() / 2.0, camera.height() / 2.0, 0.0);
+        let rotation: cgmath::Matrix4<f32> = cgmath::Decomposed {
+            scale: 1.0 + self.zoom_pulse,
+            rot: cgmath::Basis3::from_angle_z(cgmath::Rad { s: angle }),
+            disp: cgmath::vec3(0.0, 0.0, 0.0),
+        }.into();
+        let effects = cgmath::Matrix4::from_translation(center) * rotation *
+            cgmath::Matrix4::from_translation(-center - offset);
+
+        effects * camera.view_matrix()
+    }
+}
@@ -1,7 +1,14 @@
+use std::rc::Rc;
+
 use cgmath;
 use gfx;
 use gfx::traits::FactoryExt;
 
+use blend::{AlphaMode, BlendMode, PipelineSet};
+use error::{self, Result};
+use render_queue::Drawable;
+use transform::Transform;
+
 pub use types::*;
 
 gfx_defines! {
@@ -24,17 +31,38 @@ gfx_defines! {
     }
 }
 
-pub struct Circle<R: gfx::Resources> {
-    pso: gfx::PipelineState<R, pipe::Meta>,
-    data: pipe::Data<R>,
-    slice: gfx::Slice<R>,
-    pub position: cgmath::Vector3<f32>,
-    pub r: f32,
-    pub color: [f32; 3],
+/// Creates circles sharing one set of pipelines, one per blend mode, so
+/// keep one around rather than making one per circle.
+pub struct CircleFactory<R: gfx::Resources> {
+    pso: Rc<PipelineSet<R, pipe::Meta>>,
 }
 
-impl<R: gfx::Resources> Circle<R> {
-    pub fn new<F>(
+impl<R: gfx::Resources> CircleFactory<R> {
+    pub fn new<F>(factory: &mut F) -> Result<CircleFactory<R>>
+        where F: gfx::Factory<R> {
+        // Shaders based on
+        // http://www.desultoryquest.com/blog/drawing-anti-aliased-circular-points-using-opengl-slash-webgl/
+        let shader_set = try!(factory.create_shader_set(
+            include_bytes!("shader/circle_150.glslv"),
+            include_bytes!("shader/circle_150.glslf")).map_err(error::shader));
+        let pso = try!(PipelineSet::new(AlphaMode::Straight, |blend| {
+            factory.create_pipeline_state(
+                &shader_set,
+                gfx::Primitive::PointList,
+                gfx::state::Rasterizer::new_fill(),
+                pipe::Init {
+                    out: ("Target0", gfx::state::ColorMask::all(), blend),
+                    ..pipe::new()
+                },
+            ).map_err(error::shader)
+        }));
+        Ok(CircleFactory {
+            pso: Rc::new(pso),
+        })
+    }
+
+    pub fn create<F>(
+        &self,
         factory: &mut F,
         target: gfx::handle::RenderTargetView<R, ColorFormat>,
         color: [f32; 3],
@@ -48,17 +76,6 @@ impl<R: gfx::Resources> Circle<R> {
         ];
         let (vertex_buffer, slice) = factory.create_vertex_buffer_with_slice(
             &vertices, &indices as &[u16]);
-        // Shaders based on
-        // http://www.desultoryquest.com/blog/drawing-anti-aliased-circular-points-using-opengl-slash-webgl/
-        let shader_set = factory.create_shader_set(
-            include_bytes!("shader/circle_150.glslv"),
-            include_bytes!("shader/circle_150.glslf")).unwrap();
-        let pso = factory.create_pipeline_state(
-            &shader_set,
-            gfx::Primitive::PointList,
-            gfx::state::Rasterizer::new_fill(),
-            pipe::new(),
-        ).unwrap();
         let data = pipe::Data {
             vbuf: vertex_buffer,
             locals: factory.create_constant_buffer(1),
@@ -66,24 +83,36 @@ impl<R: gfx::Resources> Circle<R> {
         };
 
         Circle {
-            pso: pso,
+            pso: self.pso.clone(),
             data: data,
             slice: slice,
-            position: cgmath::vec3(0.0, 0.0, 0.0),
+            transform: Transform::new(),
             r: r,
             color: color,
+            blend: BlendMode::Alpha,
         }
     }
+}
+
+pub struct Circle<R: gfx::Resources> {
+    pso: Rc<PipelineSet<R, pipe::Meta>>,
+    data: pipe::Data<R>,
+    slice: gfx::Slice<R>,
+    /// Places the bottom left of the circle's bounding box.
+    pub transform: Transform,
+    pub r: f32,
+    pub color: [f32; 3],
+    pub blend: BlendMode,
+}
 
+impl<R: gfx::Resources> Circle<R> {
     pub fn render<C>(&mut self,
                  encoder: &mut gfx::Encoder<R, C>,
                  proj: UniformMat4,
                  view: UniformMat4)
         where C: gfx::CommandBuffer<R> {
-        // TODO: cache recomputation of model matrix where possible
-        let translate_to_position = cgmath::Matrix4::from_translation(self.position + cgmath::vec3(self.r, self.r, 0.0));
-
-        let model = translate_to_position;
+        let to_center = cgmath::Matrix4::from_translation(cgmath::vec3(self.r, self.r, 0.0));
+        let model = self.transform.interpolated_matrix() * to_center;
 
         let locals = Locals {
             proj: proj,
@@ -94,6 +123,21 @@ impl<R: gfx::Resources> Circle<R> {
         };
 
         encoder.update_buffer(&self.data.locals, &[locals], 0).unwrap();
-        encoder.draw(&self.slice, &self.pso, &self.data);
+        encoder.draw(&self.slice, &*self.pso.get(self.blend), &self.data);
+    }
+}
+
+impl<R, C> Drawable<R, C> for Circle<R>
+    where R: gfx::Resources,
+          C: gfx::CommandBuffer<R> {
+    fn draw(&mut self,
+            encoder: &mut gfx::Encoder<R, C>,
+            proj: UniformMat4,
+            view: UniformMat4) {
+        self.render(encoder, proj, view);
+    }
+
+    fn sort_position(&self) -> cgmath::Vector3<f32> {
+        self.transform.world_position()
     }
 }
@@ -0,0 +1,123 @@
+use error::{Error, Result};
+
+/// The fastest tick rate allowed, one tick a nanosecond.
+pub const MAX_TICK_RATE: u32 = 1000000000;
+
+/// How `App` steps the game.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeConfig {
+    /// Ticks per second, from 1 to `MAX_TICK_RATE`.
+    pub tick_rate: u32,
+    /// The most ticks run in one frame. Time beyond that is dropped, so
+    /// after a long stall the game slows down briefly instead of locking
+    /// up trying to catch up. Never 0.
+    pub max_ticks_per_frame: u32,
+    /// Multiplies elapsed time: 0.5 for half speed, 0 to pause. Never
+    /// negative.
+    pub time_scale: f64,
+    /// Stop ticking on the clock, and only run the ticks asked for with
+    /// `steps`.
+    pub single_step: bool,
+    /// Ticks to run next frame in single-step mode. `App` resets this once
+    /// they've run.
+    pub steps: u32,
+}
+
+impl Default for TimeConfig {
+    fn default() -> TimeConfig {
+        TimeConfig {
+            tick_rate: 50,
+            max_ticks_per_frame: 5,
+            time_scale: 1.0,
+            single_step: false,
+            steps: 0,
+        }
+    }
+}
+
+impl TimeConfig {
+    /// Check the settings are usable; `App` refuses any that aren't.
+    pub fn validate(&self) -> Result<()> {
+        if self.tick_rate == 0 || self.tick_rate > MAX_TICK_RATE {
+            return Err(Error::Config(format!("tick rate must be from 1 to {}", MAX_TICK_RATE)));
+        }
+        if self.max_ticks_per_frame == 0 {
+            return Err(Error::Config("max ticks per frame must not be 0".to_string()));
+        }
+        if !(self.time_scale >= 0.0) || self.time_scale.is_infinite() {
+            return Err(Error::Config("time scale must be 0 or more".to_string()));
+        }
+        Ok(())
+    }
+
+    /// The length of a tick in nanoseconds.
+    pub fn tick_time(&self) -> u64 {
+        1000000000 / self.tick_rate as u64
+    }
+}
+
+const AVERAGE_WEIGHT: f32 = 0.1;
+
+/// Timing of recent frames, for debug overlays and tuning.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameStats {
+    /// Wall-clock length of the last frame, in seconds.
+    pub frame_time: f32,
+    /// A moving average of `frame_time`.
+    pub average_frame_time: f32,
+    /// The longest frame so far, in seconds.
+    pub max_frame_time: f32,
+    /// Ticks run in the last frame.
+    pub ticks: u32,
+    /// Ticks run altogether.
+    pub total_ticks: u64,
+    /// Ticks skipped altogether because of `max_ticks_per_frame`.
+    pub dropped_ticks: u64,
+    pub frames: u64,
+}
+
+impl FrameStats {
+    /// Frames per second, from the average frame time.
+    pub fn fps(&self) -> f32 {
+        if self.average_frame_time > 0.0 {
+            1.0 / self.average_frame_time
+        }
+        else {
+            0.0
+        }
+    }
+
+    /// Record the start of a frame `elapsed` nanoseconds after the last.
+    pub fn begin_frame(&mut self, elapsed: u64) {
+        self.frame_time = elapsed as f32 / 1e9;
+        self.average_frame_time = if self.frames == 0 {
+            self.frame_time
+        }
+        else {
+            self.average_frame_time + (self.frame_time - self.average_frame_time) * AVERAGE_WEIGHT
+        };
+        self.max_frame_time = self.max_frame_time.max(self.frame_time);
+        self.frames += 1;
+        self.ticks = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MAX_TICK_RATE, TimeConfig};
+
+    #[test]
+    fn validate() {
+        assert!(TimeConfig::default().validate().is_ok());
+        let fastest = TimeConfig { tick_rate: MAX_TICK_RATE, ..Default::default() };
+        assert!(fastest.validate().is_ok());
+        assert_eq!(fastest.tick_time(), 1);
+
+        assert!(TimeConfig { tick_rate: 0, ..Default::default() }.validate().is_err());
+        assert!(TimeConfig { tick_rate: MAX_TICK_RATE + 1, ..Default::default() }.validate().is_err());
+        assert!(TimeConfig { max_ticks_per_frame: 0, ..Default::default() }.validate().is_err());
+        assert!(TimeConfig { time_scale: -1.0, ..Default::default() }.validate().is_err());
+        assert!(TimeConfig { time_scale: ::std::f64::NAN, ..Default::default() }.validate().is_err());
+        assert!(TimeConfig { time_scale: 0.0, ..Default::default() }.validate().is_ok());
+    }
+}
@@ -0,0 +1,100 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+use image;
+use tiled;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading an asset from disk failed.
+    Io(io::Error),
+    /// An image couldn't be decoded.
+    Image(image::ImageError),
+    /// A TMX map couldn't be parsed.
+    Tmx(tiled::TiledError),
+    /// The GPU refused to create a texture, buffer or render target.
+    Resource(String),
+    /// A shader failed to compile or link, or its pipeline couldn't be built.
+    Shader(String),
+    /// An atlas metadata file was malformed, or images didn't fit when packing.
+    Atlas(String),
+    /// An input bindings file was malformed.
+    Bindings(String),
+    /// A recording was malformed, or replaying it didn't reproduce the
+    /// recorded state.
+    Replay(String),
+    /// A setting was out of range.
+    Config(String),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+// gfx's error types differ between calls and don't all implement Display,
+// so keep their Debug output instead.
+pub fn resource<E: fmt::Debug>(err: E) -> Error {
+    Error::Resource(format!("{:?}", err))
+}
+
+pub fn shader<E: fmt::Debug>(err: E) -> Error {
+    Error::Shader(format!("{:?}", err))
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "I/O error: {}", err),
+            Error::Image(ref err) => write!(f, "could not decode image: {}", err),
+            Error::Tmx(ref err) => write!(f, "could not parse map: {:?}", err),
+            Error::Resource(ref err) => write!(f, "could not create GPU resource: {}", err),
+            Error::Shader(ref err) => write!(f, "could not build shader pipeline: {}", err),
+            Error::Atlas(ref err) => write!(f, "invalid atlas: {}", err),
+            Error::Bindings(ref err) => write!(f, "invalid bindings: {}", err),
+            Error::Replay(ref err) => write!(f, "replay failed: {}", err),
+            Error::Config(ref err) => write!(f, "invalid setting: {}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(_) => "I/O error",
+            Error::Image(_) => "could not decode image",
+            Error::Tmx(_) => "could not parse map",
+            Error::Resource(_) => "could not create GPU resource",
+            Error::Shader(_) => "could not build shader pipeline",
+            Error::Atlas(_) => "invalid atlas",
+            Error::Bindings(_) => "invalid bindings",
+            Error::Replay(_) => "replay failed",
+            Error::Config(_) => "invalid setting",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            Error::Image(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(err: image::ImageError) -> Error {
+        Error::Image(err)
+    }
+}
+
+impl From<tiled::TiledError> for Error {
+    fn from(err: tiled::TiledError) -> Error {
+        Error::Tmx(err)
+    }
+}
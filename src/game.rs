@@ -1,18 +1,83 @@
+use std::io::{self, Write};
+use std::path::Path;
+
 use gfx::{self, Device};
 use gfx_device_gl;
 use gfx_window_glutin;
 use glutin;
 use time;
 
+use clock::{FrameStats, TimeConfig};
+use error::{Error, Result};
+use gamepad::{GamepadEvent, Gamepads};
+use letterbox::{Letterbox, Scaling};
+use loader::Loader;
+use replay::{self, Record, Recorder, Replay};
+use transform;
 use types::*;
 
-pub const TICK_TIME: u64 = 20 * 1000000;
+const LETTERBOX_COLOR: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
+
+/// The state of the window, passed to `Game::resize`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WindowInfo {
+    /// The size of the window in pixels.
+    pub width: u32,
+    pub height: u32,
+    /// Pixels per point; 2 on a typical HiDPI display.
+    pub hidpi_factor: f32,
+    pub fullscreen: bool,
+}
 
-pub trait Game {
-    fn init(factory: &mut gfx_device_gl::Factory, main_color: &RenderTarget, main_depth: &DepthTarget) -> Self;
-    fn tick(&mut self);
+/// A game run by `App`. Errors from `init`, `tick` and `resize` stop the
+/// app and are returned from `App::new` or `App::run`.
+pub trait Game: Sized {
+    fn init(factory: &mut gfx_device_gl::Factory, main_color: &RenderTarget, main_depth: &DepthTarget) -> Result<Self>;
+    /// Create the game without a window or GL context, for
+    /// `App::replay_headless`. Only `tick`, `handle_event`, `handle_gamepad`
+    /// and `state_hash` are called on a game made this way, so it can leave
+    /// out everything that only draws; its loader is never finished, so it
+    /// shouldn't load anything through one. Games can't run headless
+    /// unless they override this.
+    fn init_headless() -> Result<Self> {
+        Err(Error::Replay("this game can't run headless".to_string()))
+    }
+    fn tick(&mut self) -> Result<()>;
+    /// Called for each window event. `Resized` comes with the window's size
+    /// in pixels once before the first tick and whenever it changes; ticks
+    /// that depend on the window size should take it from there, since it's
+    /// recorded and `resize` isn't called in headless replays.
     fn handle_event(&mut self, _event: &glutin::Event) {}
-    fn render(&mut self, encoder: &mut GLEncoder, target: &RenderTarget);
+    /// Called for each event from the gamepads returned by `gamepads`.
+    fn handle_gamepad(&mut self, _event: &GamepadEvent) {}
+    /// Draw the game. `alpha` is how far between the last tick and the
+    /// next this frame falls, from 0 to 1; library drawables already blend
+    /// their transforms by it.
+    fn render(&mut self, encoder: &mut GLEncoder, target: &RenderTarget, alpha: f32);
+    /// The loader whose decoded assets the app should upload each frame.
+    fn loader(&mut self) -> Option<&mut Loader<gfx_device_gl::Resources>> { None }
+    /// The gamepads the app should poll each frame, feeding their events
+    /// to `handle_gamepad`.
+    fn gamepads(&mut self) -> Option<&mut Gamepads> { None }
+    /// Called when the window changes size, and once before the first
+    /// frame. Without a virtual resolution the targets have been resized,
+    /// so anything drawing into old clones of them should be recreated.
+    fn resize(&mut self,
+              _factory: &mut gfx_device_gl::Factory,
+              _main_color: &RenderTarget,
+              _main_depth: &DepthTarget,
+              _info: &WindowInfo) -> Result<()> { Ok(()) }
+    /// Called every frame before ticking, to adjust the time config (e.g.
+    /// to pause or single-step) and to read frame timings.
+    fn update_time(&mut self, _time: &mut TimeConfig, _stats: &FrameStats) {}
+    /// How the cursor should look, e.g. `Input::cursor`. Checked after
+    /// every batch of events.
+    fn cursor_state(&self) -> glutin::CursorState { glutin::CursorState::Normal }
+    /// Whether the app should exit, checked after every frame.
+    fn quit(&self) -> bool { false }
+    /// A hash of everything ticking affects, which replays are checked
+    /// against. `replay::StateHasher` hashes the same on every build.
+    fn state_hash(&self) -> u64 { 0 }
 }
 
 pub struct App<G>
@@ -23,11 +88,32 @@ pub struct App<G>
     device: gfx_device_gl::Device,
     factory: gfx_device_gl::Factory,
     window: glutin::Window,
+    letterbox: Option<Letterbox<gfx_device_gl::Resources>>,
+    /// Where the window was and how big, while it's fullscreen.
+    windowed: Option<((i32, i32), (u32, u32))>,
+    fullscreen_key: Option<glutin::VirtualKeyCode>,
+    time: TimeConfig,
+    cursor: glutin::CursorState,
+    recorder: Option<Recorder>,
 }
 
 impl<G> App<G>
     where G: Game {
-    pub fn new(title: &str, width: u32, height: u32) -> App<G> {
+    pub fn new(title: &str, width: u32, height: u32) -> Result<App<G>> {
+        App::create(title, width, height, None)
+    }
+
+    /// Render the game at a fixed `virtual_width` by `virtual_height`,
+    /// scaled to fit the window. The game's targets and mouse coordinates
+    /// are all in virtual pixels.
+    pub fn with_virtual_resolution(title: &str, width: u32, height: u32,
+                                   virtual_width: u16, virtual_height: u16,
+                                   scaling: Scaling) -> Result<App<G>> {
+        App::create(title, width, height, Some((virtual_width, virtual_height, scaling)))
+    }
+
+    fn create(title: &str, width: u32, height: u32,
+              virtual_resolution: Option<(u16, u16, Scaling)>) -> Result<App<G>> {
         let builder = glutin::WindowBuilder::new()
             .with_title(title.to_string())
             .with_dimensions(width, height)
@@ -35,42 +121,434 @@ impl<G> App<G>
 
         let (window, device, mut factory, main_color, main_depth) =
             gfx_window_glutin::init::<ColorFormat, DepthFormat>(builder);
-        App {
-            game: G::init(&mut factory, &main_color, &main_depth),
+        let letterbox = match virtual_resolution {
+            Some((virtual_width, virtual_height, scaling)) => Some(try!(Letterbox::new(
+                &mut factory, virtual_width, virtual_height, scaling, main_color.clone()))),
+            None => None,
+        };
+        let game = try!(match letterbox {
+            Some(ref letterbox) => G::init(&mut factory, letterbox.color(), letterbox.depth()),
+            None => G::init(&mut factory, &main_color, &main_depth),
+        });
+
+        Ok(App {
+            game: game,
             main_color: main_color,
             main_depth: main_depth,
             device: device,
             factory: factory,
             window: window,
+            letterbox: letterbox,
+            windowed: None,
+            fullscreen_key: Some(glutin::VirtualKeyCode::F11),
+            time: TimeConfig::default(),
+            cursor: glutin::CursorState::Normal,
+            recorder: None,
+        })
+    }
+
+    pub fn time_config(&self) -> &TimeConfig {
+        &self.time
+    }
+
+    pub fn set_time_config(&mut self, time: TimeConfig) -> Result<()> {
+        try!(time.validate());
+        self.time = time;
+        Ok(())
+    }
+
+    pub fn fullscreen_key(&self) -> Option<glutin::VirtualKeyCode> {
+        self.fullscreen_key
+    }
+
+    /// Set the key that toggles fullscreen while running, F11 by default,
+    /// or `None` for no key. The game gets the key press either way.
+    pub fn set_fullscreen_key(&mut self, key: Option<glutin::VirtualKeyCode>) {
+        self.fullscreen_key = key;
+    }
+
+    pub fn window_info(&self) -> WindowInfo {
+        let (width, height, _, _) = self.main_color.get_dimensions();
+        WindowInfo {
+            width: width as u32,
+            height: height as u32,
+            hidpi_factor: self.window.hidpi_factor(),
+            fullscreen: self.windowed.is_some(),
+        }
+    }
+
+    /// Switch between windowed and covering the primary monitor.
+    ///
+    /// glutin can't change the fullscreen state of an open window, so this
+    /// moves and resizes it instead, and restores it afterwards.
+    pub fn toggle_fullscreen(&mut self) {
+        match self.windowed.take() {
+            Some(((x, y), (width, height))) => {
+                self.window.set_position(x, y);
+                self.window.set_inner_size(width, height);
+            }
+            None => {
+                let position = self.window.get_position().unwrap_or((0, 0));
+                let size = match self.window.get_inner_size_points() {
+                    Some(size) => size,
+                    None => return,
+                };
+                // The monitor size is in pixels, the window size in points
+                let (width, height) = glutin::get_primary_monitor().get_dimensions();
+                let scale = self.window.hidpi_factor();
+                self.window.set_position(0, 0);
+                self.window.set_inner_size((width as f32 / scale) as u32, (height as f32 / scale) as u32);
+                self.windowed = Some((position, size));
+            }
+        }
+    }
+
+    fn resize(&mut self) -> Result<()> {
+        gfx_window_glutin::update_views(&self.window, &mut self.main_color, &mut self.main_depth);
+        let info = self.window_info();
+        match self.letterbox {
+            Some(ref mut letterbox) => {
+                letterbox.set_window(self.main_color.clone());
+                self.game.resize(&mut self.factory, letterbox.color(), letterbox.depth(), &info)
+            }
+            None => self.game.resize(&mut self.factory, &self.main_color, &self.main_depth, &info),
+        }
+    }
+
+    /// Record every input event the game gets to `path` while running, to
+    /// play back with `replay_hidden`.
+    ///
+    /// While recording, anything the game's loader has in flight finishes
+    /// before each tick, so loading takes the same ticks on every run. If
+    /// the recording can't be written, it stops with a message on stderr
+    /// and the game goes on; failing to finish it is returned from `run`.
+    pub fn record<P>(&mut self, path: P) -> Result<()>
+        where P: AsRef<Path> {
+        self.recorder = Some(try!(Recorder::create(path, self.time.tick_rate)));
+        Ok(())
+    }
+
+    /// Play a recording made with `record` into the game as fast as
+    /// possible, with the window hidden and nothing rendered. The game's
+    /// `state_hash` is checked wherever the recording has one; returns the
+    /// final hash if they all match.
+    ///
+    /// The app already has its window and GL context, so this still takes a
+    /// display. Games that can be made with `Game::init_headless` can use
+    /// `replay_headless` instead.
+    pub fn replay_hidden<P>(&mut self, path: P) -> Result<u64>
+        where P: AsRef<Path> {
+        let replay = try!(Replay::load(path));
+        self.time.tick_rate = replay.tick_rate;
+        self.window.hide();
+        try!(self.resize());
+
+        let factory = &mut self.factory;
+        play(&mut self.game, replay, |game| tick(game, Some(&mut *factory)))
+    }
+
+    /// Play a recording made with `record` into a game made with
+    /// `Game::init_headless`, checking it as in `replay_hidden`. Nothing
+    /// opens a window or GL context, so this runs without a display.
+    pub fn replay_headless<P>(path: P) -> Result<u64>
+        where P: AsRef<Path> {
+        let replay = try!(Replay::load(path));
+        let mut game = try!(G::init_headless());
+        play(&mut game, replay, |game| tick(game, None))
+    }
+
+    /// Run as the command line asks: `--record <file>` records while
+    /// playing and `--replay <file>` checks a recording, as in `record` and
+    /// `replay_hidden`. Otherwise just `run`.
+    pub fn run_with_args(&mut self, args: &[String]) -> Result<()> {
+        fn file(args: &[String], index: usize) -> Result<&str> {
+            match args.get(index + 1) {
+                Some(file) => Ok(&file[..]),
+                None => Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput,
+                                                     format!("{} needs a file", args[index])))),
+            }
         }
+
+        if let Some(index) = args.iter().position(|arg| arg == "--replay") {
+            try!(self.replay_hidden(try!(file(args, index))));
+            return Ok(());
+        }
+        if let Some(index) = args.iter().position(|arg| arg == "--record") {
+            try!(self.record(try!(file(args, index))));
+        }
+        self.run()
     }
 
-    pub fn run(&mut self) {
+    // A recording that can't be written is given up on, not the game
+    fn record_input(&mut self, tick: u64, record: &Record) {
+        let result = match self.recorder {
+            Some(ref mut recorder) => recorder.record(tick, record),
+            None => return,
+        };
+        if let Err(err) = result {
+            let _ = writeln!(io::stderr(), "Stopped recording: {}", err);
+            self.recorder = None;
+        }
+    }
+
+    fn dispatch(&mut self, tick: u64, event: glutin::Event) {
+        self.record_input(tick, &Record::Event(event.clone()));
+        self.game.handle_event(&event);
+    }
+
+    // Resize to the window, and tell the game if the size changed
+    fn window_resized(&mut self, tick: u64) -> Result<()> {
+        let (old_width, old_height, _, _) = self.main_color.get_dimensions();
+        try!(self.resize());
+        let (width, height, _, _) = self.main_color.get_dimensions();
+        if (width, height) != (old_width, old_height) {
+            self.dispatch(tick, glutin::Event::Resized(width as u32, height as u32));
+        }
+        Ok(())
+    }
+
+    pub fn run(&mut self) -> Result<()> {
         let mut encoder: gfx::Encoder<_, _> = self.factory.create_command_buffer().into();
         let mut prev = time::precise_time_ns();
         let mut accum = 0;
+        let mut stats = FrameStats::default();
+        try!(self.resize());
+        let (width, height, _, _) = self.main_color.get_dimensions();
+        self.dispatch(0, glutin::Event::Resized(width as u32, height as u32));
 
         'outer: loop {
-            for event in self.window.poll_events() {
+            // Collected first, since handling them can resize the window
+            let events: Vec<glutin::Event> = self.window.poll_events().collect();
+            for event in events {
                 match event {
                     glutin::Event::Closed => break 'outer,
-                    _ => self.game.handle_event(&event),
+                    glutin::Event::Resized(_, _) => try!(self.window_resized(stats.total_ticks)),
+                    glutin::Event::KeyboardInput(glutin::ElementState::Pressed, _, Some(key))
+                        if Some(key) == self.fullscreen_key => {
+                        self.dispatch(stats.total_ticks, event);
+                        self.toggle_fullscreen();
+                        // The game hears about the new size and fullscreen
+                        // state now if the window has already changed, or
+                        // from the Resized event that follows if not
+                        try!(self.window_resized(stats.total_ticks));
+                    }
+                    glutin::Event::MouseMoved(x, y) if self.letterbox.is_some() => {
+                        // Dropped while minimized, when there's nowhere to point
+                        let point = self.letterbox.as_ref().unwrap().to_virtual(x as f32, y as f32);
+                        if let Some((x, y)) = point {
+                            self.dispatch(stats.total_ticks,
+                                          glutin::Event::MouseMoved(x.floor() as i32, y.floor() as i32));
+                        }
+                    }
+                    _ => self.dispatch(stats.total_ticks, event),
                 }
             }
 
+            let gamepad_events = match self.game.gamepads() {
+                Some(gamepads) => gamepads.poll(),
+                None => Vec::new(),
+            };
+            for event in gamepad_events {
+                self.record_input(stats.total_ticks, &Record::Gamepad(event));
+                self.game.handle_gamepad(&event);
+            }
+
+            let cursor = self.game.cursor_state();
+            if cursor != self.cursor {
+                // Not every platform supports every state; don't retry every
+                // frame if this one refuses
+                let _ = self.window.set_cursor_state(cursor);
+                self.cursor = cursor;
+            }
+
+            if let Some(loader) = self.game.loader() {
+                loader.upload(&mut self.factory);
+            }
+
             let cur = time::precise_time_ns();
-            accum += cur - prev;
+            stats.begin_frame(cur - prev);
+            self.game.update_time(&mut self.time, &stats);
+            try!(self.time.validate());
+            let tick_time = self.time.tick_time();
+
+            let ticks = if self.time.single_step {
+                accum = 0;
+                ::std::mem::replace(&mut self.time.steps, 0)
+            }
+            else {
+                accum += ((cur - prev) as f64 * self.time.time_scale) as u64;
+                let ticks = ::std::cmp::min(accum / tick_time, self.time.max_ticks_per_frame as u64);
+                accum -= ticks * tick_time;
+                if accum >= tick_time {
+                    stats.dropped_ticks += accum / tick_time;
+                    accum %= tick_time;
+                }
+                ticks as u32
+            };
             prev = cur;
-            while accum > TICK_TIME {
-                accum -= TICK_TIME;
-                self.game.tick();
+
+            for _ in 0..ticks {
+                // While recording, loads finish before each tick so they
+                // take the same ticks when played back
+                let factory = if self.recorder.is_some() { Some(&mut self.factory) } else { None };
+                try!(tick(&mut self.game, factory));
+                stats.total_ticks += 1;
+                if self.recorder.is_some() && stats.total_ticks % replay::CHECKPOINT_TICKS == 0 {
+                    let hash = self.game.state_hash();
+                    self.record_input(stats.total_ticks, &Record::Checkpoint(hash));
+                }
             }
+            stats.ticks = ticks;
 
-            self.game.render(&mut encoder, &self.main_color);
+            // In single-step mode, show exactly where the last tick left off
+            let alpha = if self.time.single_step {
+                1.0
+            }
+            else {
+                accum as f32 / tick_time as f32
+            };
+            transform::set_alpha(alpha);
+            match self.letterbox {
+                Some(ref mut letterbox) => {
+                    self.game.render(&mut encoder, letterbox.color(), alpha);
+                    encoder.clear(&self.main_color, LETTERBOX_COLOR);
+                    letterbox.present(&mut encoder);
+                }
+                None => self.game.render(&mut encoder, &self.main_color, alpha),
+            }
 
             encoder.flush(&mut self.device);
             self.window.swap_buffers().unwrap();
             self.device.cleanup();
+
+            if self.game.quit() {
+                break;
+            }
+        }
+
+        if let Some(recorder) = self.recorder.take() {
+            try!(recorder.finish(stats.total_ticks, self.game.state_hash()));
+        }
+        Ok(())
+    }
+}
+
+// Finish anything the game's loader has in flight, if there's a factory to
+// finish it with, then tick
+fn tick<G>(game: &mut G, factory: Option<&mut gfx_device_gl::Factory>) -> Result<()>
+    where G: Game {
+    if let Some(factory) = factory {
+        if let Some(loader) = game.loader() {
+            loader.finish(factory);
+        }
+    }
+    transform::begin_tick();
+    game.tick()
+}
+
+// Play `replay` into `game`, calling `tick` for each tick between records,
+// and check its state hashes
+fn play<G, F>(game: &mut G, replay: Replay, mut tick: F) -> Result<u64>
+    where G: Game,
+          F: FnMut(&mut G) -> Result<()> {
+    let mut ticks = 0;
+    for (at, record) in replay.records {
+        while ticks < at {
+            try!(tick(game));
+            ticks += 1;
+        }
+        let (expected, end) = match record {
+            Record::Event(event) => {
+                game.handle_event(&event);
+                continue;
+            }
+            Record::Gamepad(event) => {
+                game.handle_gamepad(&event);
+                continue;
+            }
+            Record::Checkpoint(hash) => (hash, false),
+            Record::End(hash) => (hash, true),
+        };
+        let actual = game.state_hash();
+        if actual != expected {
+            return Err(Error::Replay(format!("state hash {:016x} after tick {} should be {:016x}",
+                                             actual, ticks, expected)));
+        }
+        if end {
+            return Ok(actual);
+        }
+    }
+    Err(Error::Replay("recording has no end".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::hash::Hasher;
+
+    use gfx_device_gl;
+    use glutin::{ElementState, Event};
+
+    use error::{Error, Result};
+    use replay::{Record, Recorder, StateHasher};
+    use types::*;
+    use super::{App, Game};
+
+    // Counts ticks and key presses, with nothing to draw
+    struct Counter {
+        ticks: u64,
+        presses: u64,
+    }
+
+    impl Game for Counter {
+        fn init(_factory: &mut gfx_device_gl::Factory, _main_color: &RenderTarget, _main_depth: &DepthTarget)
+                -> Result<Counter> {
+            Err(Error::Replay("only runs headless".to_string()))
+        }
+
+        fn init_headless() -> Result<Counter> {
+            Ok(Counter { ticks: 0, presses: 0 })
+        }
+
+        fn tick(&mut self) -> Result<()> {
+            self.ticks += 1;
+            Ok(())
         }
+
+        fn handle_event(&mut self, event: &Event) {
+            if let Event::KeyboardInput(ElementState::Pressed, _, _) = *event {
+                self.presses += 1;
+            }
+        }
+
+        fn render(&mut self, _encoder: &mut GLEncoder, _target: &RenderTarget, _alpha: f32) {}
+
+        fn state_hash(&self) -> u64 {
+            let mut hasher = StateHasher::new();
+            hasher.write_u64(self.ticks);
+            hasher.write_u64(self.presses);
+            hasher.finish()
+        }
+    }
+
+    #[test]
+    fn replay_headless() {
+        let path = env::temp_dir().join("mgmm-game-headless.mgrp");
+        let record = |presses_at_end| {
+            let mut recorder = Recorder::create(&path, 60).unwrap();
+            recorder.record(0, &Record::Event(Event::Resized(640, 480))).unwrap();
+            recorder.record(3, &Record::Event(Event::KeyboardInput(ElementState::Pressed, 57, None))).unwrap();
+            let hash = Counter { ticks: 5, presses: 1 }.state_hash();
+            recorder.record(5, &Record::Checkpoint(hash)).unwrap();
+            recorder.finish(10, Counter { ticks: 10, presses: presses_at_end }.state_hash()).unwrap();
+        };
+
+        record(1);
+        let hash = Counter { ticks: 10, presses: 1 }.state_hash();
+        assert_eq!(App::<Counter>::replay_headless(&path).unwrap(), hash);
+
+        // Ending up somewhere else than recorded fails
+        record(2);
+        assert!(App::<Counter>::replay_headless(&path).is_err());
     }
 }
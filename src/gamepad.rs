@@ -0,0 +1,671 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::ptr;
+use std::slice;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use libc;
+use time;
+
+const DEVICE_DIR: &'static str = "/dev/input";
+
+// How often to look for newly plugged in pads
+const SCAN_INTERVAL: u64 = 1000000000;
+
+// From linux/input.h and linux/input-event-codes.h
+const EV_KEY: u16 = 0x01;
+const EV_ABS: u16 = 0x03;
+const EV_FF: u16 = 0x15;
+const BTN_MISC: u16 = 0x100;
+const BTN_JOYSTICK: u16 = 0x120;
+const BTN_GAMEPAD: u16 = 0x130;
+const KEY_MAX: u16 = 0x2ff;
+const ABS_MAX: u16 = 0x3f;
+const FF_RUMBLE: u16 = 0x50;
+const FF_MAX: u16 = 0x7f;
+
+// Key values: released, pressed and autorepeat
+const KEY_REPEAT: i32 = 2;
+
+const IOC_WRITE: u32 = 1;
+const IOC_READ: u32 = 2;
+
+/// Something that happened on a gamepad. Pads are numbered by their
+/// device, so `/dev/input/event5` is pad 5.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GamepadEvent {
+    Connected(usize),
+    Disconnected(usize),
+    /// A button, by index, was pressed (`true`) or released.
+    Button(usize, u8, bool),
+    /// An axis, by index, moved. Sticks go from -1 to 1 and triggers from
+    /// 0 to 1, after deadzones.
+    Axis(usize, u8, f32),
+}
+
+/// Shakes gamepads, where the platform can.
+pub trait Rumble {
+    /// Called when `pad` is found, with its device. The device is open for
+    /// writing too, unless only reading was allowed.
+    fn connected(&mut self, _pad: usize, _device: &File) {}
+    fn disconnected(&mut self, _pad: usize) {}
+    /// Rumble `pad` with the strong (low frequency) and weak (high
+    /// frequency) motors at 0 to 1 for `duration`. Returns `false` if the
+    /// pad can't rumble.
+    fn rumble(&mut self, pad: usize, strong: f32, weak: f32, duration: Duration) -> bool;
+}
+
+/// Never rumbles, to turn rumble off.
+pub struct NoRumble;
+
+impl Rumble for NoRumble {
+    fn rumble(&mut self, _pad: usize, _strong: f32, _weak: f32, _duration: Duration) -> bool {
+        false
+    }
+}
+
+// struct input_event
+#[repr(C)]
+struct InputEvent {
+    time: [libc::c_long; 2],
+    kind: u16,
+    code: u16,
+    value: i32,
+}
+
+// struct input_absinfo
+#[repr(C)]
+#[derive(Default)]
+struct AbsInfo {
+    value: i32,
+    minimum: i32,
+    maximum: i32,
+    fuzz: i32,
+    flat: i32,
+    resolution: i32,
+}
+
+// The union in struct ff_effect, laid out like its biggest member,
+// struct ff_periodic_effect. A rumble effect is just the first two fields:
+// the strong and weak magnitudes.
+#[repr(C)]
+struct FfParameters {
+    rumble: [u16; 2],
+    rest: [u16; 7],
+    custom_len: u32,
+    custom_data: *const i16,
+}
+
+// struct ff_effect
+#[repr(C)]
+struct FfEffect {
+    kind: u16,
+    id: i16,
+    direction: u16,
+    trigger: [u16; 2],
+    /// Length and delay in milliseconds.
+    replay: [u16; 2],
+    parameters: FfParameters,
+}
+
+fn ioc(direction: u32, number: u32, size: usize) -> u32 {
+    direction << 30 | (size as u32) << 16 | (b'E' as u32) << 8 | number
+}
+
+fn eviocgbit(kind: u16, size: usize) -> u32 {
+    ioc(IOC_READ, 0x20 + kind as u32, size)
+}
+
+fn eviocgkey(size: usize) -> u32 {
+    ioc(IOC_READ, 0x18, size)
+}
+
+fn eviocgabs(axis: u16) -> u32 {
+    ioc(IOC_READ, 0x40 + axis as u32, mem::size_of::<AbsInfo>())
+}
+
+fn eviocsff() -> u32 {
+    ioc(IOC_WRITE, 0x80, mem::size_of::<FfEffect>())
+}
+
+#[cfg(target_os = "linux")]
+fn ioctl<T>(device: &File, request: u32, argument: *mut T) -> bool {
+    use std::os::unix::io::AsRawFd;
+    unsafe { libc::ioctl(device.as_raw_fd(), request as libc::c_ulong, argument) >= 0 }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn ioctl<T>(_device: &File, _request: u32, _argument: *mut T) -> bool {
+    false
+}
+
+// A buffer big enough for every bit up to `max`
+fn bits(max: u16) -> Vec<u8> {
+    vec![0; max as usize / 8 + 1]
+}
+
+fn bit(bits: &[u8], index: u16) -> bool {
+    bits.get(index as usize / 8).map_or(false, |&byte| byte & (1 << (index % 8)) != 0)
+}
+
+fn read_event(device: &mut File) -> io::Result<InputEvent> {
+    let mut event = InputEvent { time: [0, 0], kind: 0, code: 0, value: 0 };
+    {
+        let bytes = unsafe {
+            slice::from_raw_parts_mut(&mut event as *mut InputEvent as *mut u8, mem::size_of::<InputEvent>())
+        };
+        try!(device.read_exact(bytes));
+    }
+    Ok(event)
+}
+
+fn write_event(device: &mut File, event: &InputEvent) -> io::Result<()> {
+    let bytes = unsafe {
+        slice::from_raw_parts(event as *const InputEvent as *const u8, mem::size_of::<InputEvent>())
+    };
+    device.write_all(bytes)
+}
+
+// Map `value` from `minimum..maximum` to -1..1
+fn normalize(value: i32, minimum: i32, maximum: i32) -> f32 {
+    if maximum <= minimum {
+        return 0.0;
+    }
+    (2.0 * (value as f64 - minimum as f64) / (maximum as f64 - minimum as f64) - 1.0) as f32
+}
+
+/// Rumbles through evdev force feedback, on pads whose driver supports
+/// `FF_RUMBLE` and whose device could be opened for writing. The default
+/// for `Gamepads`.
+pub struct ForceFeedback {
+    /// A device for each pad that can rumble, and the effect uploaded to
+    /// it, or -1 before the first.
+    pads: HashMap<usize, (File, i16)>,
+}
+
+impl Default for ForceFeedback {
+    fn default() -> ForceFeedback {
+        ForceFeedback::new()
+    }
+}
+
+impl ForceFeedback {
+    pub fn new() -> ForceFeedback {
+        ForceFeedback {
+            pads: HashMap::new(),
+        }
+    }
+}
+
+impl Rumble for ForceFeedback {
+    fn connected(&mut self, pad: usize, device: &File) {
+        let mut features = bits(FF_MAX);
+        if !ioctl(device, eviocgbit(EV_FF, features.len()), features.as_mut_ptr()) || !bit(&features, FF_RUMBLE) {
+            return;
+        }
+        if let Ok(device) = device.try_clone() {
+            self.pads.insert(pad, (device, -1));
+        }
+    }
+
+    fn disconnected(&mut self, pad: usize) {
+        self.pads.remove(&pad);
+    }
+
+    fn rumble(&mut self, pad: usize, strong: f32, weak: f32, duration: Duration) -> bool {
+        let &mut (ref mut device, ref mut id) = match self.pads.get_mut(&pad) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let magnitude = |value: f32| (value.max(0.0).min(1.0) * 65535.0) as u16;
+        let millis = duration.as_secs().saturating_mul(1000) + duration.subsec_nanos() as u64 / 1000000;
+        let mut effect = FfEffect {
+            kind: FF_RUMBLE,
+            // Uploading with an existing id replaces that effect
+            id: *id,
+            direction: 0,
+            trigger: [0, 0],
+            replay: [cmp::min(millis, 0xffff) as u16, 0],
+            parameters: FfParameters {
+                rumble: [magnitude(strong), magnitude(weak)],
+                rest: [0; 7],
+                custom_len: 0,
+                custom_data: ptr::null(),
+            },
+        };
+        if !ioctl(device, eviocsff(), &mut effect as *mut FfEffect) {
+            return false;
+        }
+        *id = effect.id;
+
+        let play = InputEvent {
+            time: [0, 0],
+            kind: EV_FF,
+            code: effect.id as u16,
+            value: 1,
+        };
+        write_event(device, &play).is_ok()
+    }
+}
+
+// The buttons and axes a pad has, by evdev code, in the order the joystick
+// interface numbers them, so indices are the same as on `/dev/input/js*`
+#[derive(Debug, PartialEq)]
+struct Layout {
+    buttons: Vec<u16>,
+    axes: Vec<u16>,
+}
+
+impl Layout {
+    // None for devices that aren't joysticks or gamepads, e.g. keyboards
+    fn new(keys: &[u8], axes: &[u8]) -> Option<Layout> {
+        if !bit(keys, BTN_JOYSTICK) && !bit(keys, BTN_GAMEPAD) {
+            return None;
+        }
+        let buttons = (BTN_JOYSTICK..KEY_MAX + 1).chain(BTN_MISC..BTN_JOYSTICK)
+            .filter(|&code| bit(keys, code))
+            .take(u8::max_value() as usize + 1)
+            .collect();
+        let axes = (0..ABS_MAX + 1).filter(|&code| bit(axes, code)).collect();
+        Some(Layout {
+            buttons: buttons,
+            axes: axes,
+        })
+    }
+
+    fn read(device: &File) -> Option<Layout> {
+        let mut keys = bits(KEY_MAX);
+        let mut axes = bits(ABS_MAX);
+        if !ioctl(device, eviocgbit(EV_KEY, keys.len()), keys.as_mut_ptr()) {
+            return None;
+        }
+        // Pads without axes fail this, and have none set
+        ioctl(device, eviocgbit(EV_ABS, axes.len()), axes.as_mut_ptr());
+        Layout::new(&keys, &axes)
+    }
+}
+
+enum Raw {
+    Button(u8, bool),
+    Axis(u8, f32),
+    Closed,
+}
+
+// Runs on its own thread until the device goes away
+fn read_events(pad: usize, mut device: File, layout: Layout, sender: Sender<(usize, Raw)>) {
+    let mut initial = Vec::new();
+
+    // Each axis's index and range, starting from where it is now
+    let mut axes = HashMap::new();
+    for (index, &code) in layout.axes.iter().enumerate() {
+        let mut info = AbsInfo::default();
+        if ioctl(&device, eviocgabs(code), &mut info as *mut AbsInfo) {
+            axes.insert(code, (index as u8, info.minimum, info.maximum));
+            initial.push(Raw::Axis(index as u8, normalize(info.value, info.minimum, info.maximum)));
+        }
+    }
+
+    let mut held = bits(KEY_MAX);
+    let buttons: HashMap<u16, u8> = layout.buttons.iter().enumerate()
+        .map(|(index, &code)| (code, index as u8))
+        .collect();
+    if ioctl(&device, eviocgkey(held.len()), held.as_mut_ptr()) {
+        for (index, &code) in layout.buttons.iter().enumerate() {
+            if bit(&held, code) {
+                initial.push(Raw::Button(index as u8, true));
+            }
+        }
+    }
+
+    for raw in initial {
+        if sender.send((pad, raw)).is_err() {
+            return;
+        }
+    }
+
+    loop {
+        let event = match read_event(&mut device) {
+            Ok(event) => event,
+            Err(_) => {
+                let _ = sender.send((pad, Raw::Closed));
+                return;
+            }
+        };
+
+        let raw = match event.kind {
+            EV_KEY if event.value != KEY_REPEAT => match buttons.get(&event.code) {
+                Some(&index) => Raw::Button(index, event.value != 0),
+                None => continue,
+            },
+            EV_ABS => match axes.get(&event.code) {
+                Some(&(index, minimum, maximum)) => Raw::Axis(index, normalize(event.value, minimum, maximum)),
+                None => continue,
+            },
+            _ => continue,
+        };
+        if sender.send((pad, raw)).is_err() {
+            return;
+        }
+    }
+}
+
+// Rescale `value` so the deadzone maps to 0 and the rest to 0..1
+fn rescale(value: f32, deadzone: f32) -> f32 {
+    if value <= deadzone {
+        0.0
+    }
+    else {
+        ((value - deadzone) / (1.0 - deadzone)).min(1.0)
+    }
+}
+
+/// Gamepads read through the Linux event interface
+/// (`/dev/input/event*`), numbering buttons and axes the same way as the
+/// joystick interface. Reading needs read access to the devices, and
+/// rumbling write access too.
+///
+/// Each pad is read on its own thread; `poll` collects what they've seen,
+/// applies deadzones, and picks up pads plugged in since. On other
+/// platforms no pads are ever found.
+pub struct Gamepads {
+    sender: Sender<(usize, Raw)>,
+    receiver: Receiver<(usize, Raw)>,
+    /// Raw axis values of each connected pad.
+    pads: BTreeMap<usize, HashMap<u8, f32>>,
+    /// The device of each connected pad, for rumble backends set later.
+    devices: BTreeMap<usize, File>,
+    last_scan: Option<u64>,
+    rumble: Box<Rumble>,
+    /// Axes read together as a stick, `(x, y)`. The deadzone of a stick is
+    /// a circle, so pushing diagonally isn't harder than straight.
+    pub sticks: Vec<(u8, u8)>,
+    /// Axes that rest at -1 and are reported from 0 to 1.
+    pub triggers: Vec<u8>,
+    /// How far a stick or trigger moves before it counts, from 0 to 1.
+    pub deadzone: f32,
+    pub trigger_deadzone: f32,
+}
+
+impl Default for Gamepads {
+    fn default() -> Gamepads {
+        Gamepads::new()
+    }
+}
+
+impl Gamepads {
+    /// Gamepads with the layout of an Xbox pad under the xpad driver: sticks
+    /// on axes 0 and 1 and axes 3 and 4, triggers on axes 2 and 5.
+    pub fn new() -> Gamepads {
+        let (sender, receiver) = mpsc::channel();
+        Gamepads {
+            sender: sender,
+            receiver: receiver,
+            pads: BTreeMap::new(),
+            devices: BTreeMap::new(),
+            last_scan: None,
+            rumble: Box::new(ForceFeedback::new()),
+            sticks: vec![(0, 1), (3, 4)],
+            triggers: vec![2, 5],
+            deadzone: 0.2,
+            trigger_deadzone: 0.05,
+        }
+    }
+
+    /// The connected pads.
+    pub fn connected(&self) -> Vec<usize> {
+        self.pads.keys().cloned().collect()
+    }
+
+    /// Rumble through another backend than `ForceFeedback`, e.g. `NoRumble`
+    /// to turn it off.
+    pub fn set_rumble(&mut self, rumble: Box<Rumble>) {
+        self.rumble = rumble;
+        for (&pad, device) in self.devices.iter() {
+            self.rumble.connected(pad, device);
+        }
+    }
+
+    /// Rumble every connected pad. Returns whether any of them could.
+    pub fn rumble(&mut self, strong: f32, weak: f32, duration: Duration) -> bool {
+        let mut any = false;
+        for &pad in self.pads.keys() {
+            any |= self.rumble.rumble(pad, strong, weak, duration);
+        }
+        any
+    }
+
+    fn scan(&mut self, events: &mut Vec<GamepadEvent>) {
+        let entries = match fs::read_dir(DEVICE_DIR) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with("event") {
+                continue;
+            }
+            let pad: usize = match name[5..].parse() {
+                Ok(pad) => pad,
+                Err(_) => continue,
+            };
+            if self.pads.contains_key(&pad) {
+                continue;
+            }
+
+            // Devices we can't read are skipped, and tried again next scan.
+            // Writing is only needed to rumble.
+            let path = entry.path();
+            let device = match OpenOptions::new().read(true).write(true).open(&path) {
+                Ok(device) => device,
+                Err(_) => match File::open(&path) {
+                    Ok(device) => device,
+                    Err(_) => continue,
+                },
+            };
+            let layout = match Layout::read(&device) {
+                Some(layout) => layout,
+                None => continue,
+            };
+            let reader = match device.try_clone() {
+                Ok(reader) => reader,
+                Err(_) => continue,
+            };
+            let sender = self.sender.clone();
+            thread::spawn(move || read_events(pad, reader, layout, sender));
+            self.rumble.connected(pad, &device);
+            self.devices.insert(pad, device);
+            self.pads.insert(pad, HashMap::new());
+            events.push(GamepadEvent::Connected(pad));
+        }
+    }
+
+    /// Collect what happened since the last call. `App` calls this every
+    /// frame through `Game::gamepads`.
+    pub fn poll(&mut self) -> Vec<GamepadEvent> {
+        let mut events = Vec::new();
+
+        let now = time::precise_time_ns();
+        let due = match self.last_scan {
+            Some(last) => now - last >= SCAN_INTERVAL,
+            None => true,
+        };
+        if due {
+            self.last_scan = Some(now);
+            self.scan(&mut events);
+        }
+
+        while let Ok((pad, raw)) = self.receiver.try_recv() {
+            match raw {
+                Raw::Closed => {
+                    if self.pads.remove(&pad).is_some() {
+                        self.devices.remove(&pad);
+                        self.rumble.disconnected(pad);
+                        events.push(GamepadEvent::Disconnected(pad));
+                    }
+                }
+                Raw::Button(button, pressed) => events.push(GamepadEvent::Button(pad, button, pressed)),
+                Raw::Axis(axis, value) => {
+                    if let Some(axes) = self.pads.get_mut(&pad) {
+                        axes.insert(axis, value);
+                    }
+                    self.filter_axis(pad, axis, &mut events);
+                }
+            }
+        }
+
+        events
+    }
+
+    fn filter_axis(&self, pad: usize, axis: u8, events: &mut Vec<GamepadEvent>) {
+        let axes = match self.pads.get(&pad) {
+            Some(axes) => axes,
+            None => return,
+        };
+        let raw = |axis: u8| axes.get(&axis).cloned().unwrap_or(0.0);
+
+        // Moving either axis of a stick changes both, with a radial deadzone
+        for &(x, y) in self.sticks.iter() {
+            if axis == x || axis == y {
+                let (raw_x, raw_y) = (raw(x), raw(y));
+                let length = (raw_x * raw_x + raw_y * raw_y).sqrt();
+                let scale = if length > 0.0 {
+                    rescale(length, self.deadzone) / length
+                }
+                else {
+                    0.0
+                };
+                events.push(GamepadEvent::Axis(pad, x, raw_x * scale));
+                events.push(GamepadEvent::Axis(pad, y, raw_y * scale));
+                return;
+            }
+        }
+
+        let value = raw(axis);
+        let value = if self.triggers.contains(&axis) {
+            rescale((value + 1.0) / 2.0, self.trigger_deadzone)
+        }
+        else {
+            value.signum() * rescale(value.abs(), self.deadzone)
+        };
+        events.push(GamepadEvent::Axis(pad, axis, value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{BTN_GAMEPAD, BTN_JOYSTICK, BTN_MISC, GamepadEvent, Gamepads, Layout, bit, bits, normalize, rescale};
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    #[test]
+    fn rescale_deadzone() {
+        assert_eq!(rescale(0.0, 0.2), 0.0);
+        assert_eq!(rescale(0.2, 0.2), 0.0);
+        assert!(close(rescale(0.6, 0.2), 0.5));
+        assert_eq!(rescale(1.0, 0.2), 1.0);
+        // Pads that overshoot still stop at 1
+        assert_eq!(rescale(1.1, 0.2), 1.0);
+        assert!(close(rescale(0.5, 0.0), 0.5));
+    }
+
+    fn set(bits: &mut [u8], index: u16) {
+        bits[index as usize / 8] |= 1 << (index % 8);
+    }
+
+    #[test]
+    fn layout() {
+        let mut keys = bits(0x2ff);
+        let mut axes = bits(0x3f);
+        // Keyboards and mice aren't pads
+        set(&mut keys, 30);
+        set(&mut keys, 0x110);
+        assert_eq!(Layout::new(&keys, &axes), None);
+
+        // Gamepad buttons come first, then those below the joystick range,
+        // as the joystick interface numbers them
+        set(&mut keys, BTN_GAMEPAD + 1);
+        set(&mut keys, BTN_GAMEPAD);
+        set(&mut keys, BTN_MISC + 2);
+        set(&mut keys, 0x220);
+        set(&mut axes, 0x11);
+        set(&mut axes, 0);
+        let layout = Layout::new(&keys, &axes).unwrap();
+        assert_eq!(layout.buttons, [BTN_GAMEPAD, BTN_GAMEPAD + 1, 0x220, BTN_MISC + 2, 0x110]);
+        assert_eq!(layout.axes, [0, 0x11]);
+        assert!(bit(&keys, BTN_GAMEPAD));
+        assert!(!bit(&keys, BTN_JOYSTICK));
+    }
+
+    #[test]
+    fn normalized_axes() {
+        assert_eq!(normalize(0, 0, 255), -1.0);
+        assert_eq!(normalize(255, 0, 255), 1.0);
+        assert_eq!(normalize(0, -1, 1), 0.0);
+        assert_eq!(normalize(-32768, -32768, 32767), -1.0);
+        assert_eq!(normalize(0, -32767, 32767), 0.0);
+        assert_eq!(normalize(192, 0, 256), 0.5);
+        // Axes without a range stay put
+        assert_eq!(normalize(5, 0, 0), 0.0);
+    }
+
+    fn filtered(gamepads: &mut Gamepads, axis: u8, value: f32) -> Vec<GamepadEvent> {
+        gamepads.pads.entry(0).or_insert_with(HashMap::new).insert(axis, value);
+        let mut events = Vec::new();
+        gamepads.filter_axis(0, axis, &mut events);
+        events
+    }
+
+    #[test]
+    fn sticks_and_triggers() {
+        let mut gamepads = Gamepads::new();
+
+        // Inside the circle
+        filtered(&mut gamepads, 0, 0.1);
+        let events = filtered(&mut gamepads, 1, 0.1);
+        assert_eq!(events, [GamepadEvent::Axis(0, 0, 0.0), GamepadEvent::Axis(0, 1, 0.0)]);
+
+        // Diagonally past it, though neither axis alone would be
+        filtered(&mut gamepads, 0, 0.15);
+        let events = filtered(&mut gamepads, 1, 0.15);
+        assert_eq!(events.len(), 2);
+        match (events[0], events[1]) {
+            (GamepadEvent::Axis(0, 0, x), GamepadEvent::Axis(0, 1, y)) => {
+                assert!(x > 0.0);
+                assert!(close(x, y));
+            }
+            _ => panic!("unexpected events {:?}", events),
+        }
+
+        // Pushed all the way along x, y stays put
+        filtered(&mut gamepads, 1, 0.0);
+        let events = filtered(&mut gamepads, 0, 1.0);
+        assert_eq!(events.len(), 2);
+        match (events[0], events[1]) {
+            (GamepadEvent::Axis(0, 0, x), GamepadEvent::Axis(0, 1, y)) => {
+                assert!(close(x, 1.0));
+                assert_eq!(y, 0.0);
+            }
+            _ => panic!("unexpected events {:?}", events),
+        }
+
+        // Triggers rest at -1 and read from 0 to 1
+        assert_eq!(filtered(&mut gamepads, 2, -1.0), [GamepadEvent::Axis(0, 2, 0.0)]);
+        assert_eq!(filtered(&mut gamepads, 2, 1.0), [GamepadEvent::Axis(0, 2, 1.0)]);
+
+        // Other axes get a deadzone either way
+        assert_eq!(filtered(&mut gamepads, 6, -0.1), [GamepadEvent::Axis(0, 6, 0.0)]);
+        match filtered(&mut gamepads, 6, -0.6)[0] {
+            GamepadEvent::Axis(0, 6, value) => assert!(close(value, -0.5)),
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+}
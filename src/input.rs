@@ -1,11 +1,270 @@
-use glutin;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+use std::path::Path;
 
+use glutin::{self, CursorState, ElementState, MouseButton, MouseScrollDelta, VirtualKeyCode};
+
+use error::{Error, Result};
+use gamepad::GamepadEvent;
+
+/// Something that can be held down.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Button {
+    Key(VirtualKeyCode),
+    Mouse(MouseButton),
+    /// A gamepad button, by index.
+    Gamepad(u8),
+}
+
+/// What drives an axis.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AxisBinding {
+    /// -1 while the first is held, 1 while the second is.
+    Buttons(Button, Button),
+    /// A gamepad stick or trigger axis, by index, and whether to flip it
+    /// (sticks usually read -1 pushed up).
+    Gamepad(u8, bool),
+}
+
+macro_rules! keys {
+    ($($key:ident),*) => {
+        const KEYS: &'static [(&'static str, VirtualKeyCode)] = &[
+            $((stringify!($key), VirtualKeyCode::$key)),*
+        ];
+    }
+}
+
+// The keys that can be named in a bindings file. Replay files store keys
+// by their place in this list, so only ever add to the end.
+keys!(Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, Key0,
+      A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+      Escape, F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+      Insert, Home, Delete, End, PageDown, PageUp, Left, Up, Right, Down,
+      Back, Return, Space, Tab,
+      Numpad0, Numpad1, Numpad2, Numpad3, Numpad4, Numpad5, Numpad6, Numpad7, Numpad8, Numpad9,
+      LShift, RShift, LControl, RControl, LAlt, RAlt,
+      Apostrophe, Backslash, Comma, Equals, Grave, LBracket, Minus, Period, RBracket, Semicolon, Slash);
+
+// Roughly one notch on a wheel, for touchpads that scroll in pixels
+const PIXELS_PER_LINE: f32 = 16.0;
+
+/// A number for `key` that stays the same between builds, for saving it
+/// compactly. Only keys that can be named in a bindings file have one.
+pub fn key_index(key: VirtualKeyCode) -> Option<u8> {
+    KEYS.iter().position(|&(_, code)| code == key).map(|index| index as u8)
+}
+
+pub fn key_from_index(index: u8) -> Option<VirtualKeyCode> {
+    KEYS.get(index as usize).map(|&(_, code)| code)
+}
+
+/// The name of `key` in a bindings file, if it can be named there.
+pub fn key_name(key: VirtualKeyCode) -> Option<&'static str> {
+    KEYS.iter().find(|&&(_, code)| code == key).map(|&(name, _)| name)
+}
+
+fn parse_error(line: usize, message: &str) -> Error {
+    Error::Bindings(format!("line {}: {}", line + 1, message))
+}
+
+impl Button {
+    /// Parse `key:<name>`, `mouse:<Left|Right|Middle|index>` or
+    /// `gamepad:<index>`.
+    pub fn parse(text: &str) -> Option<Button> {
+        let mut parts = text.splitn(2, ':');
+        let (kind, name) = match (parts.next(), parts.next()) {
+            (Some(kind), Some(name)) => (kind, name),
+            _ => return None,
+        };
+        match kind {
+            "key" => KEYS.iter().find(|&&(key, _)| key == name).map(|&(_, code)| Button::Key(code)),
+            "mouse" => match name {
+                "Left" => Some(Button::Mouse(MouseButton::Left)),
+                "Right" => Some(Button::Mouse(MouseButton::Right)),
+                "Middle" => Some(Button::Mouse(MouseButton::Middle)),
+                _ => name.parse().ok().map(|index| Button::Mouse(MouseButton::Other(index))),
+            },
+            "gamepad" => name.parse().ok().map(Button::Gamepad),
+            _ => None,
+        }
+    }
+
+    /// The text `parse` reads back, or `None` for a key that can't be
+    /// named in a bindings file.
+    pub fn to_text(&self) -> Option<String> {
+        match *self {
+            Button::Key(code) => key_name(code).map(|name| format!("key:{}", name)),
+            Button::Mouse(MouseButton::Other(index)) => Some(format!("mouse:{}", index)),
+            Button::Mouse(button) => Some(format!("mouse:{:?}", button)),
+            Button::Gamepad(index) => Some(format!("gamepad:{}", index)),
+        }
+    }
+}
+
+/// Which buttons and axes drive each named action and axis.
+///
+/// Bindings files are plain text, one record per line, with `#` starting
+/// a comment:
+///
+/// ```text
+/// action <name> <button>...
+/// axis <name> <negative button> <positive button>
+/// axis <name> gamepad:<axis index> [invert]
+/// ```
+///
+/// where a button is `key:<VirtualKeyCode>`, `mouse:<Left|Right|Middle|index>`
+/// or `gamepad:<button index>`. Records for the same name add up.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Bindings {
+    pub actions: BTreeMap<String, Vec<Button>>,
+    pub axes: BTreeMap<String, Vec<AxisBinding>>,
+}
+
+impl Bindings {
+    pub fn new() -> Bindings {
+        Bindings::default()
+    }
+
+    pub fn parse(text: &str) -> Result<Bindings> {
+        let mut bindings = Bindings::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let button = |index: usize| -> Result<Button> {
+                match fields.get(index) {
+                    Some(field) => Button::parse(field).ok_or_else(|| parse_error(number, "unknown button")),
+                    None => Err(parse_error(number, "missing button")),
+                }
+            };
+            match fields.first() {
+                None => continue,
+                Some(&"action") => {
+                    if fields.len() < 3 {
+                        return Err(parse_error(number, "action needs a name and a button"));
+                    }
+                    for index in 2..fields.len() {
+                        bindings.bind(fields[1], try!(button(index)));
+                    }
+                }
+                Some(&"axis") => {
+                    let binding = match fields.len() {
+                        3 | 4 if fields[2].starts_with("gamepad:") => {
+                            let invert = match fields.get(3) {
+                                None => false,
+                                Some(&"invert") => true,
+                                Some(_) => return Err(parse_error(number, "expected invert")),
+                            };
+                            match fields[2]["gamepad:".len()..].parse() {
+                                Ok(index) => AxisBinding::Gamepad(index, invert),
+                                Err(_) => return Err(parse_error(number, "malformed gamepad axis")),
+                            }
+                        }
+                        4 => AxisBinding::Buttons(try!(button(2)), try!(button(3))),
+                        _ => return Err(parse_error(number, "axis needs a gamepad axis or two buttons")),
+                    };
+                    bindings.bind_axis(fields[1], binding);
+                }
+                Some(_) => return Err(parse_error(number, "unknown record")),
+            }
+        }
+
+        Ok(bindings)
+    }
+
+    pub fn load<P>(path: P) -> Result<Bindings>
+        where P: AsRef<Path> {
+        let mut text = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut text));
+        Bindings::parse(&text)
+    }
+
+    /// The text `parse` reads back. Fails if a binding uses a key that
+    /// can't be named in a bindings file.
+    pub fn to_text(&self) -> Result<String> {
+        let to_text = |button: &Button| {
+            button.to_text().ok_or_else(|| Error::Bindings(format!("{:?} can't be saved", button)))
+        };
+        let mut text = String::new();
+        for (name, buttons) in self.actions.iter() {
+            let mut fields = Vec::new();
+            for button in buttons.iter() {
+                fields.push(try!(to_text(button)));
+            }
+            writeln!(text, "action {} {}", name, fields.join(" ")).unwrap();
+        }
+        for (name, bindings) in self.axes.iter() {
+            for binding in bindings.iter() {
+                match *binding {
+                    AxisBinding::Buttons(negative, positive) => {
+                        writeln!(text, "axis {} {} {}", name,
+                                 try!(to_text(&negative)), try!(to_text(&positive))).unwrap();
+                    }
+                    AxisBinding::Gamepad(index, invert) => {
+                        writeln!(text, "axis {} gamepad:{}{}", name, index,
+                                 if invert { " invert" } else { "" }).unwrap();
+                    }
+                }
+            }
+        }
+        Ok(text)
+    }
+
+    /// Add `button` to the buttons that trigger `action`.
+    pub fn bind(&mut self, action: &str, button: Button) {
+        let buttons = self.actions.entry(action.to_string()).or_insert_with(Vec::new);
+        if !buttons.contains(&button) {
+            buttons.push(button);
+        }
+    }
+
+    /// Remove every binding of `action`.
+    pub fn unbind(&mut self, action: &str) {
+        self.actions.remove(action);
+    }
+
+    pub fn bind_axis(&mut self, axis: &str, binding: AxisBinding) {
+        let bindings = self.axes.entry(axis.to_string()).or_insert_with(Vec::new);
+        if !bindings.contains(&binding) {
+            bindings.push(binding);
+        }
+    }
+
+    pub fn unbind_axis(&mut self, axis: &str) {
+        self.axes.remove(axis);
+    }
+}
+
+/// The state of named actions and axes, fed by window events.
+///
+/// Events can arrive at any time, but games look at input in fixed ticks,
+/// so button state is latched: call `update` at the start of every tick,
+/// and until the next `update` everything reads as it was then. Presses
+/// and releases are remembered until the tick that sees them, so a tap
+/// between two ticks still shows up as `pressed` (and `released`) once.
 pub struct Input {
-    pub forward: bool,
-    pub backward: bool,
-    pub left: bool,
-    pub right: bool,
-    pub action: bool,
+    bindings: Bindings,
+    /// Buttons down right now, as far as events have said.
+    down: HashSet<Button>,
+    /// Presses and releases since the last `update`.
+    pending_pressed: HashSet<Button>,
+    pending_released: HashSet<Button>,
+    /// Buttons held as of the last `update`, and for how many ticks.
+    held: HashMap<Button, u32>,
+    pressed: HashSet<Button>,
+    released: HashSet<Button>,
+    /// Gamepad buttons held and axis values, by pad.
+    gamepad_buttons: HashMap<usize, HashSet<u8>>,
+    gamepad_axes: HashMap<usize, HashMap<u8, f32>>,
+    /// Scrolling since the last `update`, and in the tick before it.
+    pending_wheel: (f32, f32),
+    wheel: (f32, f32),
+    cursor: CursorState,
+    /// The action to bind to the next button pressed.
+    capture: Option<String>,
     // Mouse coordinates
     pub win_x: i32,
     pub win_y: i32,
@@ -14,13 +273,21 @@ pub struct Input {
 }
 
 impl Input {
-    pub fn new() -> Input {
+    pub fn new(bindings: Bindings) -> Input {
         Input {
-            forward: false,
-            backward: false,
-            left: false,
-            right: false,
-            action: false,
+            bindings: bindings,
+            down: HashSet::new(),
+            pending_pressed: HashSet::new(),
+            pending_released: HashSet::new(),
+            held: HashMap::new(),
+            pressed: HashSet::new(),
+            released: HashSet::new(),
+            gamepad_buttons: HashMap::new(),
+            gamepad_axes: HashMap::new(),
+            pending_wheel: (0.0, 0.0),
+            wheel: (0.0, 0.0),
+            cursor: CursorState::Normal,
+            capture: None,
             win_x: 0,
             win_y: 0,
             world_x: 0.0,
@@ -28,24 +295,412 @@ impl Input {
         }
     }
 
-    pub fn key_pressed(&mut self,
-                       state: glutin::ElementState,
-                       code: glutin::ScanCode,
-                       _vcode: Option<glutin::VirtualKeyCode>) {
-        match code {
-            25 => self.forward = state == glutin::ElementState::Pressed,
-            39 => self.backward = state == glutin::ElementState::Pressed,
-            38 => self.left = state == glutin::ElementState::Pressed,
-            40 => self.right = state == glutin::ElementState::Pressed,
-            65 => self.action = state == glutin::ElementState::Pressed,
+    pub fn bindings(&self) -> &Bindings {
+        &self.bindings
+    }
+
+    /// Change bindings while running, e.g. from an options menu.
+    pub fn bindings_mut(&mut self) -> &mut Bindings {
+        &mut self.bindings
+    }
+
+    /// Replace the bindings of `action` with the next button pressed. Keys
+    /// that can't be named in a bindings file are passed through as usual
+    /// and capturing goes on, so the bindings can always be saved.
+    pub fn capture(&mut self, action: &str) {
+        self.capture = Some(action.to_string());
+    }
+
+    /// The action waiting for `capture` to bind it.
+    pub fn capturing(&self) -> Option<&str> {
+        self.capture.as_ref().map(|action| &action[..])
+    }
+
+    pub fn handle_event(&mut self, event: &glutin::Event) {
+        match *event {
+            glutin::Event::KeyboardInput(state, _, Some(code)) => self.set_button(Button::Key(code), state),
+            glutin::Event::MouseInput(state, button) => self.set_button(Button::Mouse(button), state),
+            glutin::Event::MouseMoved(x, y) => {
+                self.win_x = x;
+                self.win_y = y;
+            }
+            glutin::Event::MouseWheel(delta, _) => {
+                let (x, y) = match delta {
+                    MouseScrollDelta::LineDelta(x, y) => (x, y),
+                    MouseScrollDelta::PixelDelta(x, y) => (x / PIXELS_PER_LINE, y / PIXELS_PER_LINE),
+                };
+                self.pending_wheel.0 += x;
+                self.pending_wheel.1 += y;
+            }
+            glutin::Event::Focused(false) => self.release_all(),
             _ => {},
         }
     }
 
-    pub fn mouse_moved(&mut self, win_x: i32, win_y: i32, world_x: f32, world_y: f32) {
-        self.win_x = win_x;
-        self.win_y = win_y;
-        self.world_x = world_x;
-        self.world_y = world_y;
+    fn set_button(&mut self, button: Button, state: ElementState) {
+        if state == ElementState::Pressed {
+            if self.capture.is_some() && button.to_text().is_some() {
+                let action = self.capture.take().unwrap();
+                self.bindings.unbind(&action);
+                self.bindings.bind(&action, button);
+                // Don't trigger the action with the press that bound it
+                return;
+            }
+            // Key repeat sends more presses without releases
+            if self.down.insert(button) {
+                self.pending_pressed.insert(button);
+            }
+        }
+        else if self.down.remove(&button) {
+            self.pending_released.insert(button);
+        }
+    }
+
+    /// Latch the events since the last call. Call once at the start of
+    /// every tick, before reading any input.
+    pub fn update(&mut self) {
+        self.pressed = mem::replace(&mut self.pending_pressed, HashSet::new());
+        self.released = mem::replace(&mut self.pending_released, HashSet::new());
+        self.wheel = mem::replace(&mut self.pending_wheel, (0.0, 0.0));
+
+        let down = &self.down;
+        self.held.retain(|button, _| down.contains(button));
+        for button in self.down.iter() {
+            *self.held.entry(*button).or_insert(0) += 1;
+        }
+    }
+
+    fn gamepad_held(&self, index: u8) -> bool {
+        self.gamepad_buttons.values().any(|buttons| buttons.contains(&index))
+    }
+
+    /// Record a button press or release on gamepad `pad`.
+    pub fn gamepad_button(&mut self, pad: usize, index: u8, pressed: bool) {
+        if pressed {
+            self.gamepad_buttons.entry(pad).or_insert_with(HashSet::new).insert(index);
+            self.set_button(Button::Gamepad(index), ElementState::Pressed);
+        }
+        else {
+            if let Some(buttons) = self.gamepad_buttons.get_mut(&pad) {
+                buttons.remove(&index);
+            }
+            // Still down while another pad holds it
+            if !self.gamepad_held(index) {
+                self.set_button(Button::Gamepad(index), ElementState::Released);
+            }
+        }
+    }
+
+    /// Record an axis on gamepad `pad` moving, to a value from -1 to 1.
+    pub fn gamepad_axis(&mut self, pad: usize, index: u8, value: f32) {
+        self.gamepad_axes.entry(pad).or_insert_with(HashMap::new).insert(index, value);
+    }
+
+    /// Forget gamepad `pad`, releasing the buttons only it held.
+    pub fn gamepad_disconnected(&mut self, pad: usize) {
+        self.gamepad_axes.remove(&pad);
+        if let Some(buttons) = self.gamepad_buttons.remove(&pad) {
+            for index in buttons {
+                if !self.gamepad_held(index) {
+                    self.set_button(Button::Gamepad(index), ElementState::Released);
+                }
+            }
+        }
+    }
+
+    /// Feed in an event from `Gamepads`. Every pad drives the same
+    /// buttons and axes: a button is held while any pad holds it, and an
+    /// axis reads the pad pushing it furthest.
+    pub fn handle_gamepad(&mut self, event: &GamepadEvent) {
+        match *event {
+            GamepadEvent::Button(pad, index, pressed) => self.gamepad_button(pad, index, pressed),
+            GamepadEvent::Axis(pad, index, value) => self.gamepad_axis(pad, index, value),
+            GamepadEvent::Disconnected(pad) => self.gamepad_disconnected(pad),
+            GamepadEvent::Connected(_) => {},
+        }
+    }
+
+    fn gamepad_axis_value(&self, index: u8) -> f32 {
+        self.gamepad_axes.values()
+            .filter_map(|axes| axes.get(&index).cloned())
+            .fold(0.0, |furthest, value| if value.abs() > furthest.abs() { value } else { furthest })
+    }
+
+    /// Forget everything held, e.g. when events stop arriving because the
+    /// window lost focus.
+    pub fn release_all(&mut self) {
+        for button in self.down.drain() {
+            self.pending_released.insert(button);
+        }
+        self.gamepad_buttons.clear();
+        self.gamepad_axes.clear();
+    }
+
+    pub fn is_held(&self, button: Button) -> bool {
+        self.held.contains_key(&button)
+    }
+
+    /// Whether `button` went down since the tick before.
+    pub fn is_pressed(&self, button: Button) -> bool {
+        self.pressed.contains(&button)
+    }
+
+    /// Whether `button` came up since the tick before.
+    pub fn is_released(&self, button: Button) -> bool {
+        self.released.contains(&button)
+    }
+
+    /// How many ticks `button` has been held for, counting this one; 0 if
+    /// it isn't held.
+    pub fn held_ticks(&self, button: Button) -> u32 {
+        self.held.get(&button).cloned().unwrap_or(0)
+    }
+
+    /// How far the wheel scrolled since the tick before, in lines. `y` is
+    /// positive scrolling up, away from the user.
+    pub fn wheel(&self) -> (f32, f32) {
+        self.wheel
+    }
+
+    pub fn cursor(&self) -> CursorState {
+        self.cursor
+    }
+
+    /// Show, hide or grab the cursor. Grabbing hides it and keeps it inside
+    /// the window. `App` applies this through `Game::cursor_state`.
+    pub fn set_cursor(&mut self, cursor: CursorState) {
+        self.cursor = cursor;
+    }
+
+    fn buttons(&self, action: &str) -> &[Button] {
+        match self.bindings.actions.get(action) {
+            Some(buttons) => buttons,
+            None => &[],
+        }
+    }
+
+    /// Whether any button bound to `action` is held.
+    pub fn action(&self, action: &str) -> bool {
+        self.buttons(action).iter().any(|button| self.is_held(*button))
+    }
+
+    /// Whether any button bound to `action` was pressed since the tick
+    /// before, i.e. the action should happen once.
+    pub fn action_pressed(&self, action: &str) -> bool {
+        self.buttons(action).iter().any(|button| self.is_pressed(*button))
+    }
+
+    /// Whether a button bound to `action` was released since the tick
+    /// before, and none of them is still held.
+    pub fn action_released(&self, action: &str) -> bool {
+        self.buttons(action).iter().any(|button| self.is_released(*button)) && !self.action(action)
+    }
+
+    /// How many ticks `action` has been held for, by the button held
+    /// longest.
+    pub fn action_held_ticks(&self, action: &str) -> u32 {
+        self.buttons(action).iter().map(|button| self.held_ticks(*button)).max().unwrap_or(0)
+    }
+
+    /// The value of `axis` from -1 to 1, summing all its bindings.
+    pub fn axis(&self, axis: &str) -> f32 {
+        let bindings = match self.bindings.axes.get(axis) {
+            Some(bindings) => bindings,
+            None => return 0.0,
+        };
+        let mut value = 0.0;
+        for binding in bindings.iter() {
+            value += match *binding {
+                AxisBinding::Buttons(negative, positive) => {
+                    let mut value = 0.0;
+                    if self.is_held(negative) {
+                        value -= 1.0;
+                    }
+                    if self.is_held(positive) {
+                        value += 1.0;
+                    }
+                    value
+                }
+                AxisBinding::Gamepad(index, invert) => {
+                    let value = self.gamepad_axis_value(index);
+                    if invert { -value } else { value }
+                }
+            };
+        }
+        value.max(-1.0).min(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glutin::{ElementState, Event, MouseButton, VirtualKeyCode};
+
+    use gamepad::GamepadEvent;
+    use super::{AxisBinding, Bindings, Button, Input};
+
+    const TEXT: &'static str = "# Movement\n\
+                                action fire key:Space mouse:Left gamepad:0\n\
+                                action menu key:Escape mouse:4  # comment\n\
+                                axis move_x key:A key:D\n\
+                                axis move_x gamepad:0\n\
+                                axis move_y gamepad:1 invert\n";
+
+    #[test]
+    fn parse() {
+        let bindings = Bindings::parse(TEXT).unwrap();
+        assert_eq!(bindings.actions["fire"],
+                   [Button::Key(VirtualKeyCode::Space), Button::Mouse(MouseButton::Left), Button::Gamepad(0)]);
+        assert_eq!(bindings.actions["menu"],
+                   [Button::Key(VirtualKeyCode::Escape), Button::Mouse(MouseButton::Other(4))]);
+        assert_eq!(bindings.axes["move_x"],
+                   [AxisBinding::Buttons(Button::Key(VirtualKeyCode::A), Button::Key(VirtualKeyCode::D)),
+                    AxisBinding::Gamepad(0, false)]);
+        assert_eq!(bindings.axes["move_y"], [AxisBinding::Gamepad(1, true)]);
+    }
+
+    #[test]
+    fn round_trip() {
+        let bindings = Bindings::parse(TEXT).unwrap();
+        assert_eq!(Bindings::parse(&bindings.to_text().unwrap()).unwrap(), bindings);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Bindings::parse("action fire\n").is_err());
+        assert!(Bindings::parse("action fire key:Nope\n").is_err());
+        assert!(Bindings::parse("axis move key:A\n").is_err());
+        assert!(Bindings::parse("axis move gamepad:x\n").is_err());
+        assert!(Bindings::parse("axis move gamepad:0 sideways\n").is_err());
+        assert!(Bindings::parse("button fire key:A\n").is_err());
+    }
+
+    #[test]
+    fn unnamed_keys() {
+        // Print Screen isn't in the table of names
+        let snapshot = Button::Key(VirtualKeyCode::Snapshot);
+        assert_eq!(snapshot.to_text(), None);
+        let mut bindings = Bindings::new();
+        bindings.bind("shoot", snapshot);
+        assert!(bindings.to_text().is_err());
+
+        let mut input = Input::new(Bindings::new());
+        input.capture("fire");
+        input.handle_event(&Event::KeyboardInput(ElementState::Pressed, 0, Some(VirtualKeyCode::Snapshot)));
+        assert_eq!(input.capturing(), Some("fire"));
+        input.handle_event(&Event::KeyboardInput(ElementState::Pressed, 0, Some(VirtualKeyCode::F)));
+        assert_eq!(input.capturing(), None);
+        assert_eq!(input.bindings().actions["fire"], [Button::Key(VirtualKeyCode::F)]);
+        assert!(input.bindings().to_text().is_ok());
+    }
+
+    fn key(input: &mut Input, code: VirtualKeyCode, state: ElementState) {
+        input.handle_event(&Event::KeyboardInput(state, 0, Some(code)));
+    }
+
+    #[test]
+    fn latching() {
+        let mut bindings = Bindings::new();
+        bindings.bind("jump", Button::Key(VirtualKeyCode::Space));
+        let mut input = Input::new(bindings);
+        let space = Button::Key(VirtualKeyCode::Space);
+
+        // Nothing shows until the next tick
+        key(&mut input, VirtualKeyCode::Space, ElementState::Pressed);
+        assert!(!input.action("jump"));
+        input.update();
+        assert!(input.action("jump"));
+        assert!(input.action_pressed("jump"));
+        assert_eq!(input.held_ticks(space), 1);
+
+        // Key repeat doesn't press again
+        key(&mut input, VirtualKeyCode::Space, ElementState::Pressed);
+        input.update();
+        assert!(input.action("jump"));
+        assert!(!input.action_pressed("jump"));
+        assert_eq!(input.action_held_ticks("jump"), 2);
+
+        key(&mut input, VirtualKeyCode::Space, ElementState::Released);
+        assert!(input.action("jump"));
+        input.update();
+        assert!(!input.action("jump"));
+        assert!(input.action_released("jump"));
+        assert_eq!(input.held_ticks(space), 0);
+
+        input.update();
+        assert!(!input.action_released("jump"));
+    }
+
+    #[test]
+    fn tap_between_ticks() {
+        let mut bindings = Bindings::new();
+        bindings.bind("jump", Button::Key(VirtualKeyCode::Space));
+        let mut input = Input::new(bindings);
+
+        key(&mut input, VirtualKeyCode::Space, ElementState::Pressed);
+        key(&mut input, VirtualKeyCode::Space, ElementState::Released);
+        input.update();
+        assert!(input.action_pressed("jump"));
+        assert!(input.action_released("jump"));
+        assert!(!input.action("jump"));
+
+        input.update();
+        assert!(!input.action_pressed("jump"));
+        assert!(!input.action_released("jump"));
+    }
+
+    #[test]
+    fn axes_and_focus() {
+        let mut bindings = Bindings::new();
+        bindings.bind_axis("move", AxisBinding::Buttons(Button::Key(VirtualKeyCode::A),
+                                                        Button::Key(VirtualKeyCode::D)));
+        let mut input = Input::new(bindings);
+
+        key(&mut input, VirtualKeyCode::D, ElementState::Pressed);
+        input.update();
+        assert_eq!(input.axis("move"), 1.0);
+        key(&mut input, VirtualKeyCode::A, ElementState::Pressed);
+        input.update();
+        assert_eq!(input.axis("move"), 0.0);
+
+        // Losing focus releases everything, since the releases won't come
+        input.handle_event(&Event::Focused(false));
+        input.update();
+        assert_eq!(input.axis("move"), 0.0);
+        assert!(input.is_released(Button::Key(VirtualKeyCode::D)));
+        assert_eq!(input.axis("missing"), 0.0);
+    }
+
+    #[test]
+    fn gamepads_held_by_pad() {
+        let mut bindings = Bindings::new();
+        bindings.bind("fire", Button::Gamepad(0));
+        bindings.bind_axis("move", AxisBinding::Gamepad(0, false));
+        let mut input = Input::new(bindings);
+
+        input.handle_gamepad(&GamepadEvent::Button(0, 0, true));
+        input.handle_gamepad(&GamepadEvent::Button(1, 0, true));
+        input.handle_gamepad(&GamepadEvent::Axis(0, 0, 0.25));
+        input.handle_gamepad(&GamepadEvent::Axis(1, 0, -0.75));
+        input.update();
+        assert!(input.action_pressed("fire"));
+        assert_eq!(input.axis("move"), -0.75);
+
+        // One pad letting go leaves the button held by the other
+        input.handle_gamepad(&GamepadEvent::Button(0, 0, false));
+        input.update();
+        assert!(input.action("fire"));
+        assert!(!input.action_released("fire"));
+
+        // Unplugging a pad only forgets that pad
+        input.handle_gamepad(&GamepadEvent::Disconnected(0));
+        input.update();
+        assert!(input.action("fire"));
+        assert_eq!(input.axis("move"), -0.75);
+
+        input.handle_gamepad(&GamepadEvent::Disconnected(1));
+        input.update();
+        assert!(!input.action("fire"));
+        assert!(input.action_released("fire"));
+        assert_eq!(input.axis("move"), 0.0);
     }
 }
@@ -0,0 +1,174 @@
+use gfx::{self, Bundle, texture};
+use gfx::traits::FactoryExt;
+
+use error::{self, Result};
+
+// gfx_defines! creates a submodule, so we need `pub use` to make sure
+// the import here is visible.
+pub use types::*;
+
+gfx_defines! {
+    vertex BlitVertex {
+        pos: [f32; 2] = "a_Pos",
+        uv: [f32; 2] = "a_Uv",
+    }
+
+    constant BlitLocals {
+        rect: [f32; 4] = "u_Rect",
+    }
+
+    pipeline blit {
+        vbuf: gfx::VertexBuffer<BlitVertex> = (),
+        texture: gfx::TextureSampler<[f32; 4]> = "t_Texture",
+        locals: gfx::ConstantBuffer<BlitLocals> = "Locals",
+        out: gfx::RenderTarget<ColorFormat> = "Target0",
+    }
+}
+
+/// How the virtual screen is scaled up to fit the window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scaling {
+    /// Only scale by whole multiples, so pixels stay square and crisp.
+    /// Windows smaller than the virtual screen fall back to `Fractional`.
+    Integer,
+    /// Fill as much of the window as the aspect ratio allows.
+    Fractional,
+}
+
+/// A fixed-size offscreen screen, scaled to fit the window with black bars
+/// on the sides or top and bottom.
+pub struct Letterbox<R: gfx::Resources> {
+    width: u16,
+    height: u16,
+    scaling: Scaling,
+    color: gfx::handle::RenderTargetView<R, ColorFormat>,
+    depth: gfx::handle::DepthStencilView<R, DepthFormat>,
+    bundle: Bundle<R, blit::Data<R>>,
+}
+
+impl<R: gfx::Resources> Letterbox<R> {
+    pub fn new<F>(factory: &mut F,
+                  width: u16,
+                  height: u16,
+                  scaling: Scaling,
+                  window: gfx::handle::RenderTargetView<R, ColorFormat>) -> Result<Letterbox<R>>
+        where F: gfx::Factory<R> {
+        let (_, srv, color) = try!(factory.create_render_target::<ColorFormat>(width, height)
+                                   .map_err(error::resource));
+        let depth = try!(factory.create_depth_stencil_view_only::<DepthFormat>(width, height)
+                         .map_err(error::resource));
+
+        let vertices = [
+            BlitVertex { pos: [0.0, 0.0], uv: [0.0, 0.0] },
+            BlitVertex { pos: [1.0, 0.0], uv: [1.0, 0.0] },
+            BlitVertex { pos: [0.0, 1.0], uv: [0.0, 1.0] },
+            BlitVertex { pos: [1.0, 1.0], uv: [1.0, 1.0] },
+        ];
+        let indices: [u16; 6] = [ 0, 1, 3, 0, 3, 2 ];
+        let (vbuf, slice) = factory.create_vertex_buffer_with_slice(&vertices, &indices as &[u16]);
+        let pso = try!(factory.create_pipeline_simple(
+            include_bytes!("shader/blit_150.glslv"),
+            include_bytes!("shader/blit_150.glslf"),
+            blit::new()).map_err(error::shader));
+        let filter = match scaling {
+            Scaling::Integer => texture::FilterMethod::Scale,
+            Scaling::Fractional => texture::FilterMethod::Bilinear,
+        };
+        let sampler = factory.create_sampler(texture::SamplerInfo::new(filter, texture::WrapMode::Clamp));
+        let data = blit::Data {
+            vbuf: vbuf,
+            texture: (srv, sampler),
+            locals: factory.create_constant_buffer(1),
+            out: window,
+        };
+
+        Ok(Letterbox {
+            width: width,
+            height: height,
+            scaling: scaling,
+            color: color,
+            depth: depth,
+            bundle: Bundle::new(slice, pso, data),
+        })
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    /// The target to draw the game into.
+    pub fn color(&self) -> &gfx::handle::RenderTargetView<R, ColorFormat> {
+        &self.color
+    }
+
+    pub fn depth(&self) -> &gfx::handle::DepthStencilView<R, DepthFormat> {
+        &self.depth
+    }
+
+    /// Present into a new window target, after the window was resized.
+    pub fn set_window(&mut self, window: gfx::handle::RenderTargetView<R, ColorFormat>) {
+        self.bundle.data.out = window;
+    }
+
+    fn window_size(&self) -> (f32, f32) {
+        let (width, height, _, _) = self.bundle.data.out.get_dimensions();
+        (width as f32, height as f32)
+    }
+
+    /// Where the virtual screen ends up in the window, as
+    /// `[x, y, width, height]` in pixels from the bottom left.
+    pub fn viewport(&self) -> [f32; 4] {
+        let (window_width, window_height) = self.window_size();
+        let (width, height) = (self.width as f32, self.height as f32);
+
+        let mut scale = f32::min(window_width / width, window_height / height);
+        if self.scaling == Scaling::Integer && scale >= 1.0 {
+            scale = scale.floor();
+        }
+
+        let (scaled_width, scaled_height) = (width * scale, height * scale);
+        [((window_width - scaled_width) / 2.0).floor(),
+         ((window_height - scaled_height) / 2.0).floor(),
+         scaled_width,
+         scaled_height]
+    }
+
+    /// Convert window coordinates to virtual screen coordinates. Both are
+    /// measured from the top left; points in the bars fall outside
+    /// `0..width` and `0..height`. `None` while the window has no size,
+    /// e.g. when minimized.
+    pub fn to_virtual(&self, x: f32, y: f32) -> Option<(f32, f32)> {
+        let (_, window_height) = self.window_size();
+        let viewport = self.viewport();
+        if viewport[2] <= 0.0 || viewport[3] <= 0.0 {
+            return None;
+        }
+        let top = window_height - viewport[1] - viewport[3];
+        Some(((x - viewport[0]) * self.width as f32 / viewport[2],
+              (y - top) * self.height as f32 / viewport[3]))
+    }
+
+    /// Draw the virtual screen into the window. Doesn't clear the bars.
+    pub fn present<C>(&mut self, encoder: &mut gfx::Encoder<R, C>)
+        where C: gfx::CommandBuffer<R> {
+        let (window_width, window_height) = self.window_size();
+        if window_width <= 0.0 || window_height <= 0.0 {
+            return;
+        }
+        let viewport = self.viewport();
+        // The quad is positioned in normalized device coordinates
+        let locals = BlitLocals {
+            rect: [2.0 * viewport[0] / window_width - 1.0,
+                   2.0 * viewport[1] / window_height - 1.0,
+                   2.0 * viewport[2] / window_width,
+                   2.0 * viewport[3] / window_height],
+        };
+
+        encoder.update_buffer(&self.bundle.data.locals, &[locals], 0).unwrap();
+        self.bundle.encode(encoder);
+    }
+}
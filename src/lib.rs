@@ -5,15 +5,32 @@ extern crate gfx_device_gl;
 extern crate gfx_window_glutin;
 extern crate glutin;
 extern crate image;
+extern crate libc;
 extern crate tiled;
 extern crate time;
 
+pub mod archive;
+pub mod assets;
+pub mod atlas;
+pub mod blend;
 pub mod blur;
+pub mod camera;
+pub mod clock;
+pub mod error;
 pub mod game;
+pub mod gamepad;
 pub mod input;
+pub mod letterbox;
+pub mod loader;
 pub mod sprite;
 pub mod tilemap;
+pub mod transform;
 pub mod types;
 pub mod player;
 pub mod rectangle;
 pub mod circle;
+pub mod nine_slice;
+pub mod render_queue;
+pub mod replay;
+pub mod scene;
+pub mod vfs;
@@ -0,0 +1,256 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+
+use gfx;
+use image;
+use tiled;
+
+use error::{Error, Result};
+use sprite::{self, SampledTexture, TextureOptions};
+use tilemap;
+use vfs::{Directory, SharedFileSystem};
+
+const DEFAULT_WORKERS: usize = 2;
+
+enum Job {
+    Texture(usize, PathBuf),
+    Tilemap(usize, PathBuf),
+}
+
+enum Decoded {
+    Texture(usize, Result<image::RgbaImage>),
+    Tilemap(usize, Result<tiled::Map>),
+}
+
+/// An asset that is still being loaded.
+pub struct Loading<T> {
+    slot: Rc<RefCell<Option<Result<T>>>>,
+}
+
+impl<T> Loading<T> {
+    fn new() -> Loading<T> {
+        Loading {
+            slot: Rc::new(RefCell::new(None)),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.slot.borrow().is_some()
+    }
+
+    /// Take the loaded asset (or the error loading it), if it's ready.
+    pub fn take(&self) -> Option<Result<T>> {
+        self.slot.borrow_mut().take()
+    }
+}
+
+impl<T> Clone for Loading<T> {
+    fn clone(&self) -> Loading<T> {
+        Loading {
+            slot: self.slot.clone(),
+        }
+    }
+}
+
+/// Loads assets in the background from a `FileSystem`.
+///
+/// Images are decoded and maps are parsed on worker threads; decoded
+/// images are uploaded to the GPU by `upload`, which must be called on the
+/// main thread (`App` does this every frame for the `Game::loader`).
+pub struct Loader<R: gfx::Resources> {
+    jobs: Option<mpsc::Sender<Job>>,
+    results: mpsc::Receiver<Decoded>,
+    workers: Vec<thread::JoinHandle<()>>,
+    next_id: usize,
+    textures: HashMap<usize, (Loading<SampledTexture<R>>, TextureOptions)>,
+    tilemaps: HashMap<usize, Loading<tiled::Map>>,
+    requested: usize,
+    finished: usize,
+}
+
+fn work(fs: SharedFileSystem, jobs: Arc<Mutex<mpsc::Receiver<Job>>>, results: mpsc::Sender<Decoded>) {
+    loop {
+        let job = {
+            // A worker that panicked holding the lock leaves the receiver
+            // itself intact, so keep going
+            let jobs = jobs.lock().unwrap_or_else(|err| err.into_inner());
+            jobs.recv()
+        };
+
+        let decoded = match job {
+            Ok(Job::Texture(id, path)) => Decoded::Texture(id, sprite::decode_texture_from(&*fs, path)),
+            Ok(Job::Tilemap(id, path)) => Decoded::Tilemap(id, tilemap::load_tilemap_from(&*fs, path)),
+            // The loader was dropped
+            Err(_) => break,
+        };
+
+        if results.send(decoded).is_err() {
+            break;
+        }
+    }
+}
+
+impl<R: gfx::Resources> Loader<R> {
+    pub fn new() -> Loader<R> {
+        Loader::with_workers(DEFAULT_WORKERS)
+    }
+
+    pub fn with_workers(count: usize) -> Loader<R> {
+        Loader::with_file_system(Arc::new(Directory::new(".")), count)
+    }
+
+    /// Load from `fs`, e.g. a packed `archive::Archive`, on `count` worker
+    /// threads. There's always at least one.
+    pub fn with_file_system(fs: SharedFileSystem, count: usize) -> Loader<R> {
+        let count = ::std::cmp::max(count, 1);
+        let (job_tx, job_rx) = mpsc::channel();
+        let (result_tx, result_rx) = mpsc::channel();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+
+        let workers = (0..count).map(|_| {
+            let jobs = job_rx.clone();
+            let results = result_tx.clone();
+            let fs = fs.clone();
+            thread::spawn(move || work(fs, jobs, results))
+        }).collect();
+
+        Loader {
+            jobs: Some(job_tx),
+            results: result_rx,
+            workers: workers,
+            next_id: 0,
+            textures: HashMap::new(),
+            tilemaps: HashMap::new(),
+            requested: 0,
+            finished: 0,
+        }
+    }
+
+    fn submit(&mut self, job: Job) {
+        self.requested += 1;
+        let job = match self.jobs {
+            Some(ref jobs) => match jobs.send(job) {
+                Ok(()) => return,
+                Err(mpsc::SendError(job)) => job,
+            },
+            None => job,
+        };
+
+        // Every worker has died, so nothing will ever pick the job up
+        let err = || Error::Io(io::Error::new(io::ErrorKind::BrokenPipe, "no loader threads are running"));
+        match job {
+            Job::Texture(id, _) => {
+                if let Some((loading, _)) = self.textures.remove(&id) {
+                    *loading.slot.borrow_mut() = Some(Err(err()));
+                }
+            }
+            Job::Tilemap(id, _) => {
+                if let Some(loading) = self.tilemaps.remove(&id) {
+                    *loading.slot.borrow_mut() = Some(Err(err()));
+                }
+            }
+        }
+        self.finished += 1;
+    }
+
+    fn next_id(&mut self) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    pub fn texture<P>(&mut self, path: P) -> Loading<SampledTexture<R>>
+        where P: AsRef<Path> {
+        self.texture_with(path, &TextureOptions::default())
+    }
+
+    /// Load a texture, uploaded and sampled as `options` ask.
+    pub fn texture_with<P>(&mut self, path: P, options: &TextureOptions) -> Loading<SampledTexture<R>>
+        where P: AsRef<Path> {
+        let id = self.next_id();
+        let loading = Loading::new();
+        self.textures.insert(id, (loading.clone(), *options));
+        self.submit(Job::Texture(id, path.as_ref().to_path_buf()));
+        loading
+    }
+
+    pub fn tilemap<P>(&mut self, path: P) -> Loading<tiled::Map>
+        where P: AsRef<Path> {
+        let id = self.next_id();
+        let loading = Loading::new();
+        self.tilemaps.insert(id, loading.clone());
+        self.submit(Job::Tilemap(id, path.as_ref().to_path_buf()));
+        loading
+    }
+
+    fn finish_one<F>(&mut self, factory: &mut F, decoded: Decoded)
+        where F: gfx::Factory<R> {
+        match decoded {
+            Decoded::Texture(id, img) => {
+                if let Some((loading, options)) = self.textures.remove(&id) {
+                    let texture = match img.and_then(|img| sprite::upload_texture_with(factory, &img, &options)) {
+                        Ok(texture) => Ok(SampledTexture::new(factory, texture, &options)),
+                        Err(err) => Err(err),
+                    };
+                    *loading.slot.borrow_mut() = Some(texture);
+                }
+            }
+            Decoded::Tilemap(id, map) => {
+                if let Some(loading) = self.tilemaps.remove(&id) {
+                    *loading.slot.borrow_mut() = Some(map);
+                }
+            }
+        }
+        self.finished += 1;
+    }
+
+    /// Finish off everything the workers have decoded so far.
+    pub fn upload<F>(&mut self, factory: &mut F)
+        where F: gfx::Factory<R> {
+        while let Ok(decoded) = self.results.try_recv() {
+            self.finish_one(factory, decoded);
+        }
+    }
+
+    /// Wait for everything requested to load, e.g. so the ticks loading
+    /// takes don't depend on how fast the disk is.
+    pub fn finish<F>(&mut self, factory: &mut F)
+        where F: gfx::Factory<R> {
+        while !self.is_done() {
+            match self.results.recv() {
+                Ok(decoded) => self.finish_one(factory, decoded),
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// The fraction of requested assets that have finished loading, from
+    /// 0 to 1.
+    pub fn progress(&self) -> f32 {
+        if self.requested == 0 {
+            1.0
+        }
+        else {
+            self.finished as f32 / self.requested as f32
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.finished == self.requested
+    }
+}
+
+impl<R: gfx::Resources> Drop for Loader<R> {
+    fn drop(&mut self) {
+        // Hang up so the workers exit, then wait for them
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
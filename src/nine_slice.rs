@@ -0,0 +1,304 @@
+use cgmath;
+use gfx;
+use gfx::traits::FactoryExt;
+
+use blend::BlendMode;
+use render_queue::Drawable;
+use sprite::{self, pipe, Locals, Pipeline, Vertex};
+use transform::Transform;
+
+pub use types::*;
+
+/// Border widths of a nine-slice texture, in texels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Insets {
+    pub left: f32,
+    pub right: f32,
+    pub bottom: f32,
+    pub top: f32,
+}
+
+impl Insets {
+    pub fn uniform(inset: f32) -> Insets {
+        Insets {
+            left: inset,
+            right: inset,
+            bottom: inset,
+            top: inset,
+        }
+    }
+}
+
+/// A textured panel whose corners keep their size when resized.
+///
+/// The edges and centre stretch to fill the panel, or with `tile_edges`
+/// the edges repeat instead. Corners and edges are drawn at one world unit
+/// per texel, except that panels too small for their corners squash them
+/// so no inset is more than half the panel. The panel's bottom left corner
+/// sits at the origin of its `transform`.
+pub struct NineSlice<R: gfx::Resources> {
+    pso: Pipeline<R>,
+    data: pipe::Data<R>,
+    slice: gfx::Slice<R>,
+    texture_width: f32,
+    texture_height: f32,
+    insets: Insets,
+    width: f32,
+    height: f32,
+    tile_edges: bool,
+    pub transform: Transform,
+    pub tint: [f32; 4],
+    pub opacity: f32,
+    pub blend: BlendMode,
+}
+
+// Split [p0, p1] into pieces mapped onto [t0, t1]. When tiling, each piece
+// is at most `step` long and the last one is cut short (along with its
+// texture coordinates) rather than squashed.
+fn segments(p0: f32, p1: f32, t0: f32, t1: f32, step: Option<f32>) -> Vec<(f32, f32, f32, f32)> {
+    let mut result = Vec::new();
+    match step {
+        Some(step) if step > 0.0 => {
+            let mut p = p0;
+            while p < p1 {
+                let end = f32::min(p + step, p1);
+                let fraction = (end - p) / step;
+                result.push((p, end, t0, t0 + (t1 - t0) * fraction));
+                p = end;
+            }
+        }
+        _ => {
+            if p1 > p0 {
+                result.push((p0, p1, t0, t1));
+            }
+        }
+    }
+    result
+}
+
+fn build_mesh(texture_width: f32, texture_height: f32,
+              insets: &Insets, width: f32, height: f32, tile_edges: bool)
+              -> (Vec<Vertex>, Vec<u32>) {
+    let u_left = insets.left / texture_width;
+    let u_right = 1.0 - insets.right / texture_width;
+    // Texture rows run top to bottom, so v = 1 is the bottom of the image
+    let v_bottom = 1.0 - insets.bottom / texture_height;
+    let v_top = insets.top / texture_height;
+    let middle_width = texture_width - insets.left - insets.right;
+    let middle_height = texture_height - insets.bottom - insets.top;
+
+    let left = insets.left.min(width / 2.0);
+    let right = insets.right.min(width / 2.0);
+    let bottom = insets.bottom.min(height / 2.0);
+    let top = insets.top.min(height / 2.0);
+
+    // (start, end, tex start, tex end, is middle)
+    let columns = [
+        (0.0, left, 0.0, u_left, false),
+        (left, width - right, u_left, u_right, true),
+        (width - right, width, u_right, 1.0, false),
+    ];
+    let rows = [
+        (0.0, bottom, 1.0, v_bottom, false),
+        (bottom, height - top, v_bottom, v_top, true),
+        (height - top, height, v_top, 0.0, false),
+    ];
+
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    for &(y0, y1, v0, v1, middle_row) in rows.iter() {
+        for &(x0, x1, u0, u1, middle_column) in columns.iter() {
+            let tile_x = if tile_edges && middle_column && !middle_row { Some(middle_width) } else { None };
+            let tile_y = if tile_edges && middle_row && !middle_column { Some(middle_height) } else { None };
+
+            for &(qy0, qy1, qv0, qv1) in segments(y0, y1, v0, v1, tile_y).iter() {
+                for &(qx0, qx1, qu0, qu1) in segments(x0, x1, u0, u1, tile_x).iter() {
+                    // Tiled edges on a big panel can take more vertices
+                    // than 16 bit indices reach
+                    let offset = vertices.len() as u32;
+                    vertices.push(Vertex { pos: [qx0, qy0], color: [1.0, 1.0, 1.0], uv: [qu0, qv0] });
+                    vertices.push(Vertex { pos: [qx1, qy0], color: [1.0, 1.0, 1.0], uv: [qu1, qv0] });
+                    vertices.push(Vertex { pos: [qx0, qy1], color: [1.0, 1.0, 1.0], uv: [qu0, qv1] });
+                    vertices.push(Vertex { pos: [qx1, qy1], color: [1.0, 1.0, 1.0], uv: [qu1, qv1] });
+                    indices.extend_from_slice(&[
+                        offset, offset + 1, offset + 3,
+                        offset, offset + 3, offset + 2,
+                    ]);
+                }
+            }
+        }
+    }
+
+    (vertices, indices)
+}
+
+impl<R: gfx::Resources> NineSlice<R> {
+    pub fn new<F>(
+        factory: &mut F,
+        pso: Pipeline<R>,
+        sampler: gfx::handle::Sampler<R>,
+        target: gfx::handle::RenderTargetView<R, ColorFormat>,
+        texture: Texture<R>,
+        texture_width: f32,
+        texture_height: f32,
+        insets: Insets,
+        width: f32,
+        height: f32,
+        tile_edges: bool) -> NineSlice<R>
+        where F: gfx::Factory<R> {
+        let (vertices, indices) = build_mesh(texture_width, texture_height, &insets, width, height, tile_edges);
+        let (vbuf, slice) = factory.create_vertex_buffer_with_slice(&vertices, indices.as_slice());
+        let data = pipe::Data {
+            vbuf: vbuf,
+            texture: (texture, sampler),
+            locals: factory.create_constant_buffer(1),
+            alpha_cutoff: 0.0,
+            out: target,
+        };
+
+        NineSlice {
+            pso: pso,
+            data: data,
+            slice: slice,
+            texture_width: texture_width,
+            texture_height: texture_height,
+            insets: insets,
+            width: width,
+            height: height,
+            tile_edges: tile_edges,
+            transform: Transform::new(),
+            tint: [1.0, 1.0, 1.0, 1.0],
+            opacity: 1.0,
+            blend: BlendMode::Alpha,
+        }
+    }
+
+    pub fn width(&self) -> f32 {
+        self.width
+    }
+
+    pub fn height(&self) -> f32 {
+        self.height
+    }
+
+    pub fn tile_edges(&self) -> bool {
+        self.tile_edges
+    }
+
+    pub fn resize<F>(&mut self, factory: &mut F, width: f32, height: f32)
+        where F: gfx::Factory<R> {
+        self.width = width;
+        self.height = height;
+        self.rebuild(factory);
+    }
+
+    pub fn set_tile_edges<F>(&mut self, factory: &mut F, tile_edges: bool)
+        where F: gfx::Factory<R> {
+        self.tile_edges = tile_edges;
+        self.rebuild(factory);
+    }
+
+    fn rebuild<F>(&mut self, factory: &mut F)
+        where F: gfx::Factory<R> {
+        let (vertices, indices) = build_mesh(
+            self.texture_width, self.texture_height, &self.insets,
+            self.width, self.height, self.tile_edges);
+        let (vbuf, slice) = factory.create_vertex_buffer_with_slice(&vertices, indices.as_slice());
+        self.data.vbuf = vbuf;
+        self.slice = slice;
+    }
+
+    pub fn render<C>(&mut self,
+                 encoder: &mut gfx::Encoder<R, C>,
+                 proj: UniformMat4,
+                 view: UniformMat4)
+        where C: gfx::CommandBuffer<R> {
+        let mut model = self.transform.interpolated_matrix();
+        model.w = sprite::snap_position(model.w.truncate()).extend(1.0);
+
+        let locals = Locals {
+            proj: proj,
+            view: view,
+            model: model.into(),
+            tint: self.pso.alpha().color([self.tint[0], self.tint[1], self.tint[2], self.tint[3] * self.opacity]),
+            uv_rect: [0.0, 0.0, 1.0, 1.0],
+        };
+
+        encoder.update_buffer(&self.data.locals, &[locals], 0).unwrap();
+        encoder.draw(&self.slice, &*self.pso.get(self.blend), &self.data);
+    }
+}
+
+impl<R, C> Drawable<R, C> for NineSlice<R>
+    where R: gfx::Resources,
+          C: gfx::CommandBuffer<R> {
+    fn draw(&mut self,
+            encoder: &mut gfx::Encoder<R, C>,
+            proj: UniformMat4,
+            view: UniformMat4) {
+        self.render(encoder, proj, view);
+    }
+
+    fn sort_position(&self) -> cgmath::Vector3<f32> {
+        self.transform.world_position()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Insets, build_mesh, segments};
+
+    #[test]
+    fn stretched_segments() {
+        assert_eq!(segments(2.0, 10.0, 0.25, 0.75, None), [(2.0, 10.0, 0.25, 0.75)]);
+        // Nothing to draw for an empty span
+        assert!(segments(4.0, 4.0, 0.0, 1.0, None).is_empty());
+        assert!(segments(4.0, 4.0, 0.0, 1.0, Some(2.0)).is_empty());
+        // A zero step can't tile, so it stretches
+        assert_eq!(segments(0.0, 4.0, 0.0, 1.0, Some(0.0)), [(0.0, 4.0, 0.0, 1.0)]);
+    }
+
+    #[test]
+    fn tiled_segments() {
+        assert_eq!(segments(0.0, 8.0, 0.0, 1.0, Some(4.0)),
+                   [(0.0, 4.0, 0.0, 1.0), (4.0, 8.0, 0.0, 1.0)]);
+        // The last piece is cut short along with its texture coordinates
+        assert_eq!(segments(0.0, 10.0, 1.0, 0.0, Some(4.0)),
+                   [(0.0, 4.0, 1.0, 0.0), (4.0, 8.0, 1.0, 0.0), (8.0, 10.0, 1.0, 0.5)]);
+    }
+
+    #[test]
+    fn mesh() {
+        let insets = Insets { left: 4.0, right: 4.0, bottom: 2.0, top: 2.0 };
+        let (vertices, indices) = build_mesh(16.0, 16.0, &insets, 32.0, 8.0, false);
+        assert_eq!(vertices.len(), 9 * 4);
+        assert_eq!(indices.len(), 9 * 6);
+        let max_x = vertices.iter().map(|vertex| vertex.pos[0]).fold(0.0, f32::max);
+        let max_y = vertices.iter().map(|vertex| vertex.pos[1]).fold(0.0, f32::max);
+        assert_eq!((max_x, max_y), (32.0, 8.0));
+
+        // Tiling the 8 texel wide middle along the 24 unit top and bottom
+        // edges makes three pieces each, and the 12 texel tall middle once
+        // along the 4 unit sides
+        let (vertices, _) = build_mesh(16.0, 16.0, &insets, 32.0, 8.0, true);
+        assert_eq!(vertices.len(), (4 + 3 + 3 + 1 + 1 + 1) * 4);
+    }
+
+    #[test]
+    fn large_tiled_mesh() {
+        // A one texel middle tiled along 40000 unit edges needs far more
+        // than 65536 vertices
+        let (vertices, indices) = build_mesh(3.0, 3.0, &Insets::uniform(1.0), 40000.0, 3.0, true);
+        assert!(vertices.len() > 65536);
+        assert_eq!(indices.iter().cloned().max(), Some(vertices.len() as u32 - 1));
+    }
+
+    #[test]
+    fn small_panels_squash_corners() {
+        let (vertices, _) = build_mesh(16.0, 16.0, &Insets::uniform(6.0), 8.0, 4.0, false);
+        // The middle column and row have no size, so only corners are left
+        assert_eq!(vertices.len(), 4 * 4);
+        assert!(vertices.iter().all(|vertex| vertex.pos[0] <= 8.0 && vertex.pos[1] <= 4.0));
+        assert!(vertices.iter().any(|vertex| vertex.pos == [4.0, 2.0]));
+    }
+}
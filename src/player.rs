@@ -1,4 +1,5 @@
 use ::std::f32;
+use std::hash::Hasher;
 
 use cgmath;
 use gfx;
@@ -6,9 +7,14 @@ use gfx;
 use cgmath::Rotation3;
 
 use input::Input;
+use render_queue::Drawable;
+use replay::StateHasher;
 use sprite::Sprite;
 use types::*;
 
+/// Ticks between shots.
+const RELOAD_TICKS: u32 = 25;
+
 pub struct Player<R>
     where R: gfx::Resources {
     sprite: Sprite<R>,
@@ -17,15 +23,18 @@ pub struct Player<R>
     velocity: f32,
     angular_velocity: f32,
     angle: f32,
+    aim: f32,
+    reload: u32,
 }
 
 impl<R> Player<R>
     where R: gfx::Resources {
     pub fn new(mut sprite: Sprite<R>, mut barrel: Sprite<R>) -> Player<R> {
-        barrel.position.x = sprite.width / 2.0 - barrel.width / 2.0;
-        barrel.position.y = sprite.height / 2.0;
-        barrel.rotation_center = cgmath::vec3(barrel.width / 2.0, 0.0, 0.0);
-        sprite.rotation_center = cgmath::vec3(sprite.width / 2.0, sprite.height / 2.0, 0.0);
+        sprite.transform.set_rotation_center(cgmath::vec3(sprite.width / 2.0, sprite.height / 2.0, 0.0));
+        // The barrel pivots around the middle of the hull and moves with it
+        barrel.transform.set_parent(Some(&sprite.transform));
+        barrel.transform.set_position(cgmath::vec3(sprite.width / 2.0 - barrel.width / 2.0, sprite.height / 2.0, 0.0));
+        barrel.transform.set_rotation_center(cgmath::vec3(barrel.width / 2.0, 0.0, 0.0));
         Player {
             sprite: sprite,
             barrel: barrel,
@@ -33,25 +42,54 @@ impl<R> Player<R>
             velocity: 0.0,
             angular_velocity: 0.0,
             angle: 0.0,
+            aim: 0.0,
+            reload: 0,
         }
     }
 
     pub fn position(&self) -> (f32, f32) {
-        (self.sprite.position.x, self.sprite.position.y)
+        let position = self.sprite.transform.position();
+        (position.x, position.y)
     }
 
-    pub fn update(&mut self, input: &Input) {
-        if input.forward || input.backward {
-            if input.forward {
-                self.acceleration = 0.05;
-            }
-            if input.backward {
-                self.acceleration = -0.05;
-            }
-        }
-        else {
-            self.acceleration = 0.0;
+    /// The middle of the hull, which the barrel turns around.
+    pub fn center(&self) -> (f32, f32) {
+        let (x, y) = self.position();
+        (x + self.sprite.width / 2.0, y + self.sprite.height / 2.0)
+    }
+
+    /// The direction the barrel points in.
+    pub fn aim(&self) -> cgmath::Vector2<f32> {
+        let angle = self.aim + f32::consts::PI / 2.0;
+        cgmath::vec2(f32::cos(angle), f32::sin(angle))
+    }
+
+    /// Fire if reloaded, returning the direction of the shot.
+    pub fn fire(&mut self) -> Option<cgmath::Vector2<f32>> {
+        if self.reload > 0 {
+            return None;
         }
+        self.reload = RELOAD_TICKS;
+        Some(self.aim())
+    }
+
+    /// Hash everything `update` changes, for `Game::state_hash`.
+    pub fn hash_state(&self, hasher: &mut StateHasher) {
+        let (x, y) = self.position();
+        hasher.write_f32(x);
+        hasher.write_f32(y);
+        hasher.write_f32(self.acceleration);
+        hasher.write_f32(self.velocity);
+        hasher.write_f32(self.angular_velocity);
+        hasher.write_f32(self.angle);
+        hasher.write_f32(self.aim);
+        hasher.write_u32(self.reload);
+    }
+
+    pub fn update(&mut self, input: &Input) {
+        self.reload = self.reload.saturating_sub(1);
+
+        self.acceleration = 0.05 * input.axis("throttle");
 
         if self.velocity > 0.0 {
             self.velocity = f32::max(self.velocity - 0.025, 0.0);
@@ -63,31 +101,28 @@ impl<R> Player<R>
         self.velocity = f32::min(self.velocity, 2.0);
         self.velocity = f32::max(self.velocity, -2.0);
 
-        if input.left {
-            self.angular_velocity = 0.05;
-        }
-        else if input.right {
-            self.angular_velocity = -0.05;
-        }
-        else {
-            self.angular_velocity = 0.0;
-        }
+        // Steering right turns clockwise
+        self.angular_velocity = -0.05 * input.axis("steer");
 
         self.velocity += self.acceleration;
         let angle = self.angle + f32::consts::PI / 2.0;
         let dx = self.velocity * f32::cos(angle);
         let dy = self.velocity * f32::sin(angle);
-        self.sprite.position.x += dx;
-        self.barrel.position.x += dx;
-        self.sprite.position.y += dy;
-        self.barrel.position.y += dy;
+        self.sprite.transform.translate(cgmath::vec3(dx, dy, 0.0));
         self.angle += self.angular_velocity;
-        self.sprite.rotation = cgmath::Basis3::from_angle_z(cgmath::Rad { s: self.angle });
+        self.sprite.transform.set_rotation(cgmath::Basis3::from_angle_z(cgmath::Rad { s: self.angle }));
 
-        let cx = self.barrel.position.x + self.barrel.rotation_center.x;
-        let cy = self.barrel.position.y;
-        let angle = f32::atan2(input.world_y - cy, input.world_x - cx) - f32::consts::PI / 2.0;
-        self.barrel.rotation = cgmath::Basis3::from_angle_z(cgmath::Rad { s: angle });
+        // Aim with the right stick while it's pushed, otherwise at the
+        // mouse; the barrel's rotation is relative to the hull's
+        let (stick_x, stick_y) = (input.axis("aim_x"), input.axis("aim_y"));
+        self.aim = if stick_x != 0.0 || stick_y != 0.0 {
+            f32::atan2(stick_y, stick_x) - f32::consts::PI / 2.0
+        }
+        else {
+            let (cx, cy) = self.center();
+            f32::atan2(input.world_y - cy, input.world_x - cx) - f32::consts::PI / 2.0
+        };
+        self.barrel.transform.set_rotation(cgmath::Basis3::from_angle_z(cgmath::Rad { s: self.aim - self.angle }));
     }
 
     pub fn render<C>(&mut self,
@@ -99,3 +134,18 @@ impl<R> Player<R>
         self.barrel.render(encoder, proj, view);
     }
 }
+
+impl<R, C> Drawable<R, C> for Player<R>
+    where R: gfx::Resources,
+          C: gfx::CommandBuffer<R> {
+    fn draw(&mut self,
+            encoder: &mut gfx::Encoder<R, C>,
+            proj: UniformMat4,
+            view: UniformMat4) {
+        self.render(encoder, proj, view);
+    }
+
+    fn sort_position(&self) -> cgmath::Vector3<f32> {
+        self.sprite.transform.world_position()
+    }
+}
@@ -1,7 +1,14 @@
-use cgmath::{self, Rotation};
+use std::rc::Rc;
+
+use cgmath;
 use gfx;
 use gfx::traits::FactoryExt;
 
+use blend::{AlphaMode, BlendMode, PipelineSet};
+use error::{self, Result};
+use render_queue::Drawable;
+use transform::Transform;
+
 pub use types::*;
 
 // TODO: make a_Color a uniform instead?
@@ -25,20 +32,37 @@ gfx_defines! {
     }
 }
 
-pub struct Rectangle<R: gfx::Resources> {
-    pso: gfx::PipelineState<R, pipe::Meta>,
-    data: pipe::Data<R>,
-    slice: gfx::Slice<R>,
-    pub position: cgmath::Vector3<f32>,
-    pub scale: f32,
-    pub rotation: cgmath::Basis3<f32>,
-    pub rotation_center: cgmath::Vector3<f32>,
-    pub width: f32,
-    pub height: f32,
+/// Creates rectangles sharing one set of pipelines, one per blend mode, so
+/// keep one around rather than making one per rectangle.
+pub struct RectangleFactory<R: gfx::Resources> {
+    pso: Rc<PipelineSet<R, pipe::Meta>>,
 }
 
-impl<R: gfx::Resources> Rectangle<R> {
-    pub fn new<F>(
+impl<R: gfx::Resources> RectangleFactory<R> {
+    pub fn new<F>(factory: &mut F) -> Result<RectangleFactory<R>>
+        where F: gfx::Factory<R> {
+        // Compile once, and only vary the blend state per pipeline
+        let shader_set = try!(factory.create_shader_set(
+            include_bytes!("shader/rectangle_150.glslv"),
+            include_bytes!("shader/rectangle_150.glslf")).map_err(error::shader));
+        let pso = try!(PipelineSet::new(AlphaMode::Straight, |blend| {
+            factory.create_pipeline_state(
+                &shader_set,
+                gfx::Primitive::TriangleList,
+                gfx::state::Rasterizer::new_fill(),
+                pipe::Init {
+                    out: ("Target0", gfx::state::ColorMask::all(), blend),
+                    ..pipe::new()
+                },
+            ).map_err(error::shader)
+        }));
+        Ok(RectangleFactory {
+            pso: Rc::new(pso),
+        })
+    }
+
+    pub fn create<F>(
+        &self,
         factory: &mut F,
         target: gfx::handle::RenderTargetView<R, ColorFormat>,
         color: [f32; 3],
@@ -57,10 +81,6 @@ impl<R: gfx::Resources> Rectangle<R> {
         ];
         let (vertex_buffer, slice) = factory.create_vertex_buffer_with_slice(
             &vertices, &indices as &[u16]);
-        let pso = factory.create_pipeline_simple(
-            include_bytes!("shader/rectangle_150.glslv"),
-            include_bytes!("shader/rectangle_150.glslf"),
-            pipe::new()).unwrap();
         let data = pipe::Data {
             vbuf: vertex_buffer,
             locals: factory.create_constant_buffer(1),
@@ -68,35 +88,35 @@ impl<R: gfx::Resources> Rectangle<R> {
         };
 
         Rectangle {
-            pso: pso,
+            pso: self.pso.clone(),
             data: data,
             slice: slice,
-            position: cgmath::vec3(0.0, 0.0, 0.0),
-            scale: 1.0,
-            rotation: cgmath::Basis3::one(),
-            rotation_center: cgmath::vec3(0.0, 0.0, 0.0),
+            transform: Transform::new(),
             width: width,
             height: height,
+            blend: BlendMode::Alpha,
         }
     }
+}
 
+pub struct Rectangle<R: gfx::Resources> {
+    pso: Rc<PipelineSet<R, pipe::Meta>>,
+    data: pipe::Data<R>,
+    slice: gfx::Slice<R>,
+    pub transform: Transform,
+    pub width: f32,
+    pub height: f32,
+    pub blend: BlendMode,
+}
+
+impl<R: gfx::Resources> Rectangle<R> {
     pub fn render<C>(&mut self,
                  encoder: &mut gfx::Encoder<R, C>,
                  proj: UniformMat4,
                  view: UniformMat4)
         where C: gfx::CommandBuffer<R> {
-        // TODO: cache recomputation of model matrix where possible
-        let translate_to_center = cgmath::Matrix4::from_translation(-self.rotation_center);
-        let rotation: cgmath::Matrix4<f32> = cgmath::Decomposed {
-            scale: 1.0,
-            rot: self.rotation,
-            disp: cgmath::vec3(0.0, 0.0, 0.0),
-        }.into();
-        let translate_from_center = cgmath::Matrix4::from_translation(self.rotation_center);
-        let scale = cgmath::Matrix4::from_nonuniform_scale(self.scale * self.width, self.scale * self.height, 1.0);
-        let translate_to_position = cgmath::Matrix4::from_translation(self.position);
-
-        let model = translate_to_position * translate_from_center * rotation * translate_to_center * scale;
+        let size = cgmath::Matrix4::from_nonuniform_scale(self.width, self.height, 1.0);
+        let model = self.transform.interpolated_matrix() * size;
 
         let locals = Locals {
             proj: proj,
@@ -105,6 +125,21 @@ impl<R: gfx::Resources> Rectangle<R> {
         };
 
         encoder.update_buffer(&self.data.locals, &[locals], 0).unwrap();
-        encoder.draw(&self.slice, &self.pso, &self.data);
+        encoder.draw(&self.slice, &*self.pso.get(self.blend), &self.data);
+    }
+}
+
+impl<R, C> Drawable<R, C> for Rectangle<R>
+    where R: gfx::Resources,
+          C: gfx::CommandBuffer<R> {
+    fn draw(&mut self,
+            encoder: &mut gfx::Encoder<R, C>,
+            proj: UniformMat4,
+            view: UniformMat4) {
+        self.render(encoder, proj, view);
+    }
+
+    fn sort_position(&self) -> cgmath::Vector3<f32> {
+        self.transform.world_position()
     }
 }
@@ -0,0 +1,154 @@
+use std::cmp::Ordering;
+
+use cgmath;
+use gfx;
+
+pub use types::*;
+
+pub type Layer = i32;
+
+/// How drawables within a single layer are ordered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortMode {
+    /// Draw in the order things were submitted.
+    Submission,
+    /// Draw lower z first.
+    Z,
+    /// Draw higher y first, so things nearer the bottom of the screen
+    /// overlap things behind them (for top-down games).
+    Y,
+    /// Draw opaque and depth tested against the queue's depth target,
+    /// so z decides what's in front without sorting. Texels with alpha
+    /// below `sprite::OPAQUE_ALPHA_CUTOFF` are cut out rather than blended.
+    /// Without a depth target this sorts like `Z`.
+    Depth,
+}
+
+pub trait Drawable<R, C>
+    where R: gfx::Resources,
+          C: gfx::CommandBuffer<R> {
+    fn draw(&mut self,
+            encoder: &mut gfx::Encoder<R, C>,
+            proj: UniformMat4,
+            view: UniformMat4);
+
+    /// Draw without blending, depth tested against `depth`, for
+    /// `SortMode::Depth` layers. Drawables without an opaque pipeline just
+    /// `draw`.
+    fn draw_opaque(&mut self,
+                   encoder: &mut gfx::Encoder<R, C>,
+                   proj: UniformMat4,
+                   view: UniformMat4,
+                   _depth: &gfx::handle::DepthStencilView<R, DepthFormat>) {
+        self.draw(encoder, proj, view);
+    }
+
+    /// The position used for z- and y-sorting.
+    fn sort_position(&self) -> cgmath::Vector3<f32> {
+        cgmath::vec3(0.0, 0.0, 0.0)
+    }
+}
+
+struct Item<'a, R, C>
+    where R: gfx::Resources,
+          C: gfx::CommandBuffer<R>,
+          R: 'a,
+          C: 'a {
+    layer: Layer,
+    key: f32,
+    opaque: bool,
+    drawable: &'a mut Drawable<R, C>,
+}
+
+/// Collects drawables for a frame and draws them back to front.
+///
+/// Layers are drawn in ascending order; within a layer, drawables are
+/// ordered by that layer's `SortMode` (submission order by default).
+///
+/// Opaque geometry can go in `SortMode::Depth` layers, which use a depth
+/// buffer (e.g. the `main_depth` from `App`) instead. Clear it before
+/// flushing.
+pub struct RenderQueue<'a, R, C>
+    where R: gfx::Resources,
+          C: gfx::CommandBuffer<R>,
+          R: 'a,
+          C: 'a {
+    sort_modes: Vec<(Layer, SortMode)>,
+    depth: Option<gfx::handle::DepthStencilView<R, DepthFormat>>,
+    items: Vec<Item<'a, R, C>>,
+}
+
+impl<'a, R, C> RenderQueue<'a, R, C>
+    where R: gfx::Resources,
+          C: gfx::CommandBuffer<R> {
+    pub fn new() -> RenderQueue<'a, R, C> {
+        RenderQueue {
+            sort_modes: Vec::new(),
+            depth: None,
+            items: Vec::new(),
+        }
+    }
+
+    pub fn sort_layer(&mut self, layer: Layer, mode: SortMode) {
+        self.sort_modes.retain(|&(l, _)| l != layer);
+        self.sort_modes.push((layer, mode));
+    }
+
+    /// The depth buffer `SortMode::Depth` layers test against.
+    pub fn set_depth_target(&mut self, depth: gfx::handle::DepthStencilView<R, DepthFormat>) {
+        self.depth = Some(depth);
+    }
+
+    fn sort_mode(&self, layer: Layer) -> SortMode {
+        self.sort_modes.iter()
+            .find(|&&(l, _)| l == layer)
+            .map(|&(_, mode)| mode)
+            .unwrap_or(SortMode::Submission)
+    }
+
+    pub fn submit(&mut self, layer: Layer, drawable: &'a mut Drawable<R, C>) {
+        let position = drawable.sort_position();
+        let mode = self.sort_mode(layer);
+        let opaque = mode == SortMode::Depth && self.depth.is_some();
+        let key = match mode {
+            SortMode::Submission => 0.0,
+            SortMode::Depth if opaque => 0.0,
+            SortMode::Z | SortMode::Depth => position.z,
+            SortMode::Y => -position.y,
+        };
+
+        self.items.push(Item {
+            layer: layer,
+            key: key,
+            opaque: opaque,
+            drawable: drawable,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Draw everything submitted so far, leaving the queue empty.
+    pub fn flush(&mut self,
+                 encoder: &mut gfx::Encoder<R, C>,
+                 proj: UniformMat4,
+                 view: UniformMat4) {
+        // sort_by is stable, so ties keep their submission order
+        self.items.sort_by(|a, b| {
+            a.layer.cmp(&b.layer).then_with(
+                || a.key.partial_cmp(&b.key).unwrap_or(Ordering::Equal))
+        });
+
+        for item in self.items.drain(..) {
+            match self.depth {
+                Some(ref depth) if item.opaque => item.drawable.draw_opaque(encoder, proj, view, depth),
+                _ => item.drawable.draw(encoder, proj, view),
+            }
+        }
+    }
+}
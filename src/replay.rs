@@ -0,0 +1,602 @@
+use std::char;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{BufWriter, Read, Write};
+use std::mem;
+use std::path::Path;
+
+use glutin::{self, ElementState, MouseButton, MouseScrollDelta, TouchPhase};
+
+use clock;
+use error::{Error, Result};
+use gamepad::GamepadEvent;
+use input;
+
+const MAGIC: &'static [u8; 4] = b"MGRP";
+/// The format version written by `Recorder`. Bump it on any change to the
+/// encoding.
+pub const VERSION: u16 = 2;
+
+/// How often `App` records a state hash while recording, in ticks.
+pub const CHECKPOINT_TICKS: u64 = 50;
+
+const TAG_END: u8 = 0;
+const TAG_KEY: u8 = 1;
+const TAG_MOUSE_BUTTON: u8 = 2;
+const TAG_MOUSE_MOVED: u8 = 3;
+const TAG_MOUSE_WHEEL: u8 = 4;
+const TAG_FOCUSED: u8 = 5;
+const TAG_CHARACTER: u8 = 6;
+const TAG_GAMEPAD_CONNECTED: u8 = 7;
+const TAG_GAMEPAD_DISCONNECTED: u8 = 8;
+const TAG_GAMEPAD_BUTTON: u8 = 9;
+const TAG_GAMEPAD_AXIS: u8 = 10;
+const TAG_CHECKPOINT: u8 = 11;
+const TAG_RESIZED: u8 = 12;
+
+const NO_KEY: u8 = 0xff;
+
+/// One entry in a recording.
+#[derive(Clone, Debug)]
+pub enum Record {
+    Event(glutin::Event),
+    Gamepad(GamepadEvent),
+    /// The game's state hash after this tick.
+    Checkpoint(u64),
+    /// The end of the recording, and the final state hash.
+    End(u64),
+}
+
+/// A hasher whose output never changes between builds or platforms, for
+/// `Game::state_hash`. It's 64-bit FNV-1a.
+pub struct StateHasher {
+    hash: u64,
+}
+
+impl Default for StateHasher {
+    fn default() -> StateHasher {
+        StateHasher::new()
+    }
+}
+
+impl StateHasher {
+    pub fn new() -> StateHasher {
+        StateHasher {
+            hash: 0xcbf29ce484222325,
+        }
+    }
+
+    /// Hash the exact bits of `value`, since replays are bit-identical.
+    pub fn write_f32(&mut self, value: f32) {
+        self.write_u32(unsafe { mem::transmute::<f32, u32>(value) });
+    }
+}
+
+impl Hasher for StateHasher {
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.hash ^= byte as u64;
+            self.hash = self.hash.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn zigzag(value: i32) -> u64 {
+    ((value << 1) ^ (value >> 31)) as u32 as u64
+}
+
+fn unzigzag(value: u64) -> i32 {
+    let value = value as u32;
+    ((value >> 1) as i32) ^ -((value & 1) as i32)
+}
+
+fn write_f32(out: &mut Vec<u8>, value: f32) {
+    let bits = unsafe { mem::transmute::<f32, u32>(value) };
+    out.extend_from_slice(&[bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8]);
+}
+
+fn state_byte(state: ElementState) -> u8 {
+    match state {
+        ElementState::Pressed => 1,
+        ElementState::Released => 0,
+    }
+}
+
+fn touch_phase_byte(phase: TouchPhase) -> u8 {
+    match phase {
+        TouchPhase::Started => 0,
+        TouchPhase::Moved => 1,
+        TouchPhase::Ended => 2,
+        TouchPhase::Cancelled => 3,
+    }
+}
+
+// Encode a record, or return false for events replays don't keep
+fn encode(out: &mut Vec<u8>, record: &Record) -> Result<bool> {
+    match *record {
+        Record::Event(glutin::Event::KeyboardInput(state, scancode, key)) => {
+            let index = match key {
+                Some(key) => match input::key_index(key) {
+                    Some(index) => index,
+                    // Played back as no key at all, the game would see
+                    // something other than what was recorded
+                    None => return Err(Error::Replay(format!("{:?} can't be recorded", key))),
+                },
+                None => NO_KEY,
+            };
+            out.push(TAG_KEY);
+            out.push(state_byte(state));
+            out.push(scancode);
+            out.push(index);
+        }
+        Record::Event(glutin::Event::MouseInput(state, button)) => {
+            out.push(TAG_MOUSE_BUTTON);
+            out.push(state_byte(state));
+            match button {
+                MouseButton::Left => out.push(0),
+                MouseButton::Right => out.push(1),
+                MouseButton::Middle => out.push(2),
+                MouseButton::Other(index) => {
+                    out.push(3);
+                    out.push(index);
+                }
+            }
+        }
+        Record::Event(glutin::Event::MouseMoved(x, y)) => {
+            out.push(TAG_MOUSE_MOVED);
+            write_varint(out, zigzag(x));
+            write_varint(out, zigzag(y));
+        }
+        Record::Event(glutin::Event::MouseWheel(delta, phase)) => {
+            out.push(TAG_MOUSE_WHEEL);
+            let (kind, x, y) = match delta {
+                MouseScrollDelta::LineDelta(x, y) => (0, x, y),
+                MouseScrollDelta::PixelDelta(x, y) => (1, x, y),
+            };
+            out.push(kind);
+            write_f32(out, x);
+            write_f32(out, y);
+            out.push(touch_phase_byte(phase));
+        }
+        Record::Event(glutin::Event::Focused(focused)) => {
+            out.push(TAG_FOCUSED);
+            out.push(focused as u8);
+        }
+        Record::Event(glutin::Event::ReceivedCharacter(character)) => {
+            out.push(TAG_CHARACTER);
+            write_varint(out, character as u64);
+        }
+        Record::Event(glutin::Event::Resized(width, height)) => {
+            out.push(TAG_RESIZED);
+            write_varint(out, width as u64);
+            write_varint(out, height as u64);
+        }
+        Record::Event(_) => return Ok(false),
+        Record::Gamepad(GamepadEvent::Connected(pad)) => {
+            out.push(TAG_GAMEPAD_CONNECTED);
+            write_varint(out, pad as u64);
+        }
+        Record::Gamepad(GamepadEvent::Disconnected(pad)) => {
+            out.push(TAG_GAMEPAD_DISCONNECTED);
+            write_varint(out, pad as u64);
+        }
+        Record::Gamepad(GamepadEvent::Button(pad, button, pressed)) => {
+            out.push(TAG_GAMEPAD_BUTTON);
+            write_varint(out, pad as u64);
+            out.push(button);
+            out.push(pressed as u8);
+        }
+        Record::Gamepad(GamepadEvent::Axis(pad, axis, value)) => {
+            out.push(TAG_GAMEPAD_AXIS);
+            write_varint(out, pad as u64);
+            out.push(axis);
+            write_f32(out, value);
+        }
+        Record::Checkpoint(hash) => {
+            out.push(TAG_CHECKPOINT);
+            out.extend_from_slice(&u64_bytes(hash));
+        }
+        Record::End(hash) => {
+            out.push(TAG_END);
+            out.extend_from_slice(&u64_bytes(hash));
+        }
+    }
+    Ok(true)
+}
+
+fn u64_bytes(value: u64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    for i in 0..8 {
+        bytes[i] = (value >> (8 * i)) as u8;
+    }
+    bytes
+}
+
+/// Writes a recording: every input event a game is given, stamped with
+/// the number of ticks run before it arrived.
+///
+/// The file starts with `MGRP`, the version and the tick rate. Each record
+/// after that is the ticks since the previous record as a varint, a tag
+/// byte and the tag's fields, ending with an `End` record.
+pub struct Recorder {
+    out: BufWriter<File>,
+    tick: u64,
+    buffer: Vec<u8>,
+}
+
+impl Recorder {
+    pub fn create<P>(path: P, tick_rate: u32) -> Result<Recorder>
+        where P: AsRef<Path> {
+        let mut out = BufWriter::new(try!(File::create(path)));
+        try!(out.write_all(MAGIC));
+        try!(out.write_all(&[VERSION as u8, (VERSION >> 8) as u8]));
+        try!(out.write_all(&[tick_rate as u8, (tick_rate >> 8) as u8,
+                             (tick_rate >> 16) as u8, (tick_rate >> 24) as u8]));
+        Ok(Recorder {
+            out: out,
+            tick: 0,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Add `record`, after `tick` ticks have run. Events that don't affect
+    /// games, like window moves, are skipped. Fails on keys that can't be
+    /// named in a bindings file, which have no number to save them by.
+    pub fn record(&mut self, tick: u64, record: &Record) -> Result<()> {
+        self.buffer.clear();
+        write_varint(&mut self.buffer, tick - self.tick);
+        if try!(encode(&mut self.buffer, record)) {
+            self.tick = tick;
+            try!(self.out.write_all(&self.buffer));
+        }
+        Ok(())
+    }
+
+    /// End the recording with the final state hash.
+    pub fn finish(mut self, tick: u64, hash: u64) -> Result<()> {
+        try!(self.record(tick, &Record::End(hash)));
+        try!(self.out.flush());
+        Ok(())
+    }
+}
+
+fn replay_error(offset: usize, message: &str) -> Error {
+    Error::Replay(format!("byte {}: {}", offset, message))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8> {
+        match self.bytes.get(self.offset) {
+            Some(&byte) => {
+                self.offset += 1;
+                Ok(byte)
+            }
+            None => Err(replay_error(self.offset, "unexpected end of file")),
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let mut value = 0;
+        for i in 0..4 {
+            value |= (try!(self.byte()) as u32) << (8 * i);
+        }
+        Ok(value)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let low = try!(self.u32()) as u64;
+        let high = try!(self.u32()) as u64;
+        Ok(low | high << 32)
+    }
+
+    fn f32(&mut self) -> Result<f32> {
+        let bits = try!(self.u32());
+        Ok(unsafe { mem::transmute::<u32, f32>(bits) })
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = try!(self.byte());
+            if shift >= 64 {
+                return Err(replay_error(self.offset, "varint too long"));
+            }
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn state(&mut self) -> Result<ElementState> {
+        match try!(self.byte()) {
+            0 => Ok(ElementState::Released),
+            1 => Ok(ElementState::Pressed),
+            _ => Err(replay_error(self.offset, "bad button state")),
+        }
+    }
+
+    fn record(&mut self) -> Result<Record> {
+        let record = match try!(self.byte()) {
+            TAG_END => Record::End(try!(self.u64())),
+            TAG_KEY => {
+                let state = try!(self.state());
+                let scancode = try!(self.byte());
+                let key = try!(self.byte());
+                let key = if key == NO_KEY {
+                    None
+                }
+                else {
+                    match input::key_from_index(key) {
+                        Some(key) => Some(key),
+                        None => return Err(replay_error(self.offset, "unknown key")),
+                    }
+                };
+                Record::Event(glutin::Event::KeyboardInput(state, scancode, key))
+            }
+            TAG_MOUSE_BUTTON => {
+                let state = try!(self.state());
+                let button = match try!(self.byte()) {
+                    0 => MouseButton::Left,
+                    1 => MouseButton::Right,
+                    2 => MouseButton::Middle,
+                    3 => MouseButton::Other(try!(self.byte())),
+                    _ => return Err(replay_error(self.offset, "bad mouse button")),
+                };
+                Record::Event(glutin::Event::MouseInput(state, button))
+            }
+            TAG_MOUSE_MOVED => {
+                let x = unzigzag(try!(self.varint()));
+                let y = unzigzag(try!(self.varint()));
+                Record::Event(glutin::Event::MouseMoved(x, y))
+            }
+            TAG_MOUSE_WHEEL => {
+                let kind = try!(self.byte());
+                let (x, y) = (try!(self.f32()), try!(self.f32()));
+                let delta = match kind {
+                    0 => MouseScrollDelta::LineDelta(x, y),
+                    1 => MouseScrollDelta::PixelDelta(x, y),
+                    _ => return Err(replay_error(self.offset, "bad scroll delta")),
+                };
+                let phase = match try!(self.byte()) {
+                    0 => TouchPhase::Started,
+                    1 => TouchPhase::Moved,
+                    2 => TouchPhase::Ended,
+                    3 => TouchPhase::Cancelled,
+                    _ => return Err(replay_error(self.offset, "bad touch phase")),
+                };
+                Record::Event(glutin::Event::MouseWheel(delta, phase))
+            }
+            TAG_FOCUSED => Record::Event(glutin::Event::Focused(try!(self.byte()) != 0)),
+            TAG_CHARACTER => {
+                let code = try!(self.varint());
+                match char::from_u32(code as u32) {
+                    Some(character) => Record::Event(glutin::Event::ReceivedCharacter(character)),
+                    None => return Err(replay_error(self.offset, "bad character")),
+                }
+            }
+            TAG_GAMEPAD_CONNECTED => Record::Gamepad(GamepadEvent::Connected(try!(self.varint()) as usize)),
+            TAG_GAMEPAD_DISCONNECTED => Record::Gamepad(GamepadEvent::Disconnected(try!(self.varint()) as usize)),
+            TAG_GAMEPAD_BUTTON => {
+                let pad = try!(self.varint()) as usize;
+                let button = try!(self.byte());
+                let pressed = try!(self.byte()) != 0;
+                Record::Gamepad(GamepadEvent::Button(pad, button, pressed))
+            }
+            TAG_GAMEPAD_AXIS => {
+                let pad = try!(self.varint()) as usize;
+                let axis = try!(self.byte());
+                let value = try!(self.f32());
+                Record::Gamepad(GamepadEvent::Axis(pad, axis, value))
+            }
+            TAG_CHECKPOINT => Record::Checkpoint(try!(self.u64())),
+            TAG_RESIZED => {
+                let width = try!(self.varint());
+                let height = try!(self.varint());
+                if width > u32::max_value() as u64 || height > u32::max_value() as u64 {
+                    return Err(replay_error(self.offset, "window size out of range"));
+                }
+                Record::Event(glutin::Event::Resized(width as u32, height as u32))
+            }
+            _ => return Err(replay_error(self.offset, "unknown record")),
+        };
+        Ok(record)
+    }
+}
+
+/// A recording read back, to play into `App::replay_hidden`.
+pub struct Replay {
+    pub tick_rate: u32,
+    /// Each record, with the number of ticks run before it.
+    pub records: Vec<(u64, Record)>,
+}
+
+impl Replay {
+    pub fn parse(bytes: &[u8]) -> Result<Replay> {
+        if bytes.len() < 10 || &bytes[..4] != &MAGIC[..] {
+            return Err(replay_error(0, "not a recording"));
+        }
+        let version = bytes[4] as u16 | (bytes[5] as u16) << 8;
+        if version != VERSION {
+            return Err(Error::Replay(format!("recorded with format version {}, expected {}", version, VERSION)));
+        }
+
+        let mut reader = Reader {
+            bytes: bytes,
+            offset: 6,
+        };
+        let tick_rate = try!(reader.u32());
+        if tick_rate == 0 || tick_rate > clock::MAX_TICK_RATE {
+            return Err(replay_error(6, "tick rate out of range"));
+        }
+        let mut records = Vec::new();
+        let mut tick = 0;
+        loop {
+            let offset = reader.offset;
+            tick = match tick.checked_add(try!(reader.varint())) {
+                Some(tick) => tick,
+                None => return Err(replay_error(offset, "tick count overflows")),
+            };
+            let record = try!(reader.record());
+            let end = match record {
+                Record::End(_) => true,
+                _ => false,
+            };
+            records.push((tick, record));
+            if end {
+                break;
+            }
+        }
+
+        Ok(Replay {
+            tick_rate: tick_rate,
+            records: records,
+        })
+    }
+
+    pub fn load<P>(path: P) -> Result<Replay>
+        where P: AsRef<Path> {
+        let mut bytes = Vec::new();
+        try!(try!(File::open(path)).read_to_end(&mut bytes));
+        Replay::parse(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use glutin::{ElementState, Event, MouseButton, MouseScrollDelta, TouchPhase, VirtualKeyCode};
+
+    use gamepad::GamepadEvent;
+    use super::{Reader, Record, Recorder, Replay, unzigzag, write_varint, zigzag};
+
+    #[test]
+    fn varint() {
+        for &value in [0, 1, 127, 128, 300, 1 << 35, u64::max_value()].iter() {
+            let mut bytes = Vec::new();
+            write_varint(&mut bytes, value);
+            let mut reader = Reader { bytes: &bytes, offset: 0 };
+            assert_eq!(reader.varint().unwrap(), value);
+            assert_eq!(reader.offset, bytes.len());
+        }
+
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, 300);
+        assert_eq!(bytes, [0xac, 0x02]);
+
+        // Truncated, and longer than any u64
+        assert!(Reader { bytes: &[0x80], offset: 0 }.varint().is_err());
+        assert!(Reader { bytes: &[0xff; 11], offset: 0 }.varint().is_err());
+    }
+
+    #[test]
+    fn zigzag_round_trip() {
+        assert_eq!(zigzag(0), 0);
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(zigzag(1), 2);
+        assert_eq!(zigzag(-2), 3);
+        for &value in [0, 1, -1, 1000, -1000, i32::max_value(), i32::min_value()].iter() {
+            assert_eq!(unzigzag(zigzag(value)), value);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = env::temp_dir().join("mgmm-replay-round-trip.mgrp");
+        let records = vec![
+            (0, Record::Event(Event::KeyboardInput(ElementState::Pressed, 57, Some(VirtualKeyCode::Space)))),
+            (0, Record::Event(Event::KeyboardInput(ElementState::Released, 200, None))),
+            (3, Record::Event(Event::MouseInput(ElementState::Pressed, MouseButton::Other(7)))),
+            (3, Record::Event(Event::MouseMoved(-20, 640))),
+            (4, Record::Event(Event::MouseWheel(MouseScrollDelta::PixelDelta(0.5, -32.0), TouchPhase::Moved))),
+            (4, Record::Event(Event::Focused(false))),
+            (5, Record::Event(Event::ReceivedCharacter('é'))),
+            (5, Record::Event(Event::Resized(1920, 1080))),
+            (5, Record::Gamepad(GamepadEvent::Connected(1))),
+            (6, Record::Gamepad(GamepadEvent::Button(1, 3, true))),
+            (6, Record::Gamepad(GamepadEvent::Axis(1, 2, -0.25))),
+            (50, Record::Checkpoint(0x0123456789abcdef)),
+            (70, Record::Gamepad(GamepadEvent::Disconnected(1))),
+        ];
+
+        let mut recorder = Recorder::create(&path, 60).unwrap();
+        for &(tick, ref record) in records.iter() {
+            recorder.record(tick, record).unwrap();
+        }
+        // Skipped, since games don't see it
+        recorder.record(70, &Record::Event(Event::Moved(10, 10))).unwrap();
+        recorder.finish(80, 42).unwrap();
+
+        let replay = Replay::load(&path).unwrap();
+        assert_eq!(replay.tick_rate, 60);
+        assert_eq!(replay.records.len(), records.len() + 1);
+        for (&(tick, ref record), &(expected_tick, ref expected)) in replay.records.iter().zip(records.iter()) {
+            assert_eq!(tick, expected_tick);
+            assert_eq!(format!("{:?}", record), format!("{:?}", expected));
+        }
+        match replay.records[records.len()] {
+            (80, Record::End(42)) => {}
+            ref record => panic!("unexpected end {:?}", record),
+        }
+    }
+
+    #[test]
+    fn rejects_bad_recordings() {
+        let path = env::temp_dir().join("mgmm-replay-bad.mgrp");
+        // Keys without a name have nothing to be saved as
+        let mut recorder = Recorder::create(&path, 60).unwrap();
+        let key = Record::Event(Event::KeyboardInput(ElementState::Pressed, 0, Some(VirtualKeyCode::Snapshot)));
+        assert!(recorder.record(0, &key).is_err());
+
+        let header = b"MGRP\x02\x00\x3c\x00\x00\x00";
+        assert!(Replay::parse(b"MGRP").is_err());
+        assert!(Replay::parse(b"XXXX\x02\x00\x3c\x00\x00\x00\x00\x00").is_err());
+        assert!(Replay::parse(b"MGRP\x01\x00\x3c\x00\x00\x00\x00\x00").is_err());
+        // No end record
+        assert!(Replay::parse(header).is_err());
+        // Tick rates of 0 and more than one a nanosecond
+        assert!(Replay::parse(b"MGRP\x02\x00\x00\x00\x00\x00\x00\x00\0\0\0\0\0\0\0\0").is_err());
+        assert!(Replay::parse(b"MGRP\x02\x00\xff\xff\xff\xff\x00\x00\0\0\0\0\0\0\0\0").is_err());
+
+        // Ticks that add up past u64
+        let mut bytes = header.to_vec();
+        for _ in 0..2 {
+            bytes.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+            bytes.extend_from_slice(&[5, 1]);
+        }
+        assert!(Replay::parse(&bytes).is_err());
+
+        let mut bytes = header.to_vec();
+        bytes.extend_from_slice(&[0, 0xee]);
+        assert!(Replay::parse(&bytes).is_err());
+
+        // A window wider than a u32
+        let mut bytes = header.to_vec();
+        bytes.extend_from_slice(&[0, 12, 0x80, 0x80, 0x80, 0x80, 0x10, 1]);
+        assert!(Replay::parse(&bytes).is_err());
+    }
+}
@@ -0,0 +1,403 @@
+use std::hash::Hasher;
+use std::marker::PhantomData;
+use std::mem;
+
+use gfx::{self, texture};
+use gfx::traits::FactoryExt;
+use gfx_device_gl;
+use glutin;
+
+use error::{self, Error, Result};
+use game::{Game, WindowInfo};
+use replay::StateHasher;
+
+// gfx_defines! creates a submodule, so we need `pub use` to make sure
+// the import here is visible.
+pub use types::*;
+
+gfx_defines! {
+    vertex CompositeVertex {
+        pos: [f32; 2] = "a_Pos",
+        uv: [f32; 2] = "a_Uv",
+    }
+
+    constant CompositeLocals {
+        progress: f32 = "u_Progress",
+        mode: i32 = "u_Mode",
+    }
+
+    pipeline composite {
+        vbuf: gfx::VertexBuffer<CompositeVertex> = (),
+        from: gfx::TextureSampler<[f32; 4]> = "t_From",
+        to: gfx::TextureSampler<[f32; 4]> = "t_To",
+        locals: gfx::ConstantBuffer<CompositeLocals> = "Locals",
+        out: gfx::BlendTarget<ColorFormat> = ("Target0", gfx::state::ColorMask::all(), gfx::preset::blend::ALPHA),
+    }
+}
+
+const MODE_FADE: i32 = 0;
+const MODE_WIPE: i32 = 1;
+
+type R = gfx_device_gl::Resources;
+
+/// How one scene gives way to the next.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Effect {
+    /// Switch immediately.
+    Cut,
+    /// Cross-fade over this many ticks.
+    Fade(u32),
+    /// Wipe across from left to right over this many ticks.
+    Wipe(u32),
+}
+
+/// What a scene wants to happen to the stack after a tick or event.
+pub enum Transition {
+    None,
+    /// Cover this scene with another. This one stops ticking until it's
+    /// uncovered.
+    Push(Box<Scene>, Effect),
+    /// Remove this scene, uncovering the one below. Popping the last scene
+    /// quits.
+    Pop(Effect),
+    /// Swap this scene for another.
+    Replace(Box<Scene>, Effect),
+}
+
+/// What a scene gets to create its resources with.
+pub struct SceneContext<'a> {
+    pub factory: &'a mut gfx_device_gl::Factory,
+    /// The scene's own target; everything it draws should go here.
+    pub main_color: &'a RenderTarget,
+    pub main_depth: &'a DepthTarget,
+}
+
+/// One screen of a game, e.g. a title screen, a level or a pause menu.
+///
+/// Only the scene on top of a `SceneStack` ticks and gets events.
+pub trait Scene {
+    /// Create drawables and other resources. Called once, when the scene
+    /// is pushed. An error stops the app.
+    fn init(&mut self, context: &mut SceneContext) -> Result<()>;
+    /// Advance one tick. An error stops the app, like one from `init`.
+    fn tick(&mut self) -> Result<Transition>;
+    /// An error here stops the app at the next tick.
+    fn handle_event(&mut self, _event: &glutin::Event) -> Result<Transition> { Ok(Transition::None) }
+    /// Draw the scene into `target`, which is the `main_color` it was
+    /// initialized with.
+    fn render(&mut self, encoder: &mut GLEncoder, target: &RenderTarget, alpha: f32);
+    /// Whether the scene is drawn over the one below, e.g. a pause menu
+    /// over the paused game. Overlays should clear to a transparent color.
+    fn is_overlay(&self) -> bool { false }
+    /// Called when the scene above is popped.
+    fn resume(&mut self) {}
+    /// Called after the scene's target was recreated at a new size, so
+    /// anything drawing into old clones of it should be recreated.
+    fn resize(&mut self, _context: &mut SceneContext) -> Result<()> { Ok(()) }
+    /// A hash of the scene's state, for `Game::state_hash`.
+    fn state_hash(&self) -> u64 { 0 }
+}
+
+struct Layer {
+    scene: Box<Scene>,
+    texture: Texture<R>,
+    color: RenderTarget,
+}
+
+fn create_target(factory: &mut gfx_device_gl::Factory, width: u16, height: u16)
+                 -> Result<(Texture<R>, RenderTarget)> {
+    let (_, srv, rtv) = try!(factory.create_render_target::<ColorFormat>(width, height)
+                             .map_err(error::resource));
+    Ok((srv, rtv))
+}
+
+/// A stack of scenes, run as a `Game`. Use `App<SceneStack<S>>` to start
+/// with `S::default()`.
+///
+/// Every scene draws into its own offscreen target, and the visible ones
+/// are composited together, which is what lets transitions blend between
+/// the old and new screens.
+pub struct SceneStack<S> {
+    factory: gfx_device_gl::Factory,
+    main_depth: DepthTarget,
+    size: (u16, u16),
+    layers: Vec<Layer>,
+    /// The composited stack, and the one from before the running
+    /// transition.
+    frame: (Texture<R>, RenderTarget),
+    previous: (Texture<R>, RenderTarget),
+    /// Whether `frame` was drawn since it was last swapped into
+    /// `previous`.
+    rendered: bool,
+    /// The running transition, and the ticks it's been running for.
+    transition: Option<(Effect, u32)>,
+    /// An error or failed transition from `handle_event`, returned by the
+    /// next tick.
+    error: Option<Error>,
+    slice: gfx::Slice<R>,
+    pso: gfx::PipelineState<R, composite::Meta>,
+    vbuf: gfx::handle::Buffer<R, CompositeVertex>,
+    sampler: gfx::handle::Sampler<R>,
+    locals: gfx::handle::Buffer<R, CompositeLocals>,
+    _first: PhantomData<S>,
+}
+
+impl<S> SceneStack<S> {
+    fn new(factory: &mut gfx_device_gl::Factory,
+           main_color: &RenderTarget,
+           main_depth: &DepthTarget) -> Result<SceneStack<S>> {
+        let (width, height, _, _) = main_color.get_dimensions();
+        let frame = try!(create_target(factory, width, height));
+        let previous = try!(create_target(factory, width, height));
+
+        let vertices = [
+            CompositeVertex { pos: [-1.0, -1.0], uv: [0.0, 0.0] },
+            CompositeVertex { pos: [1.0, -1.0], uv: [1.0, 0.0] },
+            CompositeVertex { pos: [-1.0, 1.0], uv: [0.0, 1.0] },
+            CompositeVertex { pos: [1.0, 1.0], uv: [1.0, 1.0] },
+        ];
+        let indices: [u16; 6] = [ 0, 1, 3, 0, 3, 2 ];
+        let (vbuf, slice) = factory.create_vertex_buffer_with_slice(&vertices, &indices as &[u16]);
+        let pso = try!(factory.create_pipeline_simple(
+            include_bytes!("shader/composite_150.glslv"),
+            include_bytes!("shader/composite_150.glslf"),
+            composite::new()).map_err(error::shader));
+        let sampler = factory.create_sampler(
+            texture::SamplerInfo::new(texture::FilterMethod::Scale, texture::WrapMode::Clamp));
+
+        Ok(SceneStack {
+            factory: factory.clone(),
+            main_depth: main_depth.clone(),
+            size: (width, height),
+            layers: vec![],
+            frame: frame,
+            previous: previous,
+            rendered: false,
+            transition: None,
+            error: None,
+            slice: slice,
+            pso: pso,
+            vbuf: vbuf,
+            sampler: sampler,
+            locals: factory.create_constant_buffer(1),
+            _first: PhantomData,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Whether a transition effect is still running.
+    pub fn is_transitioning(&self) -> bool {
+        self.transition.is_some()
+    }
+
+    pub fn push(&mut self, mut scene: Box<Scene>) -> Result<()> {
+        let (texture, color) = try!(create_target(&mut self.factory, self.size.0, self.size.1));
+        try!(scene.init(&mut SceneContext {
+            factory: &mut self.factory,
+            main_color: &color,
+            main_depth: &self.main_depth,
+        }));
+        self.layers.push(Layer {
+            scene: scene,
+            texture: texture,
+            color: color,
+        });
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<Box<Scene>> {
+        let layer = self.layers.pop();
+        if let Some(top) = self.layers.last_mut() {
+            top.scene.resume();
+        }
+        layer.map(|layer| layer.scene)
+    }
+
+    fn begin_transition(&mut self, effect: Effect) {
+        if effect == Effect::Cut {
+            self.transition = None;
+        }
+        else {
+            // Keep the last frame to blend from. With no render since the
+            // last transition began, `previous` already holds it and
+            // `frame` is older still.
+            if self.rendered {
+                mem::swap(&mut self.frame, &mut self.previous);
+                self.rendered = false;
+            }
+            self.transition = Some((effect, 0));
+        }
+    }
+
+    fn apply(&mut self, transition: Transition) -> Result<()> {
+        match transition {
+            Transition::None => Ok(()),
+            Transition::Push(scene, effect) => {
+                self.begin_transition(effect);
+                self.push(scene)
+            }
+            Transition::Pop(effect) => {
+                self.begin_transition(effect);
+                self.pop();
+                Ok(())
+            }
+            Transition::Replace(scene, effect) => {
+                self.begin_transition(effect);
+                self.layers.pop();
+                self.push(scene)
+            }
+        }
+    }
+
+    fn draw(&mut self, encoder: &mut GLEncoder,
+            from: &Texture<R>, to: &Texture<R>, out: &RenderTarget,
+            progress: f32, mode: i32) {
+        let locals = CompositeLocals {
+            progress: progress,
+            mode: mode,
+        };
+        encoder.update_buffer(&self.locals, &[locals], 0).unwrap();
+        let data = composite::Data {
+            vbuf: self.vbuf.clone(),
+            from: (from.clone(), self.sampler.clone()),
+            to: (to.clone(), self.sampler.clone()),
+            locals: self.locals.clone(),
+            out: out.clone(),
+        };
+        encoder.draw(&self.slice, &self.pso, &data);
+    }
+}
+
+impl<S> Game for SceneStack<S>
+    where S: Scene + Default + 'static {
+    fn init(factory: &mut gfx_device_gl::Factory, main_color: &RenderTarget, main_depth: &DepthTarget)
+            -> Result<SceneStack<S>> {
+        let mut stack = try!(SceneStack::new(factory, main_color, main_depth));
+        try!(stack.push(Box::new(S::default())));
+        Ok(stack)
+    }
+
+    fn tick(&mut self) -> Result<()> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+
+        if let Some((effect, elapsed)) = self.transition {
+            let duration = match effect {
+                Effect::Cut => 0,
+                Effect::Fade(duration) | Effect::Wipe(duration) => duration,
+            };
+            self.transition = if elapsed + 1 >= duration {
+                None
+            }
+            else {
+                Some((effect, elapsed + 1))
+            };
+        }
+
+        let transition = match self.layers.last_mut() {
+            Some(top) => try!(top.scene.tick()),
+            None => Transition::None,
+        };
+        self.apply(transition)
+    }
+
+    fn handle_event(&mut self, event: &glutin::Event) {
+        let transition = match self.layers.last_mut() {
+            Some(top) => top.scene.handle_event(event),
+            None => Ok(Transition::None),
+        };
+        if let Err(err) = transition.and_then(|transition| self.apply(transition)) {
+            self.error = Some(err);
+        }
+    }
+
+    fn render(&mut self, encoder: &mut GLEncoder, target: &RenderTarget, alpha: f32) {
+        if self.layers.is_empty() {
+            return;
+        }
+
+        // Draw the top scene and any below it that show through
+        let mut bottom = self.layers.len() - 1;
+        while bottom > 0 && self.layers[bottom].scene.is_overlay() {
+            bottom -= 1;
+        }
+        let frame = self.frame.1.clone();
+        encoder.clear(&frame, [0.0, 0.0, 0.0, 0.0]);
+        for i in bottom..self.layers.len() {
+            let texture = {
+                let layer = &mut self.layers[i];
+                layer.scene.render(encoder, &layer.color, alpha);
+                layer.texture.clone()
+            };
+            self.draw(encoder, &texture, &texture, &frame, 1.0, MODE_FADE);
+        }
+        self.rendered = true;
+
+        let (from, to) = (self.previous.0.clone(), self.frame.0.clone());
+        encoder.clear(target, [0.0, 0.0, 0.0, 1.0]);
+        match self.transition {
+            Some((effect, elapsed)) => {
+                let (mode, duration) = match effect {
+                    Effect::Cut => (MODE_FADE, 1),
+                    Effect::Fade(duration) => (MODE_FADE, duration),
+                    Effect::Wipe(duration) => (MODE_WIPE, duration),
+                };
+                let progress = f32::min(1.0, (elapsed as f32 + alpha) / duration as f32);
+                self.draw(encoder, &from, &to, target, progress, mode);
+            }
+            None => self.draw(encoder, &to, &to, target, 1.0, MODE_FADE),
+        }
+    }
+
+    fn resize(&mut self,
+              factory: &mut gfx_device_gl::Factory,
+              main_color: &RenderTarget,
+              main_depth: &DepthTarget,
+              _info: &WindowInfo) -> Result<()> {
+        let (width, height, _, _) = main_color.get_dimensions();
+        self.main_depth = main_depth.clone();
+        if (width, height) == self.size {
+            return Ok(());
+        }
+
+        self.size = (width, height);
+        self.frame = try!(create_target(factory, width, height));
+        self.previous = try!(create_target(factory, width, height));
+        self.rendered = false;
+        for layer in self.layers.iter_mut() {
+            let (texture, color) = try!(create_target(factory, width, height));
+            layer.texture = texture;
+            layer.color = color;
+            try!(layer.scene.resize(&mut SceneContext {
+                factory: &mut *factory,
+                main_color: &layer.color,
+                main_depth: main_depth,
+            }));
+        }
+        Ok(())
+    }
+
+    fn quit(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    fn state_hash(&self) -> u64 {
+        let mut hasher = StateHasher::new();
+        hasher.write_u64(self.layers.len() as u64);
+        for layer in self.layers.iter() {
+            hasher.write_u64(layer.scene.state_hash());
+        }
+        if let Some((_, elapsed)) = self.transition {
+            hasher.write_u32(elapsed);
+        }
+        hasher.finish()
+    }
+}
@@ -1,9 +1,22 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::cmp;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
 
-use cgmath::{self, Rotation};
+use cgmath;
 use gfx;
 use gfx::traits::FactoryExt;
 
+use assets::{Assets, Handle};
+use atlas::Atlas;
+use blend::{AlphaMode, BlendMode, PipelineSet};
+use error::{self, Result};
+use nine_slice::{Insets, NineSlice};
+use render_queue::Drawable;
+use transform::Transform;
+use vfs::FileSystem;
+
 // gfx_defines! creates a submodule, so we need `pub use` to make sure
 // the import here is visible.
 pub use types::*;
@@ -19,14 +32,26 @@ gfx_defines! {
         proj: UniformMat4 = "u_Proj",
         view: UniformMat4 = "u_View",
         model: UniformMat4 = "u_Model",
+        tint: [f32; 4] = "u_Tint",
+        uv_rect: [f32; 4] = "u_UvRect",
     }
 
     pipeline pipe {
         vbuf: gfx::VertexBuffer<Vertex> = (),
         texture: gfx::TextureSampler<[f32; 4]> = "t_Texture",
         locals: gfx::ConstantBuffer<Locals> = "Locals",
+        alpha_cutoff: gfx::Global<f32> = "u_AlphaCutoff",
         out: gfx::BlendTarget<ColorFormat> = ("Target0", gfx::state::ColorMask::all(), gfx::preset::blend::ALPHA),
     }
+
+    pipeline opaque {
+        vbuf: gfx::VertexBuffer<Vertex> = (),
+        texture: gfx::TextureSampler<[f32; 4]> = "t_Texture",
+        locals: gfx::ConstantBuffer<Locals> = "Locals",
+        alpha_cutoff: gfx::Global<f32> = "u_AlphaCutoff",
+        out: gfx::RenderTarget<ColorFormat> = "Target0",
+        depth: gfx::DepthTarget<DepthFormat> = gfx::preset::depth::LESS_EQUAL_WRITE,
+    }
 }
 
 const TRIANGLE: [Vertex; 4] = [
@@ -36,47 +61,349 @@ const TRIANGLE: [Vertex; 4] = [
     Vertex { pos: [1.0, 1.0], color: [1.0, 1.0, 1.0], uv: [1.0, 0.0] },
 ];
 
+/// Texels less opaque than this are discarded by `Sprite::render_opaque`
+/// rather than drawn and written to the depth buffer.
+pub const OPAQUE_ALPHA_CUTOFF: f32 = 0.5;
+
+const VERTEX_SHADER: &'static [u8] = include_bytes!("shader/sprite_150.glslv");
+const FRAGMENT_SHADER: &'static [u8] = include_bytes!("shader/sprite_150.glslf");
+
 const TRIANGLE_INDICES: [u16; 6] = [
     0, 1, 3,
     0, 3, 2,
 ];
 
+static PIXEL_PERFECT: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Snap every sprite to whole world units when drawing, so pixel art
+/// drawn at one texel per unit stays crisp while it moves.
+pub fn set_pixel_perfect(enabled: bool) {
+    PIXEL_PERFECT.store(enabled, Ordering::Relaxed);
+}
+
+pub fn pixel_perfect() -> bool {
+    PIXEL_PERFECT.load(Ordering::Relaxed)
+}
+
+pub fn snap_position(position: cgmath::Vector3<f32>) -> cgmath::Vector3<f32> {
+    if pixel_perfect() {
+        cgmath::vec3(position.x.round(), position.y.round(), position.z)
+    }
+    else {
+        position
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    Nearest,
+    Linear,
+}
+
+/// How a texture is created and sampled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TextureOptions {
+    pub filter: Filter,
+    pub wrap: gfx::texture::WrapMode,
+    /// Generate a full mipmap chain when uploading.
+    pub mipmaps: bool,
+    /// Multiply colours by alpha when uploading, for drawing with
+    /// `AlphaMode::Premultiplied`. Avoids dark fringes around transparent
+    /// edges when filtering.
+    pub premultiply: bool,
+}
+
+impl Default for TextureOptions {
+    fn default() -> TextureOptions {
+        TextureOptions {
+            filter: Filter::Linear,
+            wrap: gfx::texture::WrapMode::Clamp,
+            mipmaps: false,
+            premultiply: false,
+        }
+    }
+}
+
+impl TextureOptions {
+    /// Nearest-neighbour filtering with no mipmaps, for pixel art and
+    /// tilesheets that shouldn't bleed into neighbouring tiles.
+    pub fn pixel_art() -> TextureOptions {
+        TextureOptions {
+            filter: Filter::Nearest,
+            ..TextureOptions::default()
+        }
+    }
+
+    pub fn sampler_info(&self) -> gfx::texture::SamplerInfo {
+        use gfx::texture::FilterMethod;
+
+        let method = match (self.filter, self.mipmaps) {
+            (Filter::Nearest, false) => FilterMethod::Scale,
+            (Filter::Nearest, true) => FilterMethod::Mipmap,
+            (Filter::Linear, false) => FilterMethod::Bilinear,
+            (Filter::Linear, true) => FilterMethod::Trilinear,
+        };
+        gfx::texture::SamplerInfo::new(method, self.wrap)
+    }
+
+    pub fn create_sampler<F, R>(&self, factory: &mut F) -> gfx::handle::Sampler<R>
+        where F: gfx::Factory<R>,
+              R: gfx::Resources {
+        factory.create_sampler(self.sampler_info())
+    }
+}
+
+/// A texture with the sampler its `TextureOptions` ask for, as `Assets`
+/// and `Loader` hand them out.
+#[derive(Clone)]
+pub struct SampledTexture<R: gfx::Resources> {
+    pub texture: Texture<R>,
+    pub sampler: gfx::handle::Sampler<R>,
+}
+
+impl<R: gfx::Resources> SampledTexture<R> {
+    pub fn new<F>(factory: &mut F, texture: Texture<R>, options: &TextureOptions) -> SampledTexture<R>
+        where F: gfx::Factory<R> {
+        SampledTexture {
+            texture: texture,
+            sampler: options.create_sampler(factory),
+        }
+    }
+}
+
 pub fn load_texture<F, R, P>(factory: &mut F, path: P)
-    -> Result<Texture<R>, String>
+    -> Result<Texture<R>>
     where F: gfx::Factory<R>,
           R: gfx::Resources,
           P: AsRef<::std::path::Path> {
+    load_texture_with(factory, path, &TextureOptions::default())
+}
+
+pub fn load_texture_with<F, R, P>(factory: &mut F, path: P, options: &TextureOptions)
+    -> Result<Texture<R>>
+    where F: gfx::Factory<R>,
+          R: gfx::Resources,
+          P: AsRef<::std::path::Path> {
+    let img = try!(decode_texture(path));
+    upload_texture_with(factory, &img, options)
+}
+
+/// Read and decode an image without touching the GPU, so it can be done
+/// off the main thread.
+pub fn decode_texture<P>(path: P) -> Result<::image::RgbaImage>
+    where P: AsRef<::std::path::Path> {
+    Ok(try!(::image::open(path)).to_rgba())
+}
+
+pub fn load_texture_from<F, R, P>(factory: &mut F, fs: &FileSystem, path: P)
+    -> Result<Texture<R>>
+    where F: gfx::Factory<R>,
+          R: gfx::Resources,
+          P: AsRef<::std::path::Path> {
+    load_texture_from_with(factory, fs, path, &TextureOptions::default())
+}
+
+/// Like `load_texture_with`, but reading from `fs`.
+pub fn load_texture_from_with<F, R, P>(factory: &mut F, fs: &FileSystem, path: P, options: &TextureOptions)
+    -> Result<Texture<R>>
+    where F: gfx::Factory<R>,
+          R: gfx::Resources,
+          P: AsRef<::std::path::Path> {
+    let img = try!(decode_texture_from(fs, path));
+    upload_texture_with(factory, &img, options)
+}
+
+pub fn decode_texture_from<P>(fs: &FileSystem, path: P) -> Result<::image::RgbaImage>
+    where P: AsRef<::std::path::Path> {
+    let bytes = try!(fs.read(path.as_ref()));
+    Ok(try!(::image::load_from_memory(&bytes)).to_rgba())
+}
+
+pub fn upload_texture<F, R>(factory: &mut F, img: &::image::RgbaImage)
+    -> Result<Texture<R>>
+    where F: gfx::Factory<R>,
+          R: gfx::Resources {
+    upload_texture_with(factory, img, &TextureOptions::default())
+}
+
+pub fn upload_texture_with<F, R>(factory: &mut F, img: &::image::RgbaImage, options: &TextureOptions)
+    -> Result<Texture<R>>
+    where F: gfx::Factory<R>,
+          R: gfx::Resources {
     use gfx::texture as t;
+    use image::imageops;
+
+    let premultiplied;
+    let img = if options.premultiply {
+        premultiplied = premultiply(img);
+        &premultiplied
+    }
+    else {
+        img
+    };
 
-    let img = ::image::open(path).unwrap().to_rgba();
     let (width, height) = img.dimensions();
     let kind = t::Kind::D2(width as u16, height as u16, t::AaMode::Single);
-    let (_texture, resource) = factory.create_texture_immutable_u8::<ColorFormat>(kind, &[&img]).unwrap();
+
+    // Halve down to 1x1; gfx takes one slice of data per mip level
+    let mut mips: Vec<::image::RgbaImage> = Vec::new();
+    if options.mipmaps {
+        let (mut w, mut h) = (width, height);
+        while w > 1 || h > 1 {
+            w = cmp::max(w / 2, 1);
+            h = cmp::max(h / 2, 1);
+            let next = imageops::resize(mips.last().unwrap_or(img), w, h, imageops::FilterType::Triangle);
+            mips.push(next);
+        }
+    }
+
+    let mut levels: Vec<&[u8]> = vec![&**img];
+    levels.extend(mips.iter().map(|mip| &**mip));
+
+    let (_texture, resource) = try!(factory.create_texture_immutable_u8::<ColorFormat>(kind, &levels)
+                                    .map_err(error::resource));
 
     Ok(resource)
 }
 
+/// Multiply each pixel's colour by its alpha.
+pub fn premultiply(img: &::image::RgbaImage) -> ::image::RgbaImage {
+    let mut result = img.clone();
+    for pixel in result.pixels_mut() {
+        let alpha = pixel.data[3] as u32;
+        for channel in pixel.data[..3].iter_mut() {
+            *channel = ((*channel as u32 * alpha + 127) / 255) as u8;
+        }
+    }
+    result
+}
+
+/// The sprite pipelines, shared between a factory and everything it
+/// creates so that recompiling shaders affects existing sprites.
+pub type Pipeline<R> = Rc<PipelineSet<R, pipe::Meta>>;
+
+/// The unblended, depth-tested sprite pipeline used in
+/// `SortMode::Depth` layers, shared like `Pipeline`.
+pub type OpaquePipeline<R> = Rc<RefCell<gfx::PipelineState<R, opaque::Meta>>>;
+
+fn compile<F, R>(factory: &mut F, vertex: &[u8], fragment: &[u8], blend: gfx::state::Blend)
+                 -> Result<gfx::PipelineState<R, pipe::Meta>>
+    where F: gfx::Factory<R>,
+          R: gfx::Resources {
+    factory.create_pipeline_simple(vertex, fragment, pipe::Init {
+        out: ("Target0", gfx::state::ColorMask::all(), blend),
+        ..pipe::new()
+    }).map_err(error::shader)
+}
+
+fn compile_opaque<F, R>(factory: &mut F, vertex: &[u8], fragment: &[u8])
+                        -> Result<gfx::PipelineState<R, opaque::Meta>>
+    where F: gfx::Factory<R>,
+          R: gfx::Resources {
+    factory.create_pipeline_simple(vertex, fragment, opaque::new()).map_err(error::shader)
+}
+
+fn build_opaque<F, R>(factory: &mut F,
+                      shaders: &Option<(Handle<Vec<u8>>, Handle<Vec<u8>>)>)
+                      -> Result<gfx::PipelineState<R, opaque::Meta>>
+    where F: gfx::Factory<R>,
+          R: gfx::Resources {
+    match *shaders {
+        Some((ref vertex, ref fragment)) => compile_opaque(factory, &vertex.borrow(), &fragment.borrow()),
+        None => compile_opaque(factory, VERTEX_SHADER, FRAGMENT_SHADER),
+    }
+}
+
+fn build_pipelines<F, R>(factory: &mut F,
+                         shaders: &Option<(Handle<Vec<u8>>, Handle<Vec<u8>>)>,
+                         alpha: AlphaMode) -> Result<PipelineSet<R, pipe::Meta>>
+    where F: gfx::Factory<R>,
+          R: gfx::Resources {
+    match *shaders {
+        Some((ref vertex, ref fragment)) => {
+            let (vertex, fragment) = (vertex.borrow(), fragment.borrow());
+            PipelineSet::new(alpha, |blend| compile(factory, &vertex, &fragment, blend))
+        }
+        None => PipelineSet::new(alpha, |blend| compile(factory, VERTEX_SHADER, FRAGMENT_SHADER, blend)),
+    }
+}
+
 pub struct SpriteFactory<R: gfx::Resources> {
     sampler: gfx::handle::Sampler<R>,
-    pso: Rc<gfx::PipelineState<R, pipe::Meta>>,
+    pso: Pipeline<R>,
+    opaque_pso: OpaquePipeline<R>,
+    shaders: Option<(Handle<Vec<u8>>, Handle<Vec<u8>>)>,
     vbuf: gfx::handle::Buffer<R, Vertex>,
     slice: gfx::Slice<R>,
 }
 
 impl<R> SpriteFactory<R>
     where R: gfx::Resources {
-    pub fn new<F>(factory: &mut F) -> SpriteFactory<R>
+    pub fn new<F>(factory: &mut F) -> Result<SpriteFactory<R>>
+        where F: gfx::Factory<R> {
+        SpriteFactory::with_shaders(factory, None)
+    }
+
+    /// Build the sprite pipeline from shader assets instead of the built-in
+    /// shaders, so it can be recompiled when they change.
+    pub fn from_shaders<F>(factory: &mut F, vertex: Handle<Vec<u8>>, fragment: Handle<Vec<u8>>)
+                           -> Result<SpriteFactory<R>>
+        where F: gfx::Factory<R> {
+        SpriteFactory::with_shaders(factory, Some((vertex, fragment)))
+    }
+
+    fn with_shaders<F>(factory: &mut F,
+                       shaders: Option<(Handle<Vec<u8>>, Handle<Vec<u8>>)>) -> Result<SpriteFactory<R>>
         where F: gfx::Factory<R> {
+        let pso = try!(build_pipelines(factory, &shaders, AlphaMode::Straight));
+        let opaque_pso = try!(build_opaque(factory, &shaders));
         let (vertex_buffer, slice) = factory.create_vertex_buffer_with_slice(
             &TRIANGLE, &TRIANGLE_INDICES as &[u16]);
-        SpriteFactory {
+        Ok(SpriteFactory {
             sampler: factory.create_sampler_linear(),
-            pso: Rc::new(factory.create_pipeline_simple(
-                include_bytes!("shader/sprite_150.glslv"),
-                include_bytes!("shader/sprite_150.glslf"),
-                pipe::new()).unwrap()),
+            pso: Rc::new(pso),
+            opaque_pso: Rc::new(RefCell::new(opaque_pso)),
+            shaders: shaders,
             vbuf: vertex_buffer,
             slice: slice,
+        })
+    }
+
+    /// Switch between straight and premultiplied alpha for sprites created
+    /// from now on. Premultiplied sprites should use textures loaded with
+    /// `TextureOptions::premultiply`.
+    pub fn set_alpha_mode<F>(&mut self, factory: &mut F, alpha: AlphaMode) -> Result<()>
+        where F: gfx::Factory<R> {
+        self.pso = Rc::new(try!(build_pipelines(factory, &self.shaders, alpha)));
+        Ok(())
+    }
+
+    pub fn alpha_mode(&self) -> AlphaMode {
+        self.pso.alpha()
+    }
+
+    /// Rebuild the pipeline from the current contents of the shader assets.
+    /// On failure the old pipeline stays in use and the error is logged.
+    /// Does nothing for a factory using the built-in shaders.
+    pub fn recompile<F>(&self, factory: &mut F) -> bool
+        where F: gfx::Factory<R> {
+        let (vertex, fragment) = match self.shaders {
+            Some((ref vertex, ref fragment)) => (vertex, fragment),
+            None => return false,
+        };
+
+        let (vertex, fragment) = (vertex.borrow(), fragment.borrow());
+        let rebuilt = compile_opaque(factory, &vertex, &fragment).and_then(|opaque| {
+            try!(self.pso.rebuild(|blend| compile(factory, &vertex, &fragment, blend)));
+            *self.opaque_pso.borrow_mut() = opaque;
+            Ok(())
+        });
+        match rebuilt {
+            Ok(()) => true,
+            Err(err) => {
+                let _ = writeln!(io::stderr(), "Could not recompile sprite shaders: {}", err);
+                false
+            }
         }
     }
 
@@ -87,30 +414,138 @@ impl<R> SpriteFactory<R>
         texture: Texture<R>,
         width: f32, height: f32) -> Sprite<R>
         where F: gfx::Factory<R> {
-        Sprite::new(
+        let mut sprite = Sprite::new(
             factory, self.pso.clone(),
             self.vbuf.clone(), self.slice.clone(),
             self.sampler.clone(), target, texture,
-            width, height)
+            width, height);
+        sprite.opaque_pso = Some(self.opaque_pso.clone());
+        sprite
+    }
+
+    /// Create a sprite with its own sampler, instead of the factory's
+    /// default linear one.
+    pub fn create_with_options<F>(
+        &self,
+        factory: &mut F,
+        target: gfx::handle::RenderTargetView<R, ColorFormat>,
+        texture: Texture<R>,
+        options: &TextureOptions,
+        width: f32, height: f32) -> Sprite<R>
+        where F: gfx::Factory<R> {
+        let sampler = options.create_sampler(factory);
+        let mut sprite = Sprite::new(
+            factory, self.pso.clone(),
+            self.vbuf.clone(), self.slice.clone(),
+            sampler, target, texture,
+            width, height);
+        sprite.opaque_pso = Some(self.opaque_pso.clone());
+        sprite
+    }
+
+    /// Create a sprite sharing a texture, and its sampler, from an `Assets`
+    /// manager. The sprite holds on to the handle, so the texture counts
+    /// as in use.
+    pub fn create_from_handle<F>(
+        &self,
+        factory: &mut F,
+        target: gfx::handle::RenderTargetView<R, ColorFormat>,
+        texture: &Handle<SampledTexture<R>>,
+        width: f32, height: f32) -> Sprite<R>
+        where F: gfx::Factory<R> {
+        let sampled = texture.borrow().clone();
+        let mut sprite = Sprite::new(
+            factory, self.pso.clone(),
+            self.vbuf.clone(), self.slice.clone(),
+            sampled.sampler, target, sampled.texture,
+            width, height);
+        sprite.opaque_pso = Some(self.opaque_pso.clone());
+        sprite.texture_handle = Some(texture.clone());
+        sprite
+    }
+
+    pub fn create_from_path<F, P>(
+        &self,
+        factory: &mut F,
+        assets: &mut Assets<R>,
+        target: gfx::handle::RenderTargetView<R, ColorFormat>,
+        path: P,
+        width: f32, height: f32) -> Result<Sprite<R>>
+        where F: gfx::Factory<R>,
+              P: AsRef<::std::path::Path> {
+        let texture = try!(assets.texture(factory, path));
+        Ok(self.create_from_handle(factory, target, &texture, width, height))
+    }
+
+    /// Create a sprite showing one region of an atlas, at the region's
+    /// size in pixels.
+    pub fn create_from_atlas<F>(
+        &self,
+        factory: &mut F,
+        target: gfx::handle::RenderTargetView<R, ColorFormat>,
+        atlas: &Atlas<R>,
+        name: &str) -> Option<Sprite<R>>
+        where F: gfx::Factory<R> {
+        let region = match atlas.region(name) {
+            Some(region) => *region,
+            None => return None,
+        };
+        let texture = match atlas.page(region.page) {
+            Some(texture) => texture,
+            None => return None,
+        };
+
+        let mut sprite = self.create_from_handle(factory, target, texture,
+                                                 region.width as f32, region.height as f32);
+        sprite.uv_rect = atlas.data.uv_rect(name).unwrap();
+        Some(sprite)
+    }
+
+    pub fn create_nine_slice<F>(
+        &self,
+        factory: &mut F,
+        target: gfx::handle::RenderTargetView<R, ColorFormat>,
+        texture: Texture<R>,
+        texture_width: f32, texture_height: f32,
+        insets: Insets,
+        width: f32, height: f32,
+        tile_edges: bool) -> NineSlice<R>
+        where F: gfx::Factory<R> {
+        NineSlice::new(
+            factory, self.pso.clone(), self.sampler.clone(),
+            target, texture, texture_width, texture_height,
+            insets, width, height, tile_edges)
     }
 }
 
 pub struct Sprite<R: gfx::Resources> {
-    pso: Rc<gfx::PipelineState<R, pipe::Meta>>,
+    pso: Pipeline<R>,
+    /// Set for sprites from a `SpriteFactory`; others draw blended even
+    /// in depth-tested layers.
+    opaque_pso: Option<OpaquePipeline<R>>,
     data: pipe::Data<R>,
     slice: gfx::Slice<R>,
-    pub position: cgmath::Vector3<f32>,
-    pub scale: f32,
-    pub rotation: cgmath::Basis3<f32>,
-    pub rotation_center: cgmath::Vector3<f32>,
+    texture_handle: Option<Handle<SampledTexture<R>>>,
+    pub transform: Transform,
     pub width: f32,
     pub height: f32,
+    /// Colour multiplied into every texel, as RGBA.
+    pub tint: [f32; 4],
+    /// Multiplied into the alpha of `tint`; use it to fade the sprite out.
+    pub opacity: f32,
+    /// Mirror the sprite horizontally within its bounds.
+    pub flip_x: bool,
+    /// Mirror the sprite vertically within its bounds.
+    pub flip_y: bool,
+    /// The part of the texture to draw, as `[u, v, width, height]`.
+    pub uv_rect: [f32; 4],
+    pub blend: BlendMode,
 }
 
 impl<R: gfx::Resources> Sprite<R> {
     pub fn new<F>(
         factory: &mut F,
-        pso: Rc<gfx::PipelineState<R, pipe::Meta>>,
+        pso: Pipeline<R>,
         vbuf: gfx::handle::Buffer<R, Vertex>,
         slice: gfx::Slice<R>,
         sampler: gfx::handle::Sampler<R>,
@@ -123,19 +558,25 @@ impl<R: gfx::Resources> Sprite<R> {
             vbuf: vbuf,
             texture: (texture, sampler),
             locals: factory.create_constant_buffer(1),
+            alpha_cutoff: 0.0,
             out: target,
         };
 
         Sprite {
             pso: pso,
+            opaque_pso: None,
             data: data,
             slice: slice,
-            position: cgmath::vec3(0.0, 0.0, 0.0),
-            scale: 1.0,
-            rotation: cgmath::Basis3::one(),
-            rotation_center: cgmath::vec3(0.0, 0.0, 0.0),
+            texture_handle: None,
+            transform: Transform::new(),
             width: width,
             height: height,
+            tint: [1.0, 1.0, 1.0, 1.0],
+            opacity: 1.0,
+            flip_x: false,
+            flip_y: false,
+            uv_rect: [0.0, 0.0, 1.0, 1.0],
+            blend: BlendMode::Alpha,
         }
     }
 
@@ -144,26 +585,95 @@ impl<R: gfx::Resources> Sprite<R> {
                  proj: UniformMat4,
                  view: UniformMat4)
         where C: gfx::CommandBuffer<R> {
-        // TODO: cache recomputation of model matrix where possible
-        let translate_to_center = cgmath::Matrix4::from_translation(-self.rotation_center);
-        let rotation: cgmath::Matrix4<f32> = cgmath::Decomposed {
-            scale: 1.0,
-            rot: self.rotation,
-            disp: cgmath::vec3(0.0, 0.0, 0.0),
-        }.into();
-        let translate_from_center = cgmath::Matrix4::from_translation(self.rotation_center);
-        let scale = cgmath::Matrix4::from_nonuniform_scale(self.scale * self.width, self.scale * self.height, 1.0);
-        let translate_to_position = cgmath::Matrix4::from_translation(self.position);
-
-        let model = translate_to_position * translate_from_center * rotation * translate_to_center * scale;
+        self.update_locals(encoder, proj, view);
+        encoder.draw(&self.slice, &*self.pso.get(self.blend), &self.data);
+    }
+
+    /// Draw without blending, depth tested against and writing to `depth`.
+    /// Texels with alpha below `OPAQUE_ALPHA_CUTOFF` are discarded, so the
+    /// transparent parts of a sprite don't hide what's behind them. Falls
+    /// back to `render` for sprites not made by a `SpriteFactory`.
+    pub fn render_opaque<C>(&mut self,
+                            encoder: &mut gfx::Encoder<R, C>,
+                            proj: UniformMat4,
+                            view: UniformMat4,
+                            depth: &gfx::handle::DepthStencilView<R, DepthFormat>)
+        where C: gfx::CommandBuffer<R> {
+        let pso = match self.opaque_pso {
+            Some(ref pso) => pso.clone(),
+            None => return self.render(encoder, proj, view),
+        };
+
+        self.update_locals(encoder, proj, view);
+        let data = opaque::Data {
+            vbuf: self.data.vbuf.clone(),
+            texture: self.data.texture.clone(),
+            locals: self.data.locals.clone(),
+            alpha_cutoff: OPAQUE_ALPHA_CUTOFF,
+            out: self.data.out.clone(),
+            depth: depth.clone(),
+        };
+        encoder.draw(&self.slice, &*pso.borrow(), &data);
+    }
+
+    fn update_locals<C>(&mut self,
+                        encoder: &mut gfx::Encoder<R, C>,
+                        proj: UniformMat4,
+                        view: UniformMat4)
+        where C: gfx::CommandBuffer<R> {
+        if let Some(ref handle) = self.texture_handle {
+            // Pick up textures that were hot reloaded
+            let sampled = handle.borrow();
+            self.data.texture = (sampled.texture.clone(), sampled.sampler.clone());
+        }
+
+        // Snapping happens in world space, so children of a moving
+        // parent stay on whole units too
+        let mut world = self.transform.interpolated_matrix();
+        world.w = snap_position(world.w.truncate()).extend(1.0);
+        let size = cgmath::Matrix4::from_nonuniform_scale(self.width, self.height, 1.0);
+        // Mirror the unit quad in place, so flipping doesn't move the sprite
+        let flip = cgmath::Matrix4::from_translation(cgmath::vec3(
+            if self.flip_x { 1.0 } else { 0.0 },
+            if self.flip_y { 1.0 } else { 0.0 },
+            0.0)) * cgmath::Matrix4::from_nonuniform_scale(
+            if self.flip_x { -1.0 } else { 1.0 },
+            if self.flip_y { -1.0 } else { 1.0 },
+            1.0);
+
+        let model = world * size * flip;
 
         let locals = Locals {
             proj: proj,
             view: view,
             model: model.into(),
+            tint: self.pso.alpha().color([self.tint[0], self.tint[1], self.tint[2], self.tint[3] * self.opacity]),
+            uv_rect: self.uv_rect,
         };
 
         encoder.update_buffer(&self.data.locals, &[locals], 0).unwrap();
-        encoder.draw(&self.slice, &self.pso, &self.data);
+    }
+}
+
+impl<R, C> Drawable<R, C> for Sprite<R>
+    where R: gfx::Resources,
+          C: gfx::CommandBuffer<R> {
+    fn draw(&mut self,
+            encoder: &mut gfx::Encoder<R, C>,
+            proj: UniformMat4,
+            view: UniformMat4) {
+        self.render(encoder, proj, view);
+    }
+
+    fn draw_opaque(&mut self,
+                   encoder: &mut gfx::Encoder<R, C>,
+                   proj: UniformMat4,
+                   view: UniformMat4,
+                   depth: &gfx::handle::DepthStencilView<R, DepthFormat>) {
+        self.render_opaque(encoder, proj, view, depth);
+    }
+
+    fn sort_position(&self) -> cgmath::Vector3<f32> {
+        self.transform.world_position()
     }
 }
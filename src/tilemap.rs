@@ -1,6 +1,7 @@
 use std::path::Path;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{self, BufReader, Write};
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 
 use cgmath::{self, SquareMatrix};
@@ -8,6 +9,14 @@ use gfx;
 use gfx::traits::FactoryExt;
 use tiled;
 
+use assets::{Assets, Handle};
+use blend::{AlphaMode, BlendMode, PipelineSet};
+use camera::Bounds;
+use error::{self, Result};
+use render_queue::Drawable;
+use sprite::{SampledTexture, TextureOptions};
+use vfs::FileSystem;
+
 // gfx_defines! creates a submodule, so we need `pub use` to make sure
 // the import here is visible.
 pub use types::*;
@@ -23,49 +32,180 @@ gfx_defines! {
         proj: UniformMat4 = "u_Proj",
         view: UniformMat4 = "u_View",
         model: UniformMat4 = "u_Model",
+        tint: [f32; 4] = "u_Tint",
+        uv_rect: [f32; 4] = "u_UvRect",
     }
 
     pipeline pipe {
         vbuf: gfx::VertexBuffer<Vertex> = (),
         texture: gfx::TextureSampler<[f32; 4]> = "t_Texture",
         locals: gfx::ConstantBuffer<Locals> = "Locals",
+        alpha_cutoff: gfx::Global<f32> = "u_AlphaCutoff",
         out: gfx::BlendTarget<ColorFormat> = ("Target0", gfx::state::ColorMask::all(), gfx::preset::blend::ALPHA),
     }
 }
 
-pub fn load_tilemap<P>(path: P) -> Result<tiled::Map, String>
+const VERTEX_SHADER: &'static [u8] = include_bytes!("shader/sprite_150.glslv");
+const FRAGMENT_SHADER: &'static [u8] = include_bytes!("shader/sprite_150.glslf");
+
+/// The size of a tile in world units.
+pub const TILE_WORLD_SIZE: f32 = 64.0;
+
+pub fn load_tilemap<P>(path: P) -> Result<tiled::Map>
     where P: AsRef<Path> {
-    let f = File::open(path).unwrap();
+    let f = try!(File::open(path));
     let reader = BufReader::new(f);
-    let map = tiled::parse(reader).unwrap();
+    let map = try!(tiled::parse(reader));
+    Ok(map)
+}
+
+pub fn load_tilemap_from<P>(fs: &FileSystem, path: P) -> Result<tiled::Map>
+    where P: AsRef<Path> {
+    let bytes = try!(fs.read(path.as_ref()));
+    let map = try!(tiled::parse(&bytes[..]));
     Ok(map)
 }
 
 pub struct Tilemap<R: gfx::Resources> {
     sampler: gfx::handle::Sampler<R>,
-    pso: Rc<gfx::PipelineState<R, pipe::Meta>>,
-    tilemap: tiled::Map,
-    tileset: Texture<R>,
+    pso: Rc<PipelineSet<R, pipe::Meta>>,
+    shaders: Option<(Handle<Vec<u8>>, Handle<Vec<u8>>)>,
+    tilemap: Handle<tiled::Map>,
+    /// Bumped when the map is reloaded, for maps from `Assets`.
+    generation: Option<Rc<Cell<u64>>>,
+    tileset: Handle<SampledTexture<R>>,
 }
 
 pub struct TilemapLayer<R: gfx::Resources> {
-    pso: Rc<gfx::PipelineState<R, pipe::Meta>>,
+    pso: Rc<PipelineSet<R, pipe::Meta>>,
     data: pipe::Data<R>,
     slice: gfx::Slice<R>,
+    tileset: Handle<SampledTexture<R>>,
+    /// The map generation the mesh was built from.
+    generation: u64,
+    pub blend: BlendMode,
+}
+
+fn compile<F, R>(factory: &mut F, vertex: &[u8], fragment: &[u8], blend: gfx::state::Blend)
+                 -> Result<gfx::PipelineState<R, pipe::Meta>>
+    where F: gfx::Factory<R>,
+          R: gfx::Resources {
+    factory.create_pipeline_simple(vertex, fragment, pipe::Init {
+        out: ("Target0", gfx::state::ColorMask::all(), blend),
+        ..pipe::new()
+    }).map_err(error::shader)
+}
+
+fn build_pipelines<F, R>(factory: &mut F,
+                         shaders: &Option<(Handle<Vec<u8>>, Handle<Vec<u8>>)>,
+                         alpha: AlphaMode) -> Result<PipelineSet<R, pipe::Meta>>
+    where F: gfx::Factory<R>,
+          R: gfx::Resources {
+    match *shaders {
+        Some((ref vertex, ref fragment)) => {
+            let (vertex, fragment) = (vertex.borrow(), fragment.borrow());
+            PipelineSet::new(alpha, |blend| compile(factory, &vertex, &fragment, blend))
+        }
+        None => PipelineSet::new(alpha, |blend| compile(factory, VERTEX_SHADER, FRAGMENT_SHADER, blend)),
+    }
 }
 
 impl<R> Tilemap<R>
     where R: gfx::Resources {
-    pub fn new<F>(factory: &mut F, tilemap: tiled::Map, tileset: Texture<R>) -> Tilemap<R>
+    pub fn new<F>(factory: &mut F, tilemap: tiled::Map, tileset: Texture<R>) -> Result<Tilemap<R>>
+        where F: gfx::Factory<R> {
+        let tileset = SampledTexture::new(factory, tileset, &TextureOptions::default());
+        Tilemap::from_handles(factory, Rc::new(RefCell::new(tilemap)), Rc::new(RefCell::new(tileset)))
+    }
+
+    pub fn from_paths<F, P, Q>(factory: &mut F, assets: &mut Assets<R>, tilemap: P, tileset: Q)
+                               -> Result<Tilemap<R>>
+        where F: gfx::Factory<R>,
+              P: AsRef<Path>,
+              Q: AsRef<Path> {
+        let generation = assets.tilemap_generation(&tilemap);
+        let tilemap = try!(assets.tilemap(tilemap));
+        let tileset = try!(assets.texture(factory, tileset));
+        let mut result = try!(Tilemap::from_handles(factory, tilemap, tileset));
+        result.generation = Some(generation);
+        Ok(result)
+    }
+
+    /// Layers sample the tileset with the sampler in its handle, unless
+    /// `set_texture_options` says otherwise. `update_layers` can't tell when
+    /// a map from a handle is reloaded; use `from_paths` for that.
+    pub fn from_handles<F>(factory: &mut F, tilemap: Handle<tiled::Map>, tileset: Handle<SampledTexture<R>>)
+                           -> Result<Tilemap<R>>
         where F: gfx::Factory<R> {
-        Tilemap {
-            sampler: factory.create_sampler_linear(),
-            pso: Rc::new(factory.create_pipeline_simple(
-                include_bytes!("shader/sprite_150.glslv"),
-                include_bytes!("shader/sprite_150.glslf"),
-                pipe::new()).unwrap()),
+        let pso = try!(build_pipelines(factory, &None, AlphaMode::Straight));
+        let sampler = tileset.borrow().sampler.clone();
+        Ok(Tilemap {
+            sampler: sampler,
+            pso: Rc::new(pso),
+            shaders: None,
             tilemap: tilemap,
+            generation: None,
             tileset: tileset,
+        })
+    }
+
+    /// Change how the tileset is sampled by layers created from now on.
+    /// Nearest filtering (`TextureOptions::pixel_art`) stops neighbouring
+    /// tiles bleeding into each other.
+    pub fn set_texture_options<F>(&mut self, factory: &mut F, options: &TextureOptions)
+        where F: gfx::Factory<R> {
+        self.sampler = options.create_sampler(factory);
+    }
+
+    /// Switch between straight and premultiplied alpha for layers created
+    /// from now on. A premultiplied tileset should be loaded with
+    /// `TextureOptions::premultiply`.
+    pub fn set_alpha_mode<F>(&mut self, factory: &mut F, alpha: AlphaMode) -> Result<()>
+        where F: gfx::Factory<R> {
+        self.pso = Rc::new(try!(build_pipelines(factory, &self.shaders, alpha)));
+        Ok(())
+    }
+
+    /// Build the pipeline for layers created from now on from shader
+    /// assets instead of the built-in sprite shaders, so it can be
+    /// recompiled when they change.
+    pub fn set_shaders<F>(&mut self, factory: &mut F, vertex: Handle<Vec<u8>>, fragment: Handle<Vec<u8>>)
+                          -> Result<()>
+        where F: gfx::Factory<R> {
+        let shaders = Some((vertex, fragment));
+        self.pso = Rc::new(try!(build_pipelines(factory, &shaders, self.pso.alpha())));
+        self.shaders = shaders;
+        Ok(())
+    }
+
+    /// Rebuild the pipeline from the current contents of the shader assets,
+    /// for every layer using it. On failure the old pipeline stays in use
+    /// and the error is logged. Does nothing with the built-in shaders.
+    pub fn recompile<F>(&self, factory: &mut F) -> bool
+        where F: gfx::Factory<R> {
+        let (vertex, fragment) = match self.shaders {
+            Some((ref vertex, ref fragment)) => (vertex, fragment),
+            None => return false,
+        };
+
+        let (vertex, fragment) = (vertex.borrow(), fragment.borrow());
+        match self.pso.rebuild(|blend| compile(factory, &vertex, &fragment, blend)) {
+            Ok(()) => true,
+            Err(err) => {
+                let _ = writeln!(io::stderr(), "Could not recompile tilemap shaders: {}", err);
+                false
+            }
+        }
+    }
+
+    /// The area covered by the map, in world units.
+    pub fn bounds(&self) -> Bounds {
+        let map = self.tilemap.borrow();
+        Bounds {
+            left: 0.0,
+            bottom: 0.0,
+            right: map.width as f32 * TILE_WORLD_SIZE,
+            top: map.height as f32 * TILE_WORLD_SIZE,
         }
     }
 
@@ -76,13 +216,13 @@ impl<R> Tilemap<R>
         -> Vec<TilemapLayer<R>>
         where F: gfx::Factory<R> {
         let mut result = Vec::new();
-        for layer in self.tilemap.layers.iter() {
+        for layer in self.tilemap.borrow().layers.iter() {
             let mut mesh = Vec::new();
             let mut slice = Vec::new();
             let mut y = 0.0;
             let mut offset: u16 = 0;
             let num_tiles_x: u32 = 16;
-            let tile_world_size = 64.0;
+            let tile_world_size = TILE_WORLD_SIZE;
             let tile_tex_offset_x = 2.0 / 1088.0;
             let tile_tex_offset_y = 2.0 / 816.0;
             let tile_tex_width = 64.0 / 1088.0;
@@ -116,28 +256,52 @@ impl<R> Tilemap<R>
 
             let (vertex_buffer, slice) = factory.create_vertex_buffer_with_slice(&mesh, slice.as_slice());
 
-            result.push(TilemapLayer::new(factory, self.pso.clone(), vertex_buffer, slice, self.sampler.clone(), target.clone(), self.tileset.clone()));
+            let mut layer = TilemapLayer::new(factory, self.pso.clone(), vertex_buffer, slice, self.sampler.clone(), target.clone(), self.tileset.clone());
+            layer.generation = self.generation();
+            result.push(layer);
         }
 
         result
     }
+
+    fn generation(&self) -> u64 {
+        self.generation.as_ref().map_or(0, |generation| generation.get())
+    }
+
+    /// Recreate `layers` if the map was reloaded since they were created,
+    /// into the same target and with the same blend modes. Returns whether
+    /// it did.
+    pub fn update_layers<F>(&self, factory: &mut F, layers: &mut Vec<TilemapLayer<R>>) -> bool
+        where F: gfx::Factory<R> {
+        let target = match layers.first() {
+            Some(layer) if layer.generation != self.generation() => layer.data.out.clone(),
+            _ => return false,
+        };
+        let blends: Vec<BlendMode> = layers.iter().map(|layer| layer.blend).collect();
+        *layers = self.create_layers(factory, target);
+        for (layer, blend) in layers.iter_mut().zip(blends) {
+            layer.blend = blend;
+        }
+        true
+    }
 }
 
 impl<R> TilemapLayer<R>
     where R: gfx::Resources {
     fn new<F>(
         factory: &mut F,
-        pso: Rc<gfx::PipelineState<R, pipe::Meta>>,
+        pso: Rc<PipelineSet<R, pipe::Meta>>,
         vbuf: gfx::handle::Buffer<R, Vertex>,
         slice: gfx::Slice<R>,
         sampler: gfx::handle::Sampler<R>,
         target: gfx::handle::RenderTargetView<R, ColorFormat>,
-        texture: Texture<R>) -> TilemapLayer<R>
+        tileset: Handle<SampledTexture<R>>) -> TilemapLayer<R>
         where F: gfx::Factory<R> {
         let data = pipe::Data {
             vbuf: vbuf,
-            texture: (texture, sampler),
+            texture: (tileset.borrow().texture.clone(), sampler),
             locals: factory.create_constant_buffer(1),
+            alpha_cutoff: 0.0,
             out: target,
         };
 
@@ -145,21 +309,40 @@ impl<R> TilemapLayer<R>
             pso: pso,
             data: data,
             slice: slice,
+            tileset: tileset,
+            generation: 0,
+            blend: BlendMode::Alpha,
         }
     }
 
-    pub fn render<C>(&self,
+    pub fn render<C>(&mut self,
                  encoder: &mut gfx::Encoder<R, C>,
                  proj: UniformMat4,
                  view: UniformMat4)
         where C: gfx::CommandBuffer<R> {
+        // Pick up a hot reloaded tileset
+        self.data.texture.0 = self.tileset.borrow().texture.clone();
+
         let locals = Locals {
             proj: proj,
             view: view,
             model: cgmath::Matrix4::identity().into(),
+            tint: [1.0, 1.0, 1.0, 1.0],
+            uv_rect: [0.0, 0.0, 1.0, 1.0],
         };
 
         encoder.update_buffer(&self.data.locals, &[locals], 0).unwrap();
-        encoder.draw(&self.slice, &self.pso, &self.data);
+        encoder.draw(&self.slice, &*self.pso.get(self.blend), &self.data);
+    }
+}
+
+impl<R, C> Drawable<R, C> for TilemapLayer<R>
+    where R: gfx::Resources,
+          C: gfx::CommandBuffer<R> {
+    fn draw(&mut self,
+            encoder: &mut gfx::Encoder<R, C>,
+            proj: UniformMat4,
+            view: UniformMat4) {
+        self.render(encoder, proj, view);
     }
 }
@@ -0,0 +1,264 @@
+use std::cell::{Cell, RefCell};
+use std::f32;
+use std::rc::Rc;
+
+use cgmath::{self, Rotation, Rotation3};
+
+thread_local! {
+    static TICK: Cell<u64> = Cell::new(0);
+    static ALPHA: Cell<f32> = Cell::new(1.0);
+}
+
+/// Start a new fixed tick. Transforms changed from now on remember where
+/// they were, so rendering can blend from there. `App` calls this before
+/// every `Game::tick`.
+pub fn begin_tick() {
+    TICK.with(|tick| tick.set(tick.get() + 1));
+}
+
+pub fn current_tick() -> u64 {
+    TICK.with(|tick| tick.get())
+}
+
+/// Set how far rendering is between the previous tick and the current
+/// one, from 0 to 1. `App` sets this before every `Game::render`.
+pub fn set_alpha(alpha: f32) {
+    ALPHA.with(|cell| cell.set(alpha));
+}
+
+pub fn alpha() -> f32 {
+    ALPHA.with(|cell| cell.get())
+}
+
+#[derive(Clone, Copy)]
+struct State {
+    position: cgmath::Vector3<f32>,
+    scale: f32,
+    rotation: cgmath::Basis3<f32>,
+    rotation_center: cgmath::Vector3<f32>,
+}
+
+// The angle of a rotation about the z axis
+fn angle(rotation: &cgmath::Basis3<f32>) -> f32 {
+    let matrix: &cgmath::Matrix3<f32> = rotation.as_ref();
+    matrix.x.y.atan2(matrix.x.x)
+}
+
+impl State {
+    fn matrix(&self) -> cgmath::Matrix4<f32> {
+        let translate_to_center = cgmath::Matrix4::from_translation(-self.rotation_center);
+        let rotation: cgmath::Matrix4<f32> = cgmath::Decomposed {
+            scale: 1.0,
+            rot: self.rotation,
+            disp: cgmath::vec3(0.0, 0.0, 0.0),
+        }.into();
+        let translate_from_center = cgmath::Matrix4::from_translation(self.rotation_center);
+        let scale = cgmath::Matrix4::from_nonuniform_scale(self.scale, self.scale, 1.0);
+        let translate_to_position = cgmath::Matrix4::from_translation(self.position);
+
+        translate_to_position * translate_from_center * rotation * translate_to_center * scale
+    }
+
+    // Rotations are blended as 2D rotations about z, the short way round
+    fn lerp(&self, other: &State, t: f32) -> State {
+        let from = angle(&self.rotation);
+        let mut delta = angle(&other.rotation) - from;
+        if delta > f32::consts::PI {
+            delta -= 2.0 * f32::consts::PI;
+        }
+        else if delta < -f32::consts::PI {
+            delta += 2.0 * f32::consts::PI;
+        }
+
+        State {
+            position: self.position + (other.position - self.position) * t,
+            scale: self.scale + (other.scale - self.scale) * t,
+            rotation: cgmath::Basis3::from_angle_z(cgmath::Rad { s: from + delta * t }),
+            rotation_center: self.rotation_center + (other.rotation_center - self.rotation_center) * t,
+        }
+    }
+}
+
+struct Node {
+    current: State,
+    /// The state at the end of the tick before `changed`.
+    previous: State,
+    changed: u64,
+    /// The tick the transform was created in. Changes made in it place the
+    /// transform rather than move it.
+    created: u64,
+    parent: Option<Transform>,
+    /// The local matrix, or `None` if something changed since it was last
+    /// computed.
+    local: Option<cgmath::Matrix4<f32>>,
+}
+
+/// Position, scale and rotation of a drawable, relative to an optional
+/// parent.
+///
+/// A `Transform` is a handle: clones refer to the same node, which is how
+/// children follow their parent. The local matrix is only recomputed after
+/// something changes.
+///
+/// Drawables render with `interpolated_matrix`, which blends from where
+/// the transform was before the current tick by `alpha`, so motion is
+/// smooth at any frame rate.
+#[derive(Clone)]
+pub struct Transform {
+    node: Rc<RefCell<Node>>,
+}
+
+impl Default for Transform {
+    fn default() -> Transform {
+        Transform::new()
+    }
+}
+
+impl Transform {
+    pub fn new() -> Transform {
+        let state = State {
+            position: cgmath::vec3(0.0, 0.0, 0.0),
+            scale: 1.0,
+            rotation: cgmath::Basis3::one(),
+            rotation_center: cgmath::vec3(0.0, 0.0, 0.0),
+        };
+        let tick = current_tick();
+        Transform {
+            node: Rc::new(RefCell::new(Node {
+                current: state,
+                previous: state,
+                changed: tick,
+                created: tick,
+                parent: None,
+                local: None,
+            })),
+        }
+    }
+
+    fn modify<F>(&mut self, f: F)
+        where F: FnOnce(&mut State) {
+        let mut node = self.node.borrow_mut();
+        let tick = current_tick();
+        if node.changed != tick {
+            node.previous = node.current;
+            node.changed = tick;
+        }
+        f(&mut node.current);
+        // Don't blend in from the origin when created and placed in one tick
+        if node.created == tick {
+            node.previous = node.current;
+        }
+        node.local = None;
+    }
+
+    /// Don't blend from the previous tick, e.g. after teleporting.
+    pub fn reset_interpolation(&mut self) {
+        let mut node = self.node.borrow_mut();
+        node.previous = node.current;
+    }
+
+    pub fn position(&self) -> cgmath::Vector3<f32> {
+        self.node.borrow().current.position
+    }
+
+    pub fn set_position(&mut self, position: cgmath::Vector3<f32>) {
+        self.modify(|state| state.position = position);
+    }
+
+    pub fn translate(&mut self, offset: cgmath::Vector3<f32>) {
+        self.modify(|state| state.position = state.position + offset);
+    }
+
+    pub fn scale(&self) -> f32 {
+        self.node.borrow().current.scale
+    }
+
+    pub fn set_scale(&mut self, scale: f32) {
+        self.modify(|state| state.scale = scale);
+    }
+
+    pub fn rotation(&self) -> cgmath::Basis3<f32> {
+        self.node.borrow().current.rotation
+    }
+
+    pub fn set_rotation(&mut self, rotation: cgmath::Basis3<f32>) {
+        self.modify(|state| state.rotation = rotation);
+    }
+
+    /// The point rotated around, relative to `position`.
+    pub fn rotation_center(&self) -> cgmath::Vector3<f32> {
+        self.node.borrow().current.rotation_center
+    }
+
+    pub fn set_rotation_center(&mut self, center: cgmath::Vector3<f32>) {
+        self.modify(|state| state.rotation_center = center);
+    }
+
+    pub fn parent(&self) -> Option<Transform> {
+        self.node.borrow().parent.clone()
+    }
+
+    /// Attach this transform to a parent, so that it moves, rotates and
+    /// scales along with it.
+    ///
+    /// Panics if this would make a transform its own ancestor.
+    pub fn set_parent(&mut self, parent: Option<&Transform>) {
+        let mut ancestor = parent.cloned();
+        while let Some(transform) = ancestor {
+            assert!(&*transform.node as *const RefCell<Node> != &*self.node as *const RefCell<Node>,
+                    "transform would be its own ancestor");
+            ancestor = transform.parent();
+        }
+        self.node.borrow_mut().parent = parent.cloned();
+    }
+
+    /// The matrix relative to the parent.
+    pub fn local_matrix(&self) -> cgmath::Matrix4<f32> {
+        let mut node = self.node.borrow_mut();
+        if let Some(local) = node.local {
+            return local;
+        }
+
+        let local = node.current.matrix();
+        node.local = Some(local);
+        local
+    }
+
+    /// The matrix relative to the world, including all ancestors.
+    pub fn matrix(&self) -> cgmath::Matrix4<f32> {
+        let local = self.local_matrix();
+        match self.parent() {
+            Some(parent) => parent.matrix() * local,
+            None => local,
+        }
+    }
+
+    /// The world matrix blended between the previous tick and this one by
+    /// the current `alpha`.
+    pub fn interpolated_matrix(&self) -> cgmath::Matrix4<f32> {
+        let blended = {
+            let node = self.node.borrow();
+            // Transforms that didn't change this tick are at rest
+            if node.changed == current_tick() {
+                Some(node.previous.lerp(&node.current, alpha()).matrix())
+            }
+            else {
+                None
+            }
+        };
+        let local = match blended {
+            Some(local) => local,
+            None => self.local_matrix(),
+        };
+
+        match self.parent() {
+            Some(parent) => parent.interpolated_matrix() * local,
+            None => local,
+        }
+    }
+
+    /// Where the origin of this transform ends up in the world.
+    pub fn world_position(&self) -> cgmath::Vector3<f32> {
+        self.matrix().w.truncate()
+    }
+}
@@ -0,0 +1,54 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Somewhere assets can be read from: a directory on disk, or a packed
+/// `archive::Archive`.
+pub trait FileSystem {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    fn exists(&self, path: &Path) -> bool {
+        self.read(path).is_ok()
+    }
+
+    /// When the file last changed, for hot reloading, or `None` if that
+    /// can't change.
+    fn modified(&self, _path: &Path) -> Option<SystemTime> {
+        None
+    }
+}
+
+/// A file system that can be shared with loader threads.
+pub type SharedFileSystem = Arc<FileSystem + Send + Sync>;
+
+/// Reads files relative to a directory.
+pub struct Directory {
+    root: PathBuf,
+}
+
+impl Directory {
+    pub fn new<P>(root: P) -> Directory
+        where P: AsRef<Path> {
+        Directory {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl FileSystem for Directory {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        try!(try!(File::open(self.root.join(path))).read_to_end(&mut contents));
+        Ok(contents)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.root.join(path).is_file()
+    }
+
+    fn modified(&self, path: &Path) -> Option<SystemTime> {
+        fs::metadata(self.root.join(path)).and_then(|metadata| metadata.modified()).ok()
+    }
+}